@@ -1,20 +1,21 @@
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use llama_cpp_2::context::params::LlamaContextParams;
 use llama_cpp_2::llama_backend::LlamaBackend;
 use llama_cpp_2::llama_batch::LlamaBatch;
 use llama_cpp_2::model::params::LlamaModelParams;
 use llama_cpp_2::model::LlamaModel;
 use llama_cpp_2::context::LlamaContext;
+use llama_cpp_2::sampling::LlamaSampler;
 use rustpython_parser::{parse, Mode};
-use rustpython_ast::{Mod, Stmt};
+use rustpython_ast::{Constant, Expr, Mod, Stmt, Visitor};
 use std::num::NonZeroU32;
 use std::path::PathBuf;
 use std::fs::OpenOptions;
 #[cfg(unix)]
 use std::os::unix::io::AsRawFd;
 use walkdir::WalkDir;
-use rusqlite::{Connection, params};
+use rusqlite::{Connection, OptionalExtension, params};
 use sha2::{Sha256, Digest};
 use std::fs;
 use serde::{Deserialize, Serialize};
@@ -26,42 +27,242 @@ use similar::{ChangeTag, TextDiff};
 #[command(name = "loopsleuth")]
 #[command(about = "Detect performance issues in Python code using LLM analysis", long_about = None)]
 struct Cli {
-    /// Path to the Python module or file to analyze
+    /// Path to the Python module or file to analyze (or a .zip/.tar.gz of sources)
     #[arg(value_name = "PATH")]
     python_path: Option<PathBuf>,
 
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Path to the GGUF model file
     #[arg(short, long, value_name = "MODEL")]
     model: Option<PathBuf>,
 
-    /// Number of threads to use for inference
-    #[arg(short, long, default_value_t = 4)]
+    /// Number of threads to use for inference (0 = all logical cores)
+    #[arg(short, long, default_value_t = 0)]
     threads: u32,
 
     /// Maximum tokens to generate
     #[arg(long, default_value_t = 1024)]
     max_tokens: i32,
 
+    /// Sampling temperature (0 = greedy); checks can override it per phase
+    #[arg(long, default_value_t = 0.0)]
+    temperature: f32,
+
+    /// Prepend a BOS token to prompts: auto (from model metadata), always or never
+    #[arg(long, default_value = "auto", value_parser = ["auto", "always", "never"])]
+    add_bos: String,
+
+    /// Extra system instructions for every detection prompt (TEXT, or @FILE to read them from a file)
+    #[arg(long, value_name = "TEXT|@FILE")]
+    system_prompt: Option<String>,
+
+    /// Reminder appended to the user message of every detection prompt (TEXT or @FILE)
+    #[arg(long, value_name = "TEXT|@FILE")]
+    prompt_suffix: Option<String>,
+
+    /// Retry a truncated solution once with double the --max-tokens budget
+    #[arg(long)]
+    retry_truncated: bool,
+
+    /// Detection only: report which functions have issues without generating or verifying fixes
+    #[arg(long)]
+    no_solution: bool,
+
+    /// Comma-separated checks (keys, `category/*` or globs) that get the solution and verifier
+    /// phases; the other checks only run detection
+    #[arg(long, value_name = "CHECKS", conflicts_with = "no_solution")]
+    solutions_for: Option<String>,
+
+    /// For accepted solutions, also generate a pytest/timeit snippet demonstrating the fix
+    /// (uses each check's test_prompt)
+    #[arg(long, conflicts_with = "no_solution")]
+    with_tests: bool,
+
+    /// Reject solutions whose verifier CONFIDENCE is below this value (0.0-1.0)
+    #[arg(long, value_name = "CONFIDENCE", value_parser = parse_ratio)]
+    verify_min_confidence: Option<f32>,
+
+    /// Re-run only the verifier on cached solutions, updating the cache when the verdict changes
+    #[arg(long)]
+    reverify: bool,
+
+    /// On a cache miss, run detection N times and keep the majority verdict with its average
+    /// confidence (ties count as clean). Useful with a non-zero detection temperature
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    stabilize: u32,
+
+    /// Collapse issues on the same function whose DETAIL lines overlap by at least this
+    /// fraction of words (0.0-1.0), keeping the most confident one
+    #[arg(long, value_name = "RATIO", value_parser = parse_ratio)]
+    collapse_similar: Option<f32>,
+
+    /// Report one confidence per issue that the verifier's confidence in a VALID solution
+    /// raises: detection + (1 - detection) * verifier / 2
+    #[arg(long)]
+    blend_confidence: bool,
+
+    /// Mark issues whose detection CONFIDENCE is below this value (0.0-1.0) as advisory:
+    /// still reported, but they don't trip --fail-fast or count as new findings in --compare
+    #[arg(long, value_name = "CONFIDENCE", value_parser = parse_ratio)]
+    warn_below_confidence: Option<f32>,
+
+    /// Stop at the first function with issues, report it and exit non-zero
+    #[arg(long)]
+    fail_fast: bool,
+
+    /// Abort with a diagnostic when generation runs slower than this many output tokens per
+    /// second, measured once a few functions have gone through the model
+    #[arg(long, value_name = "TOK_S")]
+    min_throughput: Option<f64>,
+
     /// Context size (max tokens for input + output)
     #[arg(long, default_value_t = 4096)]
     context_size: u32,
 
+    /// Prompt evaluation batch size for the local model (capped at --context-size). Smaller
+    /// values use less memory; longer prompts are then evaluated in several batches
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_BATCH_SIZE,
+          value_parser = clap::value_parser!(u32).range(1..))]
+    batch_size: u32,
+
+    /// Number of model layers to offload to the GPU (default: llama.cpp's own default;
+    /// needs a GPU-enabled build)
+    #[arg(long, value_name = "N")]
+    gpu_layers: Option<u32>,
+
     /// Show verbose llama.cpp output
     #[arg(short, long)]
     verbose: bool,
 
+    /// Log level: error, warn, info, debug or trace (default: warn, debug with --verbose)
+    #[arg(long, value_name = "LEVEL")]
+    log_level: Option<tracing::Level>,
+
+    /// Write logs to this file instead of stderr
+    #[arg(long, value_name = "FILE")]
+    log_file: Option<PathBuf>,
+
     /// Output report to file (markdown format)
     #[arg(short, long, value_name = "FILE")]
     output: Option<PathBuf>,
 
+    /// Gzip-compress the --output report (`.gz` is appended to FILE unless it already ends in it)
+    #[arg(long, requires = "output")]
+    compress: bool,
+
+    /// Regenerate the --output HTML report as findings arrive, instead of only at the end of
+    /// the run; every result is also recorded in a store next to it (`FILE.results.jsonl`)
+    #[arg(long, requires = "output", conflicts_with = "format")]
+    live_report: bool,
+
+    /// Write one HTML report per analyzed file plus an index.html into DIR
+    #[arg(long, value_name = "DIR")]
+    output_dir: Option<PathBuf>,
+
+    /// HTML skeleton for --output/--output-dir reports, with {{styles}}, {{generated}},
+    /// {{summary}}, {{issues}} and {{footer}} placeholders
+    #[arg(long, value_name = "FILE")]
+    report_template: Option<PathBuf>,
+
     /// Show detailed report in stdout (always included in --output file)
     #[arg(short, long)]
     details: bool,
 
+    /// Leave files without issues out of reports, JSON output and the --output-dir index
+    /// (summary counts still cover the full scan)
+    #[arg(long)]
+    only_files_with_issues: bool,
+
+    /// After the run, list the N functions that cost the most input tokens (cached results excluded)
+    #[arg(long, value_name = "N")]
+    top_token_functions: Option<usize>,
+
+    /// Print only the validated diffs of suggested fixes, each under a `# file:function` header
+    /// (progress goes to stderr)
+    #[arg(long, conflicts_with_all = ["format", "redact", "tui"])]
+    diff_only: bool,
+
+    /// Print each function's detailed report block as soon as its analysis completes,
+    /// instead of waiting for the end of the run (the summary still comes last)
+    #[arg(long, conflicts_with_all = ["format", "diff_only", "tui"])]
+    stream_report: bool,
+
+    /// Width of the progress bar in characters (shrunk to fit narrower terminals)
+    #[arg(long, value_name = "N", default_value_t = 30)]
+    progress_width: usize,
+
+    /// Draw the progress bar with `#` and `-` for terminals without Unicode block characters
+    #[arg(long)]
+    progress_ascii: bool,
+
+    /// Write every skipped file, function and check (with stage and reason) to FILE as JSON
+    #[arg(long, value_name = "FILE")]
+    skips_report: Option<PathBuf>,
+
+    /// Also write the aggregate stats (functions, issues per check, token usage, health score)
+    /// to FILE as JSON, next to the normal output
+    #[arg(long, value_name = "FILE")]
+    summary_json: Option<PathBuf>,
+
+    /// Browse functions with issues in an interactive terminal UI after the run
+    #[arg(long)]
+    tui: bool,
+
     /// Skip functions larger than this many lines (0 = no limit)
     #[arg(long, default_value_t = 0)]
     skip_large: usize,
 
+    /// Skip functions smaller than this many lines (0 = no limit)
+    #[arg(long, default_value_t = 0)]
+    skip_small: usize,
+
+    /// Stop after analyzing this many functions (across all files)
+    #[arg(long, value_name = "N")]
+    max_functions: Option<usize>,
+
+    /// Analyze a reproducible random sample of N functions (seeded by --seed)
+    #[arg(long, value_name = "N")]
+    sample: Option<usize>,
+
+    /// Also extract functions nested inside other functions, analyzed as `outer::inner`
+    #[arg(long)]
+    nested_functions: bool,
+
+    /// Refuse any network access (only loopback API endpoints are allowed), for airgapped runs
+    #[arg(long)]
+    no_network: bool,
+
+    /// Only analyze functions whose source isn't in the cache yet (new or changed since the last run)
+    #[arg(long)]
+    only_changed_functions: bool,
+
+    /// Analyze the staged (git index) version of each file instead of the working tree, for pre-commit hooks
+    #[arg(long)]
+    staged: bool,
+
+    /// Leave out test files (see --test-pattern)
+    #[arg(long, conflicts_with = "analyze_tests")]
+    skip_tests: bool,
+
+    /// Analyze test files too, even when the config sets `skip_tests = true`
+    #[arg(long)]
+    analyze_tests: bool,
+
+    /// Comma-separated patterns identifying test files: file-name globs, or `dir/` for any file
+    /// under a directory of that name inside PATH (default: test_*.py,*_test.py,tests/)
+    #[arg(long, value_name = "PATTERNS", value_delimiter = ',')]
+    test_pattern: Vec<String>,
+
+    /// Analyze this Python snippet instead of PATH; results are reported under the path `<inline>`
+    #[arg(long, value_name = "SOURCE", conflicts_with_all = ["python_path", "staged"])]
+    code: Option<String>,
+
+    /// Only analyze functions overlapping this 1-based, inclusive line range (single-file PATH)
+    #[arg(long, value_name = "START:END", value_parser = parse_line_range)]
+    lines: Option<(usize, usize)>,
+
     /// Disable caching of analysis results
     #[arg(long)]
     no_cache: bool,
@@ -70,11 +271,32 @@ struct Cli {
     #[arg(long)]
     clear_cache: bool,
 
+    /// Compact the cache database (keeping valid entries), report the space reclaimed and exit
+    #[arg(long, conflicts_with_all = ["no_cache", "clear_cache"])]
+    cache_vacuum: bool,
+
+    /// With --cache-vacuum, first drop cached results older than this many days
+    #[arg(long, value_name = "DAYS", requires = "cache_vacuum")]
+    cache_max_age: Option<u64>,
+
+    /// Print each check's dismissal rate (per month the findings were first reported) and exit
+    #[arg(long, conflicts_with = "no_cache")]
+    feedback_report: bool,
+
     /// Directory for cache storage (default: .loopsleuth_cache)
-    #[arg(long, value_name = "DIR")]
+    #[arg(long, value_name = "DIR", global = true)]
     cache_dir: Option<PathBuf>,
 
-    /// Comma-separated list of checks to run (default: all checks)
+    /// Lock the cache database for the whole run; a concurrent run on the same cache waits
+    /// for it instead of racing
+    #[arg(long, conflicts_with = "no_cache")]
+    concurrency_safe_cache: bool,
+
+    /// Use this exact SQLite file as the cache database instead of `<cache-dir>/analysis_cache.db`
+    #[arg(long, value_name = "PATH", global = true)]
+    cache_file: Option<PathBuf>,
+
+    /// Comma-separated checks to run: keys, `category/*` or globs like `quad*` (default: all checks)
     #[arg(long, value_name = "CHECKS")]
     checks: Option<String>,
 
@@ -82,14 +304,62 @@ struct Cli {
     #[arg(long)]
     list_checks: bool,
 
+    /// Validate the checks configuration (templates, guards, duplicate keys) and exit
+    #[arg(long)]
+    config_check: bool,
+
+    /// List the functions extraction finds under PATH (file:line, name, async, size) and exit
+    #[arg(long)]
+    list_functions: bool,
+
+    /// Print the detection prompt each selected check would send for one function, then exit
+    #[arg(long, num_args = 2, value_names = ["FILE", "FUNCTION"])]
+    print_prompt_for: Option<Vec<String>>,
+
+    /// Run detection for one function under PATH (name or Class::method) without the cache and
+    /// print each check's prompt, raw response and parsed verdict, e.g. to see why it came back clean
+    #[arg(long, value_name = "FUNCTION")]
+    explain_clean: Option<String>,
+
+    /// Compare the findings of two `--format json` reports (fixed, new, persistent) and exit
+    #[arg(long, num_args = 2, value_names = ["OLD", "NEW"])]
+    compare: Option<Vec<PathBuf>>,
+
     /// Comma-separated list of checks to exclude from analysis
     #[arg(long, value_name = "CHECKS")]
     exclude: Option<String>,
 
+    /// File with check keys to run, one per line ('#' starts a comment)
+    #[arg(long, value_name = "FILE")]
+    checks_file: Option<PathBuf>,
+
+    /// File with check keys to exclude, one per line ('#' starts a comment)
+    #[arg(long, value_name = "FILE")]
+    exclude_file: Option<PathBuf>,
+
+    /// Fail instead of warning when --checks/--exclude name unknown check keys, or when PATH
+    /// holds no Python files (exit code 3)
+    #[arg(long)]
+    strict: bool,
+
+    /// Abort on the first file that fails to parse instead of skipping it
+    #[arg(long)]
+    strict_parse: bool,
+
     /// Path to custom checks configuration file (TOML format)
     #[arg(long, value_name = "FILE")]
     config: Option<PathBuf>,
 
+    /// Override one config value for this run, e.g. `settings.context_size=8192` or
+    /// `check.quadratic.detection_temperature=0.2` (repeatable; checks are addressed by key)
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    config_overrides: Vec<String>,
+
+    /// Apply the `[model_profiles.NAME]` table from the config: model path, threads, context
+    /// size and generation parameters as one bundle (explicit flags still take precedence)
+    #[arg(long, value_name = "NAME")]
+    model_profile: Option<String>,
+
     /// URL of a HF Inference Endpoint (uses HF_TOKEN env var for auth)
     #[arg(long, value_name = "URL")]
     api_url: Option<String>,
@@ -98,17 +368,86 @@ struct Cli {
     #[arg(long)]
     print_default_config: bool,
 
+    /// Don't add the "constructors are rarely hot" note to `__init__` detection prompts, so
+    /// constructors doing real work get flagged like any other function
+    #[arg(long)]
+    no_init_special_case: bool,
+
+    /// Print the effective settings (config file, `--set` and CLI flags combined) as TOML,
+    /// noting where each value came from, and exit
+    #[arg(long)]
+    show_config: bool,
+
     /// Filter functions by name (substring match, case-insensitive)
     #[arg(short = 'k', long, value_name = "NAME")]
     filter_function: Option<String>,
 
-    /// Output format: text (default) or json
+    /// Output format: text (default), json, terminal-table or codeclimate (GitLab code quality)
     #[arg(long, default_value = "text")]
     format: String,
+
+    /// Seed for randomized behavior (recorded in the run manifest for reproducibility)
+    #[arg(long, value_name = "N")]
+    seed: Option<u64>,
+
+    /// Show report paths relative to this directory (default: the analyzed directory, or cwd for a single file)
+    #[arg(long, value_name = "DIR")]
+    relative_to: Option<PathBuf>,
+
+    /// Show this many lines of the file above each function in detailed reports
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    include_context_lines: usize,
+
+    /// Omit source code and diffs from reports, keeping names, locations and analyses
+    #[arg(long)]
+    redact: bool,
+
+    /// Show the complete optimized function under each suggested diff, and add it to JSON
+    /// reports as `optimized_code`, for pasting straight into an editor
+    #[arg(long, conflicts_with = "redact")]
+    emit_optimized: bool,
+
+    /// Arguments given on the command line (rather than left at their defaults)
+    #[arg(skip)]
+    explicit_args: std::collections::HashSet<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Record findings as false positives in the cache and exit
+    Dismiss {
+        /// `finding_id`s from a JSON report (the `fingerprint` in Code Climate output)
+        #[arg(value_name = "FINDING_ID", required = true)]
+        finding_ids: Vec<String>,
+    },
+}
+
+impl Cli {
+    /// Parse arguments like `Cli::parse_from`, remembering which ones were given explicitly
+    fn parse_tracking_sources<I, T>(args: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        use clap::{CommandFactory, FromArgMatches};
+
+        let matches = Cli::command().get_matches_from(args);
+        let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+        cli.explicit_args = matches.ids()
+            .filter(|id| matches.value_source(id.as_str()) == Some(clap::parser::ValueSource::CommandLine))
+            .map(|id| id.to_string())
+            .collect();
+        cli
+    }
+
+    /// Whether `--<arg>` was given on the command line (`arg` is the field name)
+    fn is_explicit(&self, arg: &str) -> bool {
+        self.explicit_args.contains(arg)
+    }
 }
 
 /// Token usage statistics
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 struct TokenStats {
     input_tokens: usize,
     output_tokens: usize,
@@ -130,6 +469,32 @@ impl TokenStats {
         self.generation_time += other.generation_time;
     }
 
+    /// Usage accumulated since an earlier snapshot of the same running total
+    fn since(&self, earlier: &TokenStats) -> TokenStats {
+        TokenStats {
+            input_tokens: self.input_tokens.saturating_sub(earlier.input_tokens),
+            output_tokens: self.output_tokens.saturating_sub(earlier.output_tokens),
+            generation_time: self.generation_time.saturating_sub(earlier.generation_time),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.input_tokens == 0 && self.output_tokens == 0
+    }
+
+    /// Short per-finding cost line, `None` when nothing was generated (cached or rule-based)
+    fn usage_label(&self) -> Option<String> {
+        if self.is_empty() {
+            return None;
+        }
+        Some(format!(
+            "{} in / {} out tokens, {:.1}s",
+            self.input_tokens,
+            self.output_tokens,
+            self.generation_time.as_secs_f64()
+        ))
+    }
+
     fn tokens_per_second(&self) -> f64 {
         if self.generation_time.as_secs_f64() > 0.0 {
             self.output_tokens as f64 / self.generation_time.as_secs_f64()
@@ -139,7 +504,7 @@ impl TokenStats {
     }
 }
 
-#[derive(Clone, Serialize)]
+#[derive(Clone, Default, Serialize, Deserialize)]
 struct FunctionInfo {
     name: String,
     #[serde(skip)]
@@ -149,6 +514,42 @@ struct FunctionInfo {
     file_path: PathBuf,
     line_number: usize,
     class_name: Option<String>,
+    /// With --nested-functions, the qualified name of the function this one is defined in
+    enclosing_function: Option<String>,
+    /// Body is only `...`/`pass`/docstring/`raise NotImplementedError` (nothing to analyze)
+    #[serde(skip)]
+    is_stub: bool,
+    /// Whole-class unit for class-scoped checks (`name` is the class name)
+    #[serde(skip)]
+    is_class_scope: bool,
+    /// The function calls itself directly (`f(...)`, or `self.f(...)` in a method)
+    is_recursive: bool,
+    /// For methods, the `self.x = ...` assignments of the class's `__init__` ({class_context})
+    #[serde(skip)]
+    class_context: String,
+    /// Decorators as written, one `@...` line each ({decorators})
+    #[serde(skip)]
+    decorators: Vec<String>,
+    /// Dotted module path relative to the analyzed root (e.g. `app.models.user`)
+    module_path: String,
+    /// --include-context-lines of the parsed source above the function, for reports only
+    #[serde(skip)]
+    context_above: Option<String>,
+    /// Lines from `def`/`class` up to the body, so a multi-line signature counts whole
+    #[serde(skip)]
+    header_lines: usize,
+}
+
+impl FunctionInfo {
+    /// `Class::method`, with any enclosing functions in front (`outer::inner`)
+    fn qualified_name(&self) -> String {
+        self.enclosing_function.iter()
+            .chain(&self.class_name)
+            .chain(std::iter::once(&self.name))
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join("::")
+    }
 }
 
 /// Configuration for a single check loaded from TOML
@@ -167,25 +568,141 @@ struct CheckConfig {
     solution_prompt: String,
     #[serde(default = "default_verifier_prompt")]  // For backward compat
     verifier_prompt: String,
+    /// Prompt for a pytest/timeit snippet demonstrating an accepted solution (--with-tests);
+    /// empty means no test phase for this check
+    #[serde(default)]
+    test_prompt: String,
     #[serde(default)]
     guard: GuardConfig,
+    /// Optional regex used instead of the built-in call/dotted patterns to pick
+    /// hotspot tokens out of the DETAIL line (first capture group if present)
+    #[serde(default)]
+    highlight_regex: Option<String>,
+    /// Cheap detection hint: functions that don't match are reported as pre-filtered instead
+    /// of being sent to the model (counted separately from guard skips)
+    #[serde(default)]
+    prefilter_regex: Option<String>,
+    /// `prefilter_regex`, compiled once when the config is loaded
+    #[serde(skip)]
+    prefilter: Option<Regex>,
+    /// Analysis unit: individual functions (default) or whole classes
+    #[serde(default)]
+    scope: CheckScope,
+    /// Shape of the detection response the prompt asks the model for
+    #[serde(default)]
+    detection_format: DetectionFormat,
+    /// Sampling temperature for detection (default: --temperature)
+    #[serde(default)]
+    detection_temperature: Option<f32>,
+    /// Sampling temperature for solution generation (default: --temperature)
+    #[serde(default)]
+    solution_temperature: Option<f32>,
+    /// Number of verifier runs; with more than one, a majority must say VALID
+    #[serde(default = "default_verifier_rounds")]
+    verifier_rounds: u32,
+    /// Number of solutions to generate; the ones that pass validation and the verifier are
+    /// reported, best verifier confidence first
+    #[serde(default = "default_solution_candidates")]
+    solution_candidates: u32,
+    /// How the suggested fix is presented
+    #[serde(default)]
+    solution_format: SolutionFormat,
+    /// Start of the assistant turn in the detection prompt; extra context (e.g. for `__init__`)
+    /// is inserted before it. Defaults to ChatML's `<|im_start|>assistant`; empty means append
+    #[serde(default)]
+    assistant_marker: Option<String>,
+    /// Whether the check runs on test files (see --test-pattern)
+    #[serde(default = "default_applies_to_tests")]
+    applies_to_tests: bool,
+    /// Add the "constructors are rarely hot" note to `__init__` detection prompts.
+    /// Defaults to `settings.init_special_case` (true)
+    #[serde(default)]
+    init_special_case: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum DetectionFormat {
+    /// VERDICT: OK|{keyword}, CONFIDENCE, DETAIL, END
+    #[default]
+    Verdict,
+    /// A leading YES or NO, optionally followed by CONFIDENCE/DETAIL lines
+    YesNo,
+    /// {"issue": true, "confidence": 0.9, "detail": "..."}
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum SolutionFormat {
+    /// Unified diff against the original function
+    #[default]
+    Diff,
+    /// The validated, rewritten function in full
+    Full,
+    /// The model's recommendation as text, with no code extraction or validation
+    Prose,
+}
+
+impl SolutionFormat {
+    /// Report heading above the solution
+    fn heading(self) -> &'static str {
+        match self {
+            SolutionFormat::Diff | SolutionFormat::Full => "Suggested Optimization",
+            SolutionFormat::Prose => "Recommendation",
+        }
+    }
+}
+
+/// Solution format of the check that produced a result (the default for unknown keys)
+fn solution_format_for(checks: &[CheckConfig], check_key: &str) -> SolutionFormat {
+    checks.iter()
+        .find(|check| check.key == check_key)
+        .map(|check| check.solution_format)
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum CheckScope {
+    #[default]
+    Function,
+    /// The full ClassDef source (methods included) is passed as {function_source}
+    Class,
 }
 
 fn default_verifier_prompt() -> String {
     String::from("")  // Empty default for transition
 }
 
+fn default_verifier_rounds() -> u32 {
+    1
+}
+
+fn default_solution_candidates() -> u32 {
+    1
+}
+
+fn default_applies_to_tests() -> bool {
+    true
+}
+
 #[derive(Debug, Clone)]
 struct ParsedDetection {
     has_issue: bool,
     confidence: Option<f32>,
     _detail: String,  // Reserved for future use
+    /// Optional `LINES:` field: hotspot lines relative to the function shown (1 = `def` line)
+    lines: Vec<usize>,
+    /// Whether the response held a verdict at all; without one it is reported as clean
+    verdict_found: bool,
 }
 
 #[derive(Debug, Clone)]
 struct VerificationResult {
     is_valid: bool,
     reason: String,
+    confidence: Option<f32>,
 }
 
 /// Optional settings from config file
@@ -196,8 +713,29 @@ struct ConfigSettings {
     max_tokens: Option<i32>,
     context_size: Option<u32>,
     skip_large: Option<usize>,
+    skip_small: Option<usize>,
     cache_dir: Option<PathBuf>,
     api_url: Option<String>,
+    /// Start of the assistant turn in the chat template, for checks that don't set their own
+    assistant_marker: Option<String>,
+    skip_tests: Option<bool>,
+    test_patterns: Option<Vec<String>>,
+    /// Default for the checks' `init_special_case`
+    init_special_case: Option<bool>,
+}
+
+/// A named bundle of model settings from `[model_profiles.NAME]`, selected with --model-profile.
+/// Its values take precedence over `[settings]`
+#[derive(Debug, Clone, Deserialize, Serialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ModelProfile {
+    model: Option<PathBuf>,
+    threads: Option<u32>,
+    max_tokens: Option<i32>,
+    context_size: Option<u32>,
+    batch_size: Option<u32>,
+    temperature: Option<f32>,
+    gpu_layers: Option<u32>,
 }
 
 /// Configuration for API-based inference
@@ -215,6 +753,8 @@ struct ChecksConfig {
     settings: ConfigSettings,
     #[serde(default)]
     templates: std::collections::HashMap<String, String>,
+    #[serde(default)]
+    model_profiles: std::collections::HashMap<String, ModelProfile>,
     check: Vec<CheckConfig>,
     #[serde(default)]
     dedupe: Vec<DedupeRule>,
@@ -222,10 +762,25 @@ struct ChecksConfig {
 
 #[derive(Debug, Clone, Deserialize, Serialize, Default)]
 struct DedupeRule {
+    #[serde(default)]
+    strategy: DedupeStrategy,
     #[serde(default)]
     prefer: String,
     #[serde(default)]
     drop: Vec<String>,
+    /// Check keys competing under the highest_confidence strategy
+    #[serde(default)]
+    group: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum DedupeStrategy {
+    /// Drop the `drop` checks whenever the `prefer` check fires
+    #[default]
+    Prefer,
+    /// Among the `group` checks that fire, keep only the one with the highest confidence
+    HighestConfidence,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, Default)]
@@ -242,25 +797,133 @@ struct GuardConfig {
     require_regex_all: Vec<String>,
     #[serde(default)]
     exclude_regex_any: Vec<String>,
+    /// Only run when the function's module path matches one of these regexes
+    #[serde(default)]
+    module_regex_any: Vec<String>,
+    /// Skip functions whose module path matches one of these regexes
+    #[serde(default)]
+    exclude_module_regex_any: Vec<String>,
+    /// Only run on functions of this kind (`recursive` or `decorated`)
+    #[serde(default)]
+    applies_to: Option<GuardTarget>,
+}
+
+/// Kinds of functions a guard can restrict a check to
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum GuardTarget {
+    /// Functions that call themselves directly
+    Recursive,
+    /// Functions (or classes) with at least one decorator
+    Decorated,
+}
+
+/// A fully assembled prompt that remembers where the per-function part starts.
+/// Everything before the first per-function placeholder is the same for every function
+/// of a check, so its tokens can be computed once and reused.
+#[derive(Debug, Clone)]
+struct Prompt {
+    text: String,
+    prefix_len: usize,
+}
+
+impl Prompt {
+    /// `prefix` is the template text before the first per-function placeholder, with
+    /// `{name}` and `{keyword}` already substituted; it only counts if `text` really starts with it
+    fn new(text: String, prefix: &str) -> Self {
+        let prefix_len = if text.starts_with(prefix) { prefix.len() } else { 0 };
+        Prompt { text, prefix_len }
+    }
+
+    fn prefix(&self) -> &str {
+        &self.text[..self.prefix_len]
+    }
+
+    fn suffix(&self) -> &str {
+        &self.text[self.prefix_len..]
+    }
 }
 
+/// Placeholders whose value changes from one function (or solution) to the next
+const PER_FUNCTION_PLACEHOLDERS: &[&str] = &[
+    "{function_source}",
+    "{solution}",
+    "{module}",
+    "{is_recursive}",
+    "{class_context}",
+    "{decorators}",
+];
+
+/// Template text before the first per-function placeholder (empty if there is none). Stopping
+/// at e.g. `{decorators}` too keeps the prefix the same for every function of a check
+fn template_prefix(template: &str) -> &str {
+    PER_FUNCTION_PLACEHOLDERS
+        .iter()
+        .filter_map(|placeholder| template.find(placeholder))
+        .min()
+        .map(|pos| &template[..pos])
+        .unwrap_or("")
+}
+
+/// ChatML assistant turn, used by the built-in prompts
+const DEFAULT_ASSISTANT_MARKER: &str = "<|im_start|>assistant";
+
 impl CheckConfig {
+    /// Hash of everything that shapes this check's prompts; cached results produced with
+    /// other prompts (e.g. before an upgrade or a prompt tweak) are re-analyzed
+    fn prompt_hash(&self) -> String {
+        let prompts = [
+            &self.detection_rules,
+            &self.fix_recipes,
+            &self.detection_prompt,
+            &self.solution_prompt,
+            &self.verifier_prompt,
+        ];
+        let mut joined: Vec<&str> = prompts.iter().map(|prompt| prompt.as_str()).collect();
+        // Only mixed in when off, so the default keeps existing cache entries valid
+        if self.init_special_case == Some(false) {
+            joined.push("init_special_case=false");
+        }
+        // Rows cached with a different number of candidates would lack (or have extra) alternatives
+        let candidates = format!("solution_candidates={}", self.solution_candidates);
+        if self.solution_candidates != 1 {
+            joined.push(&candidates);
+        }
+        // Cached solutions are stored in the format they were asked for
+        let format = format!("solution_format={:?}", self.solution_format);
+        if self.solution_format != SolutionFormat::default() {
+            joined.push(&format);
+        }
+        AnalysisCache::hash_function(&joined.join("\0"))[..16].to_string()
+    }
+
+    /// Class units only go to class-scoped checks, and functions only to the others
+    fn applies_to_unit(&self, func: &FunctionInfo) -> bool {
+        (self.scope == CheckScope::Class) == func.is_class_scope
+    }
+
     /// Generate detection prompt by substituting function source
-    fn format_detection_prompt(&self, func: &FunctionInfo) -> String {
-        let mut prompt = self.detection_prompt
-            .replace("{function_source}", &func.source_no_docstring)
+    fn format_detection_prompt(&self, func: &FunctionInfo) -> Prompt {
+        let substitute = |text: &str| text
             .replace("{name}", &self.name)
-            .replace("{keyword}", &self.keyword);
+            .replace("{keyword}", &self.keyword)
+            .replace("{module}", &func.module_path)
+            .replace("{is_recursive}", if func.is_recursive { "true" } else { "false" })
+            .replace("{class_context}", &func.class_context)
+            .replace("{decorators}", &func.decorators.join("\n"));
+        let mut prompt = substitute(&self.detection_prompt
+            .replace("{function_source}", &func.source_no_docstring));
 
         // Add special context for __init__ methods to reduce false positives
-        if func.name == "__init__" {
+        if func.name == "__init__" && func.class_name.is_some() && self.init_special_case.unwrap_or(true) {
             let context = "\n\nIMPORTANT: This is an __init__ (constructor) method that initializes object state. \
                           Constructor methods typically run once per object and should NOT be flagged unless they \
                           have genuine algorithmic complexity issues (like nested loops over input data). \
                           Simple attribute assignments and one-time setup calls are NOT performance issues.\n";
 
             // Insert the context before the final assistant prompt marker
-            if let Some(pos) = prompt.rfind("<|im_start|>assistant") {
+            let marker = self.assistant_marker.as_deref().unwrap_or(DEFAULT_ASSISTANT_MARKER);
+            if let Some(pos) = prompt.rfind(marker).filter(|_| !marker.is_empty()) {
                 prompt.insert_str(pos, context);
             } else {
                 // Fallback: append at the end
@@ -268,31 +931,68 @@ impl CheckConfig {
             }
         }
 
-        prompt
+        Prompt::new(prompt, &substitute(template_prefix(&self.detection_prompt)))
     }
 
     /// Generate solution prompt by substituting function source
-    fn format_solution_prompt(&self, func: &FunctionInfo) -> String {
-        self.solution_prompt
-            .replace("{function_source}", &func.source_no_docstring)
+    fn format_solution_prompt(&self, func: &FunctionInfo) -> Prompt {
+        let substitute = |text: &str| text
             .replace("{keyword}", &self.keyword)
+            .replace("{module}", &func.module_path)
+            .replace("{is_recursive}", if func.is_recursive { "true" } else { "false" })
+            .replace("{class_context}", &func.class_context)
+            .replace("{decorators}", &func.decorators.join("\n"));
+        let text = substitute(&self.solution_prompt
+            .replace("{function_source}", &func.source_no_docstring));
+        Prompt::new(text, &substitute(template_prefix(&self.solution_prompt)))
     }
 
     /// Generate verifier prompt by substituting function source and solution
-    fn format_verifier_prompt(&self, func: &FunctionInfo, solution: &str) -> String {
-        self.verifier_prompt
-            .replace("{function_source}", &func.source_no_docstring)
+    fn format_verifier_prompt(&self, func: &FunctionInfo, solution: &str) -> Prompt {
+        let substitute = |text: &str| text
+            .replace("{solution}", solution)
+            .replace("{keyword}", &self.keyword)
+            .replace("{module}", &func.module_path)
+            .replace("{is_recursive}", if func.is_recursive { "true" } else { "false" })
+            .replace("{class_context}", &func.class_context)
+            .replace("{decorators}", &func.decorators.join("\n"));
+        let text = substitute(&self.verifier_prompt
+            .replace("{function_source}", &func.source_no_docstring));
+        Prompt::new(text, &substitute(template_prefix(&self.verifier_prompt)))
+    }
+
+    /// Generate test prompt by substituting function source and the accepted solution
+    fn format_test_prompt(&self, func: &FunctionInfo, solution: &str) -> Prompt {
+        let substitute = |text: &str| text
             .replace("{solution}", solution)
             .replace("{keyword}", &self.keyword)
+            .replace("{module}", &func.module_path)
+            .replace("{is_recursive}", if func.is_recursive { "true" } else { "false" })
+            .replace("{class_context}", &func.class_context)
+            .replace("{decorators}", &func.decorators.join("\n"));
+        let text = substitute(&self.test_prompt
+            .replace("{function_source}", &func.source_no_docstring));
+        Prompt::new(text, &substitute(template_prefix(&self.test_prompt)))
+    }
+
+    /// Parse detection output according to the check's detection_format
+    fn parse_detection(&self, response: &str) -> ParsedDetection {
+        match self.detection_format {
+            DetectionFormat::Verdict => self.parse_verdict_detection(response),
+            DetectionFormat::YesNo => parse_yes_no_detection(response),
+            DetectionFormat::Json => parse_json_detection(response),
+        }
     }
 
     /// Parse structured detection output
     /// Expected format: VERDICT: OK|{keyword}, CONFIDENCE: 0.0-1.0, DETAIL: text, END
     /// Also handles: "{keyword}: confidence" format from some models
-    fn parse_detection(&self, response: &str) -> ParsedDetection {
+    fn parse_verdict_detection(&self, response: &str) -> ParsedDetection {
         let mut has_issue = false;
+        let mut verdict_found = false;
         let mut confidence: Option<f32> = None;
         let mut detail = String::new();
+        let mut lines = Vec::new();
         let keyword_upper = self.keyword.to_uppercase();
 
         for line in response.lines() {
@@ -301,9 +1001,11 @@ impl CheckConfig {
             if trimmed.starts_with("VERDICT:") {
                 let verdict = trimmed[8..].trim().to_uppercase();
                 has_issue = verdict.starts_with(&keyword_upper);
+                verdict_found |= has_issue || verdict.starts_with("OK");
             } else if trimmed.to_uppercase().starts_with(&format!("{}:", keyword_upper)) {
                 // Handle "KEYWORD: confidence" format (e.g. "QUADRATIC: 0.99")
                 has_issue = true;
+                verdict_found = true;
                 let after_keyword = &trimmed[self.keyword.len() + 1..];
                 if let Ok(val) = after_keyword.trim().parse::<f32>() {
                     confidence = Some(val.clamp(0.0, 1.0));
@@ -314,19 +1016,111 @@ impl CheckConfig {
                 }
             } else if trimmed.starts_with("DETAIL:") {
                 detail = trimmed[7..].trim().to_string();
+            } else if let Some(value) = trimmed.strip_prefix("LINES:") {
+                lines = parse_line_list(value);
             } else if trimmed == "END" {
                 break;
             }
         }
 
-        ParsedDetection { has_issue, confidence, _detail: detail }
+        ParsedDetection { has_issue, confidence, _detail: detail, lines, verdict_found }
+    }
+}
+
+/// Parse a YES/NO detection: the first non-empty line decides, and optional
+/// CONFIDENCE:/DETAIL: lines are read as in the verdict format
+fn parse_yes_no_detection(response: &str) -> ParsedDetection {
+    let mut lines = response.lines().map(str::trim).filter(|line| !line.is_empty());
+    let answer = lines.next().unwrap_or("")
+        .trim_start_matches(|c: char| !c.is_ascii_alphabetic())
+        .to_uppercase();
+    let has_issue = answer.starts_with("YES");
+    let verdict_found = has_issue || answer.starts_with("NO");
+
+    let mut confidence: Option<f32> = None;
+    let mut detail = String::new();
+    let mut hotspot_lines = Vec::new();
+    for line in lines {
+        if let Some(value) = line.strip_prefix("CONFIDENCE:") {
+            if let Ok(val) = value.trim().parse::<f32>() {
+                confidence = Some(val.clamp(0.0, 1.0));
+            }
+        } else if let Some(value) = line.strip_prefix("DETAIL:") {
+            detail = value.trim().to_string();
+        } else if let Some(value) = line.strip_prefix("LINES:") {
+            hotspot_lines = parse_line_list(value);
+        } else if line == "END" {
+            break;
+        }
+    }
+
+    ParsedDetection { has_issue, confidence, _detail: detail, lines: hotspot_lines, verdict_found }
+}
+
+/// Parse a JSON detection object, tolerating prose or code fences around it
+fn parse_json_detection(response: &str) -> ParsedDetection {
+    let value = match (response.find('{'), response.rfind('}')) {
+        (Some(start), Some(end)) if start < end => {
+            serde_json::from_str::<serde_json::Value>(&response[start..=end]).ok()
+        }
+        _ => None,
+    };
+    let Some(value) = value else {
+        return ParsedDetection { has_issue: false, confidence: None, _detail: String::new(), lines: Vec::new(), verdict_found: false };
+    };
+
+    ParsedDetection {
+        has_issue: value["issue"].as_bool().unwrap_or(false),
+        confidence: value["confidence"].as_f64().map(|c| (c as f32).clamp(0.0, 1.0)),
+        _detail: value["detail"].as_str().unwrap_or("").to_string(),
+        lines: value["lines"].as_array()
+            .map(|lines| lines.iter().filter_map(|n| n.as_u64()).map(|n| n as usize).collect())
+            .unwrap_or_default(),
+        verdict_found: value["issue"].is_boolean(),
+    }
+}
+
+/// Parse a `LINES:` value such as `3, 5-7` into line numbers (invalid entries are ignored)
+fn parse_line_list(value: &str) -> Vec<usize> {
+    let mut lines = Vec::new();
+    for part in value.split([',', ' ']).map(str::trim).filter(|part| !part.is_empty()) {
+        match part.split_once('-') {
+            Some((start, end)) => {
+                if let (Ok(start), Ok(end)) = (start.trim().parse::<usize>(), end.trim().parse::<usize>()) {
+                    // Cap ranges so a hallucinated "1-999999" can't blow up
+                    lines.extend((start..=end).take(500));
+                }
+            }
+            None => lines.extend(part.parse::<usize>().ok()),
+        }
     }
+    lines
+}
+
+/// Map function-relative `LINES:` (as numbered in the docstring-free source the model saw)
+/// to file line numbers, dropping lines outside the function
+fn validated_detection_lines(lines: &[usize], func: &FunctionInfo) -> Vec<usize> {
+    // strip_docstring only drops whole lines, so each shown line maps back to a source line
+    let mut source_lines = func.source.lines().enumerate();
+    let positions: Vec<usize> = func.source_no_docstring
+        .lines()
+        .map_while(|shown| source_lines.by_ref().find(|(_, line)| *line == shown).map(|(idx, _)| idx))
+        .collect();
+
+    let mut file_lines: Vec<usize> = lines.iter()
+        .filter_map(|&line| positions.get(line.checked_sub(1)?))
+        .map(|&idx| func.line_number + idx)
+        .collect();
+    file_lines.sort_unstable();
+    file_lines.dedup();
+    file_lines
 }
 
 /// Parse verifier output
 fn parse_verification_result(response: &str) -> VerificationResult {
     let mut is_valid = false;
     let mut reason = String::new();
+    let mut confidence: Option<f32> = None;
 
     for line in response.lines() {
         let trimmed = line.trim();
@@ -336,62 +1130,461 @@ fn parse_verification_result(response: &str) -> VerificationResult {
             is_valid = verdict == "VALID";
         } else if trimmed.starts_with("REASON:") {
             reason = trimmed[7..].trim().to_string();
+        } else if let Some(value) = trimmed.strip_prefix("CONFIDENCE:") {
+            if let Ok(val) = value.trim().parse::<f32>() {
+                confidence = Some(val.clamp(0.0, 1.0));
+            }
         } else if trimmed == "END" {
             break;
         }
     }
 
-    VerificationResult { is_valid, reason }
+    VerificationResult { is_valid, reason, confidence }
 }
 
-/// Get the default built-in checks configuration as a TOML string
-fn get_default_config_toml() -> &'static str {
-    include_str!("../loopsleuth.toml")
-}
+/// Sampling temperature floor for repeated verifier rounds, so the votes aren't all the same answer
+const VERIFIER_VOTE_TEMPERATURE: f32 = 0.7;
 
-/// Apply config settings to CLI arguments (CLI takes precedence)
-fn apply_config_settings(cli: &mut Cli, config: &ChecksConfig) {
-    let settings = &config.settings;
+/// Sampling temperature floor when a check asks for several solution candidates
+const SOLUTION_CANDIDATE_TEMPERATURE: f32 = 0.7;
 
-    // Only apply config settings if CLI argument wasn't provided
-    if cli.model.is_none() {
-        cli.model = settings.model.clone();
-    }
-    if cli.threads == 4 && settings.threads.is_some() {
-        // 4 is the default, so override with config if present
-        cli.threads = settings.threads.unwrap();
-    }
-    if cli.max_tokens == 1024 && settings.max_tokens.is_some() {
-        // 1024 is the default, so override with config if present
-        cli.max_tokens = settings.max_tokens.unwrap();
-    }
-    if cli.context_size == 4096 && settings.context_size.is_some() {
-        // 4096 is the default, so override with config if present
-        cli.context_size = settings.context_size.unwrap();
-    }
-    if cli.skip_large == 0 && settings.skip_large.is_some() {
-        // 0 is the default, so override with config if present
-        cli.skip_large = settings.skip_large.unwrap();
-    }
-    if cli.cache_dir.is_none() {
-        cli.cache_dir = settings.cache_dir.clone();
-    }
-    if cli.api_url.is_none() {
-        cli.api_url = settings.api_url.clone();
-    }
+/// Order accepted solution candidates by verifier confidence (unverified ones keep their
+/// place at the end); returns the best solution, its confidence and the runners-up
+fn rank_solution_candidates(mut accepted: Vec<(String, Option<f32>)>) -> Option<(String, Option<f32>, Vec<String>)> {
+    // Stable, so equally confident candidates stay in generation order
+    accepted.sort_by(|a, b| b.1.unwrap_or(-1.0).total_cmp(&a.1.unwrap_or(-1.0)));
+    let mut ranked = accepted.into_iter();
+    let (best, confidence) = ranked.next()?;
+    Some((best, confidence, ranked.map(|(solution, _)| solution).collect()))
 }
 
-/// Load checks configuration from file or use defaults
-fn load_checks_config(config_path: Option<PathBuf>) -> Result<ChecksConfig> {
-    // If specific config file provided, load it
-    if let Some(path) = config_path {
-        let content = fs::read_to_string(&path)
+/// Run the check's verifier on an optimized function, `verifier_rounds` times with a majority vote
+fn verify_solution<F>(
+    check: &CheckConfig,
+    func: &FunctionInfo,
+    optimized_code: &str,
+    max_tokens: i32,
+    temperature: f32,
+    verbose: bool,
+    generate_fn: &mut F,
+) -> Option<(VerificationResult, TokenStats)>
+where
+    F: FnMut(&Prompt, i32, f32, bool) -> Result<(String, StopReason, TokenStats)>,
+{
+    let rounds = check.verifier_rounds.max(1);
+    let temperature = if rounds > 1 { temperature.max(VERIFIER_VOTE_TEMPERATURE) } else { temperature };
+    let verifier_input = format!("```python\n{}\n```", optimized_code);
+    let verifier_prompt = check.format_verifier_prompt(func, &verifier_input);
+
+    let mut stats = TokenStats::default();
+    let mut votes = Vec::new();
+    for _ in 0..rounds {
+        let verifier_result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            generate_fn(&verifier_prompt, max_tokens, temperature, verbose)
+        }))
+        .ok()
+        .and_then(|r| r.ok());
+        if let Some((verifier_output, _stop, round_stats)) = verifier_result {
+            stats.add(&round_stats);
+            votes.push(parse_verification_result(&verifier_output));
+        }
+    }
+    majority_verdict(votes).map(|verification| (verification, stats))
+}
+
+/// Run the check's test prompt on an accepted solution and return the snippet it produced
+fn generate_test<F>(
+    check: &CheckConfig,
+    func: &FunctionInfo,
+    solution: &str,
+    max_tokens: i32,
+    temperature: f32,
+    verbose: bool,
+    generate_fn: &mut F,
+) -> Result<(String, TokenStats)>
+where
+    F: FnMut(&Prompt, i32, f32, bool) -> Result<(String, StopReason, TokenStats)>,
+{
+    let solution = match optimized_code_from_solution(solution) {
+        Some(code) => format!("```python\n{}\n```", code.trim_end()),
+        None => solution.to_string(),
+    };
+    let prompt = check.format_test_prompt(func, &solution);
+    let (response, _stop, stats) = generate_fn(&prompt, max_tokens, temperature, verbose)?;
+    let snippet = extract_test_snippet(&response)
+        .ok_or_else(|| anyhow::anyhow!("no test code in the response"))?;
+    Ok((snippet, stats))
+}
+
+/// Code of a test response: the first ```python block, or everything up to the closing fence
+/// when the prompt already opened one
+fn extract_test_snippet(response: &str) -> Option<String> {
+    let code = match response.find("```python") {
+        Some(start) => {
+            let body = &response[start + "```python".len()..];
+            &body[..body.find("```").unwrap_or(body.len())]
+        }
+        None => &response[..response.find("```").unwrap_or(response.len())],
+    };
+    let code = code.trim_matches('\n').trim_end();
+    (!code.trim().is_empty()).then(|| code.to_string())
+}
+
+/// Combine verifier votes: VALID only with a strict majority (ties reject). The reasons and
+/// confidences of the winning side are aggregated; a single vote is returned unchanged.
+fn majority_verdict(mut votes: Vec<VerificationResult>) -> Option<VerificationResult> {
+    if votes.len() <= 1 {
+        return votes.pop();
+    }
+    let total = votes.len();
+    let valid_votes = votes.iter().filter(|vote| vote.is_valid).count();
+    let is_valid = valid_votes * 2 > total;
+    let majority: Vec<&VerificationResult> = votes.iter().filter(|vote| vote.is_valid == is_valid).collect();
+
+    let mut reasons: Vec<&str> = Vec::new();
+    for vote in &majority {
+        if !vote.reason.is_empty() && !reasons.contains(&vote.reason.as_str()) {
+            reasons.push(&vote.reason);
+        }
+    }
+    let confidences: Vec<f32> = majority.iter().filter_map(|vote| vote.confidence).collect();
+    let confidence = (!confidences.is_empty())
+        .then(|| confidences.iter().sum::<f32>() / confidences.len() as f32);
+
+    Some(VerificationResult {
+        is_valid,
+        reason: format!(
+            "{}/{} verifier rounds {}: {}",
+            majority.len(), total, if is_valid { "accepted" } else { "rejected" }, reasons.join("; ")
+        ),
+        confidence,
+    })
+}
+
+/// Outcome of repeating detection with --stabilize
+#[derive(Debug, Clone, PartialEq)]
+struct DetectionVote {
+    issue_votes: usize,
+    rounds: usize,
+    /// Average confidence of the rounds on the winning side
+    confidence: Option<f32>,
+}
+
+impl DetectionVote {
+    /// An issue needs a strict majority of the rounds; ties count as clean
+    fn has_issue(&self) -> bool {
+        self.issue_votes * 2 > self.rounds
+    }
+
+    /// Vote breakdown appended to the stored analysis
+    fn note(&self) -> String {
+        format!("[Stabilized: {}/{} detection rounds flagged an issue]", self.issue_votes, self.rounds)
+    }
+}
+
+fn tally_detections(detections: &[ParsedDetection]) -> DetectionVote {
+    let issue_votes = detections.iter().filter(|d| d.has_issue).count();
+    let has_issue = issue_votes * 2 > detections.len();
+    let confidences: Vec<f32> = detections.iter()
+        .filter(|d| d.has_issue == has_issue)
+        .filter_map(|d| d.confidence)
+        .collect();
+    let confidence = (!confidences.is_empty())
+        .then(|| confidences.iter().sum::<f32>() / confidences.len() as f32);
+    DetectionVote { issue_votes, rounds: detections.len(), confidence }
+}
+
+/// Run detection `rounds` times. Returns the first response that agrees with the majority
+/// (so its DETAIL and LINES match the kept verdict), the summed usage and the vote.
+fn stabilized_detection<F>(
+    check: &CheckConfig,
+    prompt: &Prompt,
+    rounds: u32,
+    max_tokens: i32,
+    temperature: f32,
+    verbose: bool,
+    generate_fn: &mut F,
+) -> Result<(String, StopReason, TokenStats, DetectionVote)>
+where
+    F: FnMut(&Prompt, i32, f32, bool) -> Result<(String, StopReason, TokenStats)>,
+{
+    let mut stats = TokenStats::default();
+    let mut responses = Vec::new();
+    for _ in 0..rounds.max(1) {
+        let (text, stop_reason, round_stats) = generate_fn(prompt, max_tokens, temperature, verbose)?;
+        stats.add(&round_stats);
+        responses.push((text, stop_reason));
+    }
+
+    let detections: Vec<ParsedDetection> = responses.iter().map(|(text, _)| check.parse_detection(text)).collect();
+    let vote = tally_detections(&detections);
+    let winner = detections.iter().position(|d| d.has_issue == vote.has_issue()).unwrap_or(0);
+    let (text, stop_reason) = responses.swap_remove(winner);
+    Ok((text, stop_reason, stats, vote))
+}
+
+/// Why a verification rejects a solution, if it does (invalid verdict or confidence below the minimum)
+fn verifier_rejection(verification: &VerificationResult, verify_min_confidence: Option<f32>) -> Option<String> {
+    if !verification.is_valid {
+        return Some(verification.reason.clone());
+    }
+    match (verification.confidence, verify_min_confidence) {
+        (Some(conf), Some(min)) if conf < min => Some(format!(
+            "verifier confidence {:.2} below --verify-min-confidence {:.2}",
+            conf, min
+        )),
+        _ => None,
+    }
+}
+
+/// Recover the optimized function from a cached solution: a ```python rewrite as is, or a
+/// ```diff rebuilt from its context and added lines
+fn optimized_code_from_solution(solution: &str) -> Option<String> {
+    if let Some(code) = solution.trim().strip_prefix("```python\n").and_then(|rest| rest.strip_suffix("```")) {
+        return (!code.trim().is_empty()).then(|| code.to_string());
+    }
+    let body = solution.trim().strip_prefix("```diff")?.strip_suffix("```")?;
+    let code: String = body
+        .lines()
+        .filter_map(|line| line.strip_prefix('+').or_else(|| line.strip_prefix(' ')))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    (!code.trim().is_empty()).then_some(code)
+}
+
+/// Get the default built-in checks configuration as a TOML string
+fn get_default_config_toml() -> &'static str {
+    include_str!("../loopsleuth.toml")
+}
+
+const DEFAULT_BATCH_SIZE: u32 = 4096;
+
+/// A batch can never hold more tokens than the context, so --batch-size is capped there.
+/// Returns the effective size and a warning when an explicit value had to be lowered.
+fn resolve_batch_size(requested: u32, context_size: u32) -> (u32, Option<String>) {
+    if requested <= context_size {
+        return (requested, None);
+    }
+    let warning = (requested != DEFAULT_BATCH_SIZE).then(|| format!(
+        "--batch-size {} exceeds --context-size {}; using {}",
+        requested, context_size, context_size
+    ));
+    (context_size, warning)
+}
+
+/// Resolve a --threads value, where 0 means every logical core
+fn resolve_threads(requested: u32) -> u32 {
+    if requested > 0 {
+        return requested;
+    }
+    std::thread::available_parallelism()
+        .map(|n| n.get() as u32)
+        .unwrap_or(4)
+}
+
+/// Apply config settings to CLI arguments (CLI takes precedence)
+fn apply_config_settings(cli: &mut Cli, config: &ChecksConfig) -> Result<()> {
+    let settings = &effective_settings(cli, config)?;
+    if let Some(profile) = cli.model_profile.as_ref().and_then(|name| config.model_profiles.get(name)) {
+        if let (false, Some(temperature)) = (cli.is_explicit("temperature"), profile.temperature) {
+            cli.temperature = temperature;
+        }
+        if let (false, Some(batch_size)) = (cli.is_explicit("batch_size"), profile.batch_size) {
+            cli.batch_size = batch_size;
+        }
+        if let (false, Some(gpu_layers)) = (cli.is_explicit("gpu_layers"), profile.gpu_layers) {
+            cli.gpu_layers = Some(gpu_layers);
+        }
+    }
+
+    // Only apply config settings if CLI argument wasn't provided
+    if cli.model.is_none() {
+        cli.model = settings.model.clone();
+    }
+    if let (false, Some(threads)) = (cli.is_explicit("threads"), settings.threads) {
+        cli.threads = threads;
+    }
+    cli.threads = resolve_threads(cli.threads);
+    if let (false, Some(max_tokens)) = (cli.is_explicit("max_tokens"), settings.max_tokens) {
+        cli.max_tokens = max_tokens;
+    }
+    if let (false, Some(context_size)) = (cli.is_explicit("context_size"), settings.context_size) {
+        cli.context_size = context_size;
+    }
+    if let (false, Some(skip_large)) = (cli.is_explicit("skip_large"), settings.skip_large) {
+        cli.skip_large = skip_large;
+    }
+    if let (false, Some(skip_small)) = (cli.is_explicit("skip_small"), settings.skip_small) {
+        cli.skip_small = skip_small;
+    }
+    if cli.cache_dir.is_none() {
+        cli.cache_dir = settings.cache_dir.clone();
+    }
+    if cli.api_url.is_none() {
+        cli.api_url = settings.api_url.clone();
+    }
+    if !cli.analyze_tests && !cli.skip_tests {
+        cli.skip_tests = settings.skip_tests.unwrap_or(false);
+    }
+    if cli.test_pattern.is_empty() {
+        cli.test_pattern = settings.test_patterns.clone()
+            .unwrap_or_else(|| DEFAULT_TEST_PATTERNS.iter().map(|p| p.to_string()).collect());
+    }
+    Ok(())
+}
+
+/// `[settings]` with the --model-profile bundle laid over it
+fn effective_settings(cli: &Cli, config: &ChecksConfig) -> Result<ConfigSettings> {
+    let mut settings = config.settings.clone();
+    let Some(name) = &cli.model_profile else {
+        return Ok(settings);
+    };
+    let Some(profile) = config.model_profiles.get(name) else {
+        let mut known: Vec<&str> = config.model_profiles.keys().map(String::as_str).collect();
+        known.sort_unstable();
+        anyhow::bail!(
+            "Unknown model profile '{}' (config defines: {})",
+            name,
+            if known.is_empty() { "none".to_string() } else { known.join(", ") }
+        );
+    };
+    settings.model = profile.model.clone().or(settings.model);
+    settings.threads = profile.threads.or(settings.threads);
+    settings.max_tokens = profile.max_tokens.or(settings.max_tokens);
+    settings.context_size = profile.context_size.or(settings.context_size);
+    Ok(settings)
+}
+
+/// Test files by default: pytest's naming conventions and anything under a `tests` directory
+const DEFAULT_TEST_PATTERNS: &[&str] = &["test_*.py", "*_test.py", "tests/"];
+
+/// Whether `path` matches one of the test patterns: a `name/` pattern matches a directory
+/// anywhere below the analyzed `root`, anything else is a glob on the file name
+fn is_test_file(path: &std::path::Path, root: &std::path::Path, patterns: &[String]) -> bool {
+    let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+    let relative = root_relative_path(path, root);
+    patterns.iter().any(|pattern| match pattern.strip_suffix('/') {
+        Some(dir) => relative.parent().is_some_and(|parent| {
+            parent.components().any(|component| component.as_os_str() == dir)
+        }),
+        None => glob_matches(pattern, file_name),
+    })
+}
+
+/// Match `text` against a glob with `*` and `?` wildcards
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern = format!(
+        "^{}$",
+        regex::escape(pattern).replace("\\*", ".*").replace("\\?", ".")
+    );
+    Regex::new(&pattern).is_ok_and(|re| re.is_match(text))
+}
+
+/// `--show-config`: the settings a run would use once the config file, `--set` overrides and
+/// CLI flags are combined (call after `apply_config_settings`), as TOML with the source of each
+/// value in a trailing comment
+fn effective_config_toml(cli: &Cli, config: &ChecksConfig, config_source: &str, checks: &[CheckConfig]) -> String {
+    let settings = &effective_settings(cli, config).unwrap_or_else(|_| config.settings.clone());
+    // Explicit flags win over the config, which wins over the built-in defaults
+    let source = |name: &str, explicit: bool, from_config: bool| -> String {
+        let key = format!("settings.{}", name);
+        let overridden = cli.config_overrides.iter()
+            .any(|assignment| assignment.split_once('=').is_some_and(|(path, _)| path.trim() == key));
+        match (explicit, from_config, overridden) {
+            (true, _, _) => "command line".to_string(),
+            (false, true, true) => "--set".to_string(),
+            (false, true, false) => config_source.to_string(),
+            (false, false, _) => "default".to_string(),
+        }
+    };
+    let path = |path: &PathBuf| toml::Value::String(path.display().to_string());
+    let entries: Vec<(&str, Option<toml::Value>, String)> = vec![
+        ("model", cli.model.as_ref().map(path),
+            source("model", cli.is_explicit("model"), settings.model.is_some())),
+        ("threads", Some(toml::Value::Integer(cli.threads.into())),
+            source("threads", cli.is_explicit("threads"), settings.threads.is_some())),
+        ("max_tokens", Some(toml::Value::Integer(cli.max_tokens.into())),
+            source("max_tokens", cli.is_explicit("max_tokens"), settings.max_tokens.is_some())),
+        ("context_size", Some(toml::Value::Integer(cli.context_size.into())),
+            source("context_size", cli.is_explicit("context_size"), settings.context_size.is_some())),
+        ("skip_large", Some(toml::Value::Integer(cli.skip_large as i64)),
+            source("skip_large", cli.is_explicit("skip_large"), settings.skip_large.is_some())),
+        ("skip_small", Some(toml::Value::Integer(cli.skip_small as i64)),
+            source("skip_small", cli.is_explicit("skip_small"), settings.skip_small.is_some())),
+        ("cache_dir", Some(path(cli.cache_dir.as_ref().unwrap_or(&PathBuf::from(DEFAULT_CACHE_DIR)))),
+            source("cache_dir", cli.is_explicit("cache_dir"), settings.cache_dir.is_some())),
+        ("api_url", cli.api_url.clone().map(toml::Value::String),
+            source("api_url", cli.is_explicit("api_url"), settings.api_url.is_some())),
+        ("assistant_marker", Some(toml::Value::String(
+                settings.assistant_marker.clone().unwrap_or_else(|| DEFAULT_ASSISTANT_MARKER.to_string()))),
+            source("assistant_marker", false, settings.assistant_marker.is_some())),
+        ("init_special_case", Some(toml::Value::Boolean(!cli.no_init_special_case && settings.init_special_case != Some(false))),
+            source("init_special_case", cli.is_explicit("no_init_special_case"), settings.init_special_case.is_some())),
+        ("skip_tests", Some(toml::Value::Boolean(cli.skip_tests)),
+            source("skip_tests", cli.is_explicit("skip_tests") || cli.is_explicit("analyze_tests"), settings.skip_tests.is_some())),
+        ("test_patterns", Some(toml::Value::Array(cli.test_pattern.iter().cloned().map(toml::Value::String).collect())),
+            source("test_patterns", cli.is_explicit("test_pattern"), settings.test_patterns.is_some())),
+    ];
+
+    let selection = if cli.checks.is_some() || cli.checks_file.is_some() {
+        "--checks"
+    } else if cli.exclude.is_some() || cli.exclude_file.is_some() {
+        "--exclude"
+    } else {
+        "all configured checks"
+    };
+    let keys = toml::Value::Array(checks.iter().map(|check| toml::Value::String(check.key.clone())).collect());
+
+    let mut out = format!("# Effective configuration (checks from {})\n", config_source);
+    if let Some(name) = &cli.model_profile {
+        out.push_str(&format!("# Model profile: {} (overrides [settings])\n", name));
+    }
+    out.push_str(&format!(
+        "# API token (HF_TOKEN environment variable): {}\n\n",
+        if std::env::var_os("HF_TOKEN").is_some() { "set" } else { "not set" }
+    ));
+    out.push_str(&format!("checks = {}  # {}\n\n[settings]\n", keys, selection));
+    for (name, value, source) in entries {
+        match value {
+            Some(value) => out.push_str(&format!("{} = {}  # {}\n", name, value, source)),
+            None => out.push_str(&format!("# {} is not set  # {}\n", name, source)),
+        }
+    }
+    out
+}
+
+/// Front-end syntax of a checks configuration; both deserialize into the same `ChecksConfig`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ConfigFormat {
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// `.yaml`/`.yml` files are YAML, everything else (including the built-in config) is TOML
+    fn for_path(path: &std::path::Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("yaml") || ext.eq_ignore_ascii_case("yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Toml,
+        }
+    }
+
+    fn parse(self, content: &str) -> Result<ChecksConfig> {
+        Ok(match self {
+            ConfigFormat::Toml => toml::from_str(content)?,
+            ConfigFormat::Yaml => serde_yaml::from_str(content)?,
+        })
+    }
+}
+
+/// Locate the checks configuration: explicit path, then the user config, then built-in defaults.
+/// Returns a description of the source (for error messages), its contents and its format.
+fn read_checks_config_source(config_path: Option<PathBuf>) -> Result<(String, String, ConfigFormat)> {
+    // If specific config file provided, load it
+    if let Some(path) = config_path {
+        let content = fs::read_to_string(&path)
             .with_context(|| format!("Failed to read config file: {}", path.display()))?;
-        let mut config: ChecksConfig = toml::from_str(&content)
-            .with_context(|| format!("Failed to parse config file: {}", path.display()))?;
-        apply_template_expansion(&mut config)
-            .with_context(|| format!("Failed to expand templates in config file: {}", path.display()))?;
-        return Ok(config);
+        return Ok((format!("config file: {}", path.display()), content, ConfigFormat::for_path(&path)));
     }
 
     // Try ~/.config/loopsleuth/loopsleuth.toml
@@ -404,38 +1597,288 @@ fn load_checks_config(config_path: Option<PathBuf>) -> Result<ChecksConfig> {
         if config_path.exists() {
             let content = fs::read_to_string(&config_path)
                 .with_context(|| format!("Failed to read config file: {}", config_path.display()))?;
-            let mut config: ChecksConfig = toml::from_str(&content)
-                .with_context(|| format!("Failed to parse config file: {}", config_path.display()))?;
-            apply_template_expansion(&mut config)
-                .with_context(|| format!("Failed to expand templates in config file: {}", config_path.display()))?;
-            return Ok(config);
+            return Ok((format!("config file: {}", config_path.display()), content, ConfigFormat::Toml));
         }
     }
 
     // Fall back to built-in defaults
-    let mut config: ChecksConfig = toml::from_str(get_default_config_toml())
-        .context("Failed to parse built-in default configuration")?;
+    Ok(("built-in default configuration".to_string(), get_default_config_toml().to_string(), ConfigFormat::Toml))
+}
+
+/// Load checks configuration from file or use defaults, then apply `--set` overrides
+fn load_checks_config(config_path: Option<PathBuf>, overrides: &[String]) -> Result<ChecksConfig> {
+    let (source, content, format) = read_checks_config_source(config_path)?;
+    let mut config = format.parse(&content)
+        .with_context(|| format!("Failed to parse {}", source))?;
     apply_template_expansion(&mut config)
-        .context("Failed to expand templates in built-in default configuration")?;
+        .with_context(|| format!("Failed to expand templates in {}", source))?;
+    for assignment in overrides {
+        config = apply_config_override(&config, assignment)
+            .with_context(|| format!("Invalid --set {}", assignment))?;
+    }
     Ok(config)
 }
 
+/// Apply one `dotted.path=value` assignment by round-tripping the config through a TOML value.
+/// Path segments under `check` select a check by key; the value is parsed as a TOML literal,
+/// falling back to a plain string (so `--set settings.api_url=http://...` needs no quoting).
+fn apply_config_override(config: &ChecksConfig, assignment: &str) -> Result<ChecksConfig> {
+    let (path, raw_value) = assignment
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("expected KEY=VALUE"))?;
+    let segments: Vec<&str> = path.trim().split('.').collect();
+    let (field, parents) = segments.split_last().unwrap();
+    if field.is_empty() || parents.is_empty() {
+        anyhow::bail!("expected a dotted path such as settings.context_size");
+    }
+    let value = toml::from_str::<toml::Table>(&format!("value = {}", raw_value.trim()))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(raw_value.trim().to_string()));
+
+    let mut root = toml::Value::try_from(config).context("Failed to serialize config")?;
+    config_table_at(&mut root, parents)?.insert(field.to_string(), value);
+    let updated: ChecksConfig = root.try_into().context("value has the wrong type")?;
+
+    // Unknown fields are silently dropped by deserialization: make sure the value landed
+    let mut check = toml::Value::try_from(&updated).context("Failed to serialize config")?;
+    if !config_table_at(&mut check, parents)?.contains_key(*field) {
+        anyhow::bail!("unknown config key '{}'", path.trim());
+    }
+    Ok(updated)
+}
+
+/// Table addressed by `segments` (arrays such as `check` are indexed by their entries' `key`)
+fn config_table_at<'a>(root: &'a mut toml::Value, segments: &[&str]) -> Result<&'a mut toml::Table> {
+    let mut current = root;
+    for segment in segments {
+        current = match current {
+            toml::Value::Table(table) => table.get_mut(*segment),
+            toml::Value::Array(entries) => entries.iter_mut()
+                .find(|entry| entry.get("key").and_then(|key| key.as_str()) == Some(*segment)),
+            _ => None,
+        }
+        .ok_or_else(|| anyhow::anyhow!("unknown config key '{}'", segment))?;
+    }
+    current
+        .as_table_mut()
+        .ok_or_else(|| anyhow::anyhow!("'{}' is not a table", segments.join(".")))
+}
+
+/// Problems found in an unexpanded config: duplicate keys, unknown template
+/// references, empty required fields and invalid regexes
+fn config_problems(config: &ChecksConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut seen_keys = std::collections::HashSet::new();
+
+    for (idx, check) in config.check.iter().enumerate() {
+        let label = if check.key.trim().is_empty() {
+            format!("check #{}", idx + 1)
+        } else {
+            format!("check '{}'", check.key)
+        };
+
+        if !check.key.trim().is_empty() && !seen_keys.insert(check.key.as_str()) {
+            problems.push(format!("{}: duplicate key", label));
+        }
+
+        let required = [
+            ("key", &check.key),
+            ("name", &check.name),
+            ("keyword", &check.keyword),
+            ("detection_prompt", &check.detection_prompt),
+            ("solution_prompt", &check.solution_prompt),
+        ];
+        for (field, value) in required {
+            if value.trim().is_empty() {
+                problems.push(format!("{}: {} is empty", label, field));
+            }
+        }
+
+        for (field, prompt) in [
+            ("detection_prompt", &check.detection_prompt),
+            ("solution_prompt", &check.solution_prompt),
+            ("verifier_prompt", &check.verifier_prompt),
+            ("test_prompt", &check.test_prompt),
+        ] {
+            if let Some(name) = get_template_name(prompt) {
+                if !config.templates.contains_key(name) {
+                    problems.push(format!("{}: {} references unknown template '{}'", label, field, name));
+                }
+            }
+        }
+
+        if check.verifier_rounds == 0 {
+            problems.push(format!("{}: verifier_rounds must be at least 1", label));
+        }
+        if check.solution_candidates == 0 {
+            problems.push(format!("{}: solution_candidates must be at least 1", label));
+        }
+        if let Err(e) = validate_guard_patterns(check) {
+            problems.push(format!("{}: guard {}", label, e));
+        }
+        if let Some(pattern) = &check.highlight_regex {
+            if Regex::new(pattern).is_err() {
+                problems.push(format!("{}: invalid highlight_regex: {}", label, pattern));
+            }
+        }
+        if let Some(pattern) = &check.prefilter_regex {
+            if Regex::new(pattern).is_err() {
+                problems.push(format!("{}: invalid prefilter_regex: {}", label, pattern));
+            }
+        }
+    }
+
+    problems
+}
+
+/// `--config-check`: validate the configuration without loading a model or touching any Python
+fn run_config_check(cli: &Cli) -> Result<()> {
+    let (source, content, format) = read_checks_config_source(cli.config.clone())?;
+    println!("🔎 Checking {}", source);
+
+    let mut config = match format.parse(&content) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("❌ Failed to parse: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut problems = config_problems(&config);
+    if problems.is_empty() {
+        if let Err(e) = apply_template_expansion(&mut config) {
+            problems.push(format!("template expansion failed: {:#}", e));
+        }
+    }
+
+    if problems.is_empty() {
+        println!(
+            "✅ Config OK: {} checks, {} templates, {} dedupe rules",
+            config.check.len(),
+            config.templates.len(),
+            config.dedupe.len()
+        );
+        return Ok(());
+    }
+
+    println!("❌ Config has {} problem(s):", problems.len());
+    for problem in &problems {
+        println!("   • {}", problem);
+    }
+    std::process::exit(1);
+}
+
 // CheckConfig removed - now using CheckConfig directly from loaded configuration
 
 /// Registry of all available checks - loaded from configuration
 fn get_all_checks(cli: &Cli) -> Result<Vec<CheckConfig>> {
-    let config = load_checks_config(cli.config.clone())?;
-    Ok(config.check)
+    let config = load_checks_config(cli.config.clone(), &cli.config_overrides)?;
+    let mut checks = config.check;
+    if cli.no_init_special_case {
+        for check in &mut checks {
+            check.init_special_case = Some(false);
+        }
+    }
+    let overrides = prompt_overrides(cli)?;
+    if overrides == (None, None) {
+        return Ok(checks);
+    }
+    let (system, suffix) = overrides;
+    Ok(checks
+        .into_iter()
+        .map(|mut check| {
+            check.detection_prompt = override_prompt(&check.detection_prompt, system.as_deref(), suffix.as_deref());
+            check
+        })
+        .collect())
+}
+
+/// Read a TEXT|@FILE argument
+fn read_text_arg(flag: &str, value: &str) -> Result<String> {
+    match value.strip_prefix('@') {
+        Some(path) => fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {} file: {}", flag, path)),
+        None => Ok(value.to_string()),
+    }
+}
+
+/// The --system-prompt and --prompt-suffix texts, if given
+fn prompt_overrides(cli: &Cli) -> Result<(Option<String>, Option<String>)> {
+    let system = cli.system_prompt.as_deref()
+        .map(|value| read_text_arg("--system-prompt", value))
+        .transpose()?;
+    let suffix = cli.prompt_suffix.as_deref()
+        .map(|value| read_text_arg("--prompt-suffix", value))
+        .transpose()?;
+    Ok((system, suffix))
 }
 
-/// Expand {template:name} placeholders and inject detection/fix blocks.
+/// Add CLI system instructions and a trailing reminder to a detection prompt. ChatML prompts
+/// get the instructions at the top of their system message (or a new one) and the reminder
+/// at the end of the last user message; other prompts get plain text at either end.
+fn override_prompt(prompt: &str, system: Option<&str>, suffix: Option<&str>) -> String {
+    const SYSTEM_HEADER: &str = "<|im_start|>system\n";
+    let is_chatml = prompt.contains("<|im_start|>");
+    let mut prompt = prompt.to_string();
+
+    if let Some(suffix) = suffix {
+        let user_end = prompt
+            .rfind("<|im_start|>assistant")
+            .and_then(|assistant| prompt[..assistant].rfind("<|im_end|>"));
+        match user_end {
+            Some(pos) if is_chatml => prompt.insert_str(pos, &format!("\n\n{}", suffix.trim())),
+            _ => prompt = format!("{}\n\n{}", prompt.trim_end(), suffix.trim()),
+        }
+    }
+
+    if let Some(system) = system {
+        let system = system.trim();
+        prompt = if let Some(rest) = prompt.strip_prefix(SYSTEM_HEADER) {
+            format!("{}{}\n\n{}", SYSTEM_HEADER, system, rest)
+        } else if is_chatml {
+            format!("{}{}<|im_end|>\n{}", SYSTEM_HEADER, system, prompt)
+        } else {
+            format!("{}\n\n{}", system, prompt)
+        };
+    }
+
+    prompt
+}
+
+/// Expand {template:name} placeholders, inject detection/fix blocks and compile prefilter regexes.
 fn apply_template_expansion(config: &mut ChecksConfig) -> Result<()> {
+    // Keys identify checks in the cache and in --checks, so they must be unique
+    let mut seen_keys = std::collections::HashSet::new();
+    for check in &config.check {
+        if !seen_keys.insert(check.key.as_str()) {
+            return Err(anyhow::anyhow!(
+                "Duplicate check key '{}': each [[check]] entry needs a unique key",
+                check.key
+            ));
+        }
+    }
+
     let templates = &config.templates;
 
     for check in &mut config.check {
+        if check.assistant_marker.is_none() {
+            check.assistant_marker = config.settings.assistant_marker.clone();
+        }
+        if check.init_special_case.is_none() {
+            check.init_special_case = config.settings.init_special_case;
+        }
         warn_missing_template_refs(check, templates);
         validate_guard_patterns(check)
             .with_context(|| format!("Failed to validate guard patterns for check '{}'", check.key))?;
+        if let Some(pattern) = &check.highlight_regex {
+            Regex::new(pattern)
+                .with_context(|| format!("Invalid highlight_regex for check '{}': {}", check.key, pattern))?;
+        }
+        if let Some(pattern) = &check.prefilter_regex {
+            check.prefilter = Some(Regex::new(pattern)
+                .with_context(|| format!("Invalid prefilter_regex for check '{}': {}", check.key, pattern))?);
+        }
 
         check.detection_prompt = expand_template_string(&check.detection_prompt, templates)
             .context("Failed to expand detection prompt template")?;
@@ -455,15 +1898,47 @@ fn apply_template_expansion(config: &mut ChecksConfig) -> Result<()> {
             .verifier_prompt
             .replace("{detection_rules}", &check.detection_rules)
             .replace("{fix_recipes}", &check.fix_recipes);
+
+        check.test_prompt = expand_template_string(&check.test_prompt, templates)
+            .context("Failed to expand test prompt template")?;
+        check.test_prompt = check.test_prompt.replace("{fix_recipes}", &check.fix_recipes);
+
+        if let Some(problem) = missing_placeholders(check).first() {
+            return Err(anyhow::anyhow!("Check '{}': {}", check.key, problem));
+        }
     }
 
     Ok(())
 }
 
+/// Placeholders an expanded check's prompts can't work without: the model would never see
+/// the code (or the proposed solution) and produce garbage instead
+fn missing_placeholders(check: &CheckConfig) -> Vec<String> {
+    let mut missing = Vec::new();
+    for (field, prompt) in [
+        ("detection_prompt", &check.detection_prompt),
+        ("solution_prompt", &check.solution_prompt),
+    ] {
+        if !prompt.contains("{function_source}") {
+            missing.push(format!("{} does not contain {{function_source}}", field));
+        }
+    }
+    // An empty verifier prompt disables verification
+    if !check.verifier_prompt.trim().is_empty() && !check.verifier_prompt.contains("{solution}") {
+        missing.push("verifier_prompt does not contain {solution}".to_string());
+    }
+    if !check.test_prompt.trim().is_empty() && !check.test_prompt.contains("{solution}") {
+        missing.push("test_prompt does not contain {solution}".to_string());
+    }
+    missing
+}
+
 fn validate_guard_patterns(check: &CheckConfig) -> Result<()> {
     for pattern in check.guard.require_regex_any.iter()
         .chain(check.guard.require_regex_all.iter())
         .chain(check.guard.exclude_regex_any.iter())
+        .chain(check.guard.module_regex_any.iter())
+        .chain(check.guard.exclude_module_regex_any.iter())
     {
         Regex::new(pattern)
             .with_context(|| format!("Invalid regex pattern: {}", pattern))?;
@@ -471,6 +1946,11 @@ fn validate_guard_patterns(check: &CheckConfig) -> Result<()> {
     Ok(())
 }
 
+/// Whether a check's `prefilter_regex` rules the function out before any LLM call
+fn is_prefiltered(check: &CheckConfig, func: &FunctionInfo) -> bool {
+    check.prefilter.as_ref().is_some_and(|re| !re.is_match(&func.source_no_docstring))
+}
+
 fn guard_skip_reason(check: &CheckConfig, func: &FunctionInfo) -> Result<Option<String>> {
     let text = &func.source_no_docstring;
 
@@ -524,6 +2004,36 @@ fn guard_skip_reason(check: &CheckConfig, func: &FunctionInfo) -> Result<Option<
         }
     }
 
+    if !check.guard.module_regex_any.is_empty() {
+        let mut matched = false;
+        for pattern in &check.guard.module_regex_any {
+            let re = Regex::new(pattern)?;
+            if re.is_match(&func.module_path) {
+                matched = true;
+                break;
+            }
+        }
+        if !matched {
+            return Ok(Some("guard module_regex_any missing".to_string()));
+        }
+    }
+
+    if !check.guard.exclude_module_regex_any.is_empty() {
+        for pattern in &check.guard.exclude_module_regex_any {
+            let re = Regex::new(pattern)?;
+            if re.is_match(&func.module_path) {
+                return Ok(Some("guard exclude_module_regex_any hit".to_string()));
+            }
+        }
+    }
+
+    if check.guard.applies_to == Some(GuardTarget::Recursive) && !func.is_recursive {
+        return Ok(Some("guard applies_to recursive: not recursive".to_string()));
+    }
+    if check.guard.applies_to == Some(GuardTarget::Decorated) && func.decorators.is_empty() {
+        return Ok(Some("guard applies_to decorated: no decorators".to_string()));
+    }
+
     Ok(None)
 }
 
@@ -743,7 +2253,7 @@ fn warn_missing_template_refs(
     templates: &std::collections::HashMap<String, String>,
 ) {
     let mut missing: Vec<String> = Vec::new();
-    for prompt in [&check.detection_prompt, &check.solution_prompt, &check.verifier_prompt] {
+    for prompt in [&check.detection_prompt, &check.solution_prompt, &check.verifier_prompt, &check.test_prompt] {
         if let Some(name) = get_template_name(prompt) {
             if !templates.contains_key(name) && !missing.contains(&name.to_string()) {
                 missing.push(name.to_string());
@@ -790,30 +2300,409 @@ fn parse_check_keys(keys: &str) -> Vec<String> {
         .collect()
 }
 
-/// Get the checks to run based on CLI arguments
-fn get_checks_to_run(cli: &Cli) -> Result<Vec<CheckConfig>> {
-    let all_checks = get_all_checks(cli)?;
+/// Turn a newline-separated check list file into the comma-separated form
+/// accepted by `parse_check_keys`, dropping blank lines and `#` comments
+fn check_keys_from_file_contents(contents: &str) -> String {
+    contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(",")
+}
 
-    // If specific checks requested, filter to those
-    if let Some(check_list) = &cli.checks {
-        let requested_keys = parse_check_keys(check_list);
-        return Ok(all_checks
-            .into_iter()
-            .filter(|check| requested_keys.contains(&check.key))
-            .collect());
+/// Merge an inline comma-separated key list with an optional key file
+fn collect_check_keys(inline: Option<&str>, file: Option<&std::path::Path>) -> Result<Option<Vec<String>>> {
+    if inline.is_none() && file.is_none() {
+        return Ok(None);
     }
 
-    // If excludes specified, filter those out
-    if let Some(exclude_list) = &cli.exclude {
-        let excluded_keys = parse_check_keys(exclude_list);
-        return Ok(all_checks
-            .into_iter()
-            .filter(|check| !excluded_keys.contains(&check.key))
-            .collect());
+    let mut keys = inline.map(parse_check_keys).unwrap_or_default();
+    if let Some(path) = file {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read check list file: {}", path.display()))?;
+        keys.extend(parse_check_keys(&check_keys_from_file_contents(&contents)));
     }
+    Ok(Some(keys))
+}
 
-    // Default: run all checks
-    Ok(all_checks)
+/// Match a check selector: an exact key, `category/*`, or a key glob (`quad*`, `?-in-loop`)
+fn check_selector_matches(selector: &str, check: &CheckConfig) -> bool {
+    if let Some(category) = selector.strip_suffix("/*") {
+        return check.category.eq_ignore_ascii_case(category);
+    }
+    if selector.contains(['*', '?']) {
+        return glob_matches(selector, &check.key);
+    }
+    check.key == selector
+}
+
+/// Return the requested selectors that don't match any loaded check
+fn unknown_check_keys(keys: &[String], all_checks: &[CheckConfig]) -> Vec<String> {
+    let mut unknown: Vec<String> = Vec::new();
+    for key in keys {
+        if !all_checks.iter().any(|check| check_selector_matches(key, check)) && !unknown.contains(key) {
+            unknown.push(key.clone());
+        }
+    }
+    unknown
+}
+
+/// Warn (or fail with --strict) about keys that would otherwise be silently ignored
+fn validate_check_keys(option: &str, keys: &[String], all_checks: &[CheckConfig], strict: bool) -> Result<()> {
+    let unknown = unknown_check_keys(keys, all_checks);
+    if unknown.is_empty() {
+        return Ok(());
+    }
+
+    let message = format!(
+        "{} references unknown check(s): {} (see --list-checks)",
+        option,
+        unknown.join(", ")
+    );
+    if strict {
+        anyhow::bail!(message);
+    }
+    eprintln!("Warning: {}", message);
+    Ok(())
+}
+
+/// Get the checks to run based on CLI arguments
+fn get_checks_to_run(cli: &Cli) -> Result<Vec<CheckConfig>> {
+    let all_checks = get_all_checks(cli)?;
+
+    // If specific checks requested, filter to those
+    if let Some(requested_keys) = collect_check_keys(cli.checks.as_deref(), cli.checks_file.as_deref())? {
+        validate_check_keys("--checks", &requested_keys, &all_checks, cli.strict)?;
+        return Ok(all_checks
+            .into_iter()
+            .filter(|check| requested_keys.iter().any(|key| check_selector_matches(key, check)))
+            .collect());
+    }
+
+    // If excludes specified, filter those out
+    if let Some(excluded_keys) = collect_check_keys(cli.exclude.as_deref(), cli.exclude_file.as_deref())? {
+        validate_check_keys("--exclude", &excluded_keys, &all_checks, cli.strict)?;
+        return Ok(all_checks
+            .into_iter()
+            .filter(|check| !excluded_keys.iter().any(|key| check_selector_matches(key, check)))
+            .collect());
+    }
+
+    // Default: run all checks
+    Ok(all_checks)
+}
+
+/// A finding in a JSON report: (file path, qualified function name, check key)
+type FindingKey = (String, String, String);
+
+/// Stable ID of a finding, for correlating it across runs and tools: a short hash of the
+/// file's path below the analyzed directory, `Class::function` and check key. Line numbers are left out so the ID
+/// survives code moving around the file
+fn finding_id(path: &str, qualified_name: &str, check_key: &str) -> String {
+    let mut hasher = Sha256::new();
+    // Windows separators would otherwise give the same finding another ID
+    hasher.update(path.replace('\\', "/").as_bytes());
+    hasher.update(b"\0");
+    hasher.update(qualified_name.as_bytes());
+    hasher.update(b"\0");
+    hasher.update(check_key.as_bytes());
+    format!("{:x}", hasher.finalize())[..16].to_string()
+}
+
+/// `--format codeclimate`: one Code Climate issue per finding, as read by GitLab's code quality
+/// widget. The finding ID doubles as the fingerprint, so GitLab can tell new issues from old ones
+fn codeclimate_issues(file_results: &[FileResults], checks: &[CheckConfig], options: &ReportOptions) -> serde_json::Value {
+    let mut issues = Vec::new();
+    for file_result in file_results {
+        let path = options.display_path(&file_result.file_path);
+        for result in &file_result.results {
+            let qualified_name = result.function.qualified_name();
+            for issue in result.check_results.iter().filter(|cr| cr.has_issue) {
+                let category = checks.iter()
+                    .find(|check| check.key == issue.check_key)
+                    .map_or("", |check| check.category.as_str());
+                let detail = issue.analysis.lines()
+                    .find_map(|line| line.trim_start().strip_prefix("DETAIL:"))
+                    .map(str::trim)
+                    .filter(|detail| !detail.is_empty());
+                let description = match detail {
+                    Some(detail) => format!("{} in `{}`: {}", issue.check_name, qualified_name, detail),
+                    None => format!("{} in `{}`", issue.check_name, qualified_name),
+                };
+                issues.push(serde_json::json!({
+                    "type": "issue",
+                    "check_name": issue.check_key,
+                    "description": description,
+                    "categories": [codeclimate_category(category)],
+                    "location": {
+                        "path": path,
+                        "lines": { "begin": result.function.line_number },
+                    },
+                    "fingerprint": options.finding_id(&file_result.file_path, &qualified_name, &issue.check_key),
+                    "severity": codeclimate_severity(issue),
+                }));
+            }
+        }
+    }
+    serde_json::Value::Array(issues)
+}
+
+/// Code Climate category for a check category; anything unrecognized (e.g. `ml-specific`)
+/// is a performance issue, which is what LoopSleuth looks for
+fn codeclimate_category(category: &str) -> &'static str {
+    match category.to_ascii_lowercase().as_str() {
+        "bug-risk" | "bug risk" | "correctness" => "Bug Risk",
+        "clarity" => "Clarity",
+        "compatibility" => "Compatibility",
+        "complexity" => "Complexity",
+        "duplication" => "Duplication",
+        "security" => "Security",
+        "style" => "Style",
+        _ => "Performance",
+    }
+}
+
+/// Severity bucket for a finding's detection confidence; advisory findings are always `info`
+fn codeclimate_severity(issue: &CheckResult) -> &'static str {
+    if issue.advisory {
+        return "info";
+    }
+    match extract_confidence_percentage(&issue.analysis) {
+        90.. => "critical",
+        70..=89 => "major",
+        50..=69 => "minor",
+        _ => "info",
+    }
+}
+
+/// Findings listed in a `--format json` report, with the `finding_id` the report gave them
+fn report_findings(report: &serde_json::Value) -> std::collections::BTreeMap<FindingKey, String> {
+    let mut findings = std::collections::BTreeMap::new();
+    for file in report["files"].as_array().into_iter().flatten() {
+        let path = file["path"].as_str().unwrap_or_default();
+        for result in file["results"].as_array().into_iter().flatten() {
+            let name = result["function_name"].as_str().unwrap_or_default();
+            let qualified_name = [result["enclosing_function"].as_str(), result["class_name"].as_str(), Some(name)]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>()
+                .join("::");
+            // Advisory issues (--warn-below-confidence) never count as findings
+            for issue in result["issues"].as_array().into_iter().flatten().filter(|issue| issue["advisory"] != true) {
+                let check_key = issue["check_key"].as_str().unwrap_or_default();
+                // Reports from before finding IDs were keyed on the analyzed directory lack one
+                let id = issue["finding_id"].as_str()
+                    .map_or_else(|| finding_id(path, &qualified_name, check_key), str::to_string);
+                findings.insert((path.to_string(), qualified_name.clone(), check_key.to_string()), id);
+            }
+        }
+    }
+    findings
+}
+
+/// `--compare`: diff the findings of two JSON reports; exits non-zero when new findings appear
+fn run_compare(old_path: &std::path::Path, new_path: &std::path::Path, json_mode: bool) -> Result<()> {
+    let load = |path: &std::path::Path| -> Result<std::collections::BTreeMap<FindingKey, String>> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read report: {}", path.display()))?;
+        let report: serde_json::Value = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse JSON report: {}", path.display()))?;
+        Ok(report_findings(&report))
+    };
+    let old = load(old_path)?;
+    let new = load(new_path)?;
+
+    let fixed: Vec<_> = old.iter().filter(|(key, _)| !new.contains_key(*key)).collect();
+    let introduced: Vec<_> = new.iter().filter(|(key, _)| !old.contains_key(*key)).collect();
+    let persistent: Vec<_> = new.iter().filter(|(key, _)| old.contains_key(*key)).collect();
+
+    if json_mode {
+        let to_json = |findings: &[(&FindingKey, &String)]| -> Vec<serde_json::Value> {
+            findings.iter()
+                .map(|((path, function, check_key), id)| serde_json::json!({
+                    "finding_id": id,
+                    "path": path,
+                    "function": function,
+                    "check_key": check_key,
+                }))
+                .collect()
+        };
+        let comparison = serde_json::json!({
+            "fixed": to_json(&fixed),
+            "new": to_json(&introduced),
+            "persistent": to_json(&persistent),
+        });
+        println!("{}", serde_json::to_string_pretty(&comparison)?);
+    } else {
+        println!("📊 Comparing {} → {}\n", old_path.display(), new_path.display());
+        for (label, findings) in [
+            ("✅ Fixed", &fixed),
+            ("🆕 New", &introduced),
+            ("⏸️  Persistent", &persistent),
+        ] {
+            println!("{} ({}):", label, findings.len());
+            for ((path, function, check_key), _) in findings.iter() {
+                println!("   • {}::{} [{}]", path, function, check_key);
+            }
+            println!();
+        }
+    }
+
+    if !introduced.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// `--list-functions`: print what extraction sees, without running any checks
+fn list_functions(cli: &Cli, root: &std::path::Path, checks: &[CheckConfig]) -> Result<()> {
+    let include_classes = checks.iter().any(|c| c.scope == CheckScope::Class);
+    let base = cli.relative_to.as_deref().or(root.is_dir().then_some(root));
+    let mut python_files = collect_sources(cli, &root.to_path_buf())?;
+    if cli.staged {
+        python_files.retain(|path| is_in_git_index(path));
+    }
+
+    let mut total = 0;
+    for path in &python_files {
+        let functions = match extract_functions(path, root, include_classes, cli.nested_functions, SourceOrigin::from_cli(cli)) {
+            Ok(functions) => functions,
+            Err(e) => {
+                eprintln!("⚠️  Skipping unparseable file {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        let shown_path = display_path(path, base);
+        for func in &functions {
+            let name = if func.is_class_scope {
+                format!("class {}", func.name)
+            } else {
+                func.qualified_name()
+            };
+            let line_count = func.source.lines().count();
+            println!(
+                "{}:{} {} ({}{} line{})",
+                shown_path,
+                func.line_number,
+                name,
+                if is_async_def(&func.source) { "async, " } else { "" },
+                line_count,
+                if line_count == 1 { "" } else { "s" }
+            );
+        }
+        total += functions.len();
+    }
+
+    println!("\n{} function(s) in {} file(s)", total, python_files.len());
+    Ok(())
+}
+
+/// Whether a function's source (decorators included) is an `async def`
+fn is_async_def(source: &str) -> bool {
+    source
+        .lines()
+        .map(str::trim_start)
+        .find(|line| line.starts_with("def ") || line.starts_with("async def "))
+        .is_some_and(|line| line.starts_with("async "))
+}
+
+/// `--print-prompt-for`: render detection prompts for one real function without calling the model.
+/// FUNCTION is a bare name or `Class::name`.
+fn print_prompts_for(cli: &Cli, file_path: &std::path::Path, function: &str) -> Result<()> {
+    let checks = get_checks_to_run(cli)?;
+    let file_path = file_path.to_path_buf();
+    let mut functions = extract_functions(&file_path, &file_path, true, cli.nested_functions, SourceOrigin::from_cli(cli))
+        .with_context(|| format!("Failed to extract functions from {}", file_path.display()))?;
+
+    functions.retain(|func| function_has_name(func, function));
+    if functions.is_empty() {
+        return Err(anyhow::anyhow!("Function '{}' not found in {}", function, file_path.display()));
+    }
+
+    for func in &functions {
+        for check in &checks {
+            if !check.applies_to_unit(func) {
+                continue;
+            }
+            println!("╔════════════════════════════════════════════════════════════════");
+            println!("║ DETECTION PROMPT [{}] {} ({}:{})", check.key, func.name, file_path.display(), func.line_number);
+            println!("╚════════════════════════════════════════════════════════════════");
+            println!("{}", check.format_detection_prompt(func).text);
+            println!();
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether `name` is the function's name or its qualified `Class::method` form
+fn function_has_name(func: &FunctionInfo, name: &str) -> bool {
+    func.name == name || func.qualified_name() == name
+}
+
+/// Handle --explain-clean: run every selected check's detection for the named function,
+/// bypassing the cache, and show the raw response next to the verdict it was parsed into
+fn explain_clean<F>(
+    cli: &Cli,
+    python_files: &[PathBuf],
+    root: &std::path::Path,
+    checks: &[CheckConfig],
+    function: &str,
+    generate_fn: &mut F,
+) -> Result<()>
+where
+    F: FnMut(&Prompt, i32, f32, bool) -> Result<(String, StopReason, TokenStats)>,
+{
+    let include_classes = checks.iter().any(|c| c.scope == CheckScope::Class);
+    let mut found = false;
+    for file_path in python_files {
+        let mut functions = extract_functions(file_path, root, include_classes, cli.nested_functions, SourceOrigin::from_cli(cli))?;
+        functions.retain(|func| function_has_name(func, function));
+        let in_test_file = is_test_file(file_path, root, &cli.test_pattern);
+        for func in &functions {
+            found = true;
+            for check in checks {
+                if !check.applies_to_unit(func) {
+                    continue;
+                }
+                println!("╔════════════════════════════════════════════════════════════════");
+                println!("║ [{}] {} ({}:{})", check.key, func.name, file_path.display(), func.line_number);
+                println!("╚════════════════════════════════════════════════════════════════");
+                if in_test_file && !check.applies_to_tests {
+                    println!("Not run: the check has applies_to_tests = false and this is a test file\n");
+                    continue;
+                }
+                if let Some(analysis) = rule_based_detection(check, func) {
+                    println!("Rule-based verdict (no model call):\n{}\n", analysis);
+                    continue;
+                }
+
+                let prompt = check.format_detection_prompt(func);
+                let temperature = check.detection_temperature.unwrap_or(cli.temperature);
+                let (response, stop_reason, _stats) = generate_fn(&prompt, cli.max_tokens, temperature, cli.verbose)?;
+                let detection = check.parse_detection(&response);
+                println!("── Detection prompt ──\n{}\n", prompt.text.trim_end());
+                println!("── Raw response (stop: {}) ──\n{}\n", stop_reason.as_str(), response.trim_end());
+                let verdict = if detection.has_issue { check.keyword.to_uppercase() } else { "clean".to_string() };
+                match detection.confidence {
+                    Some(confidence) => println!("── Parsed verdict: {} (confidence {:.2})", verdict, confidence),
+                    None => println!("── Parsed verdict: {}", verdict),
+                }
+                if !detection.verdict_found {
+                    println!("   ⚠️  No verdict in the check's detection_format found; the response was treated as clean");
+                }
+                if stop_reason.is_truncated() {
+                    println!("   ⚠️  The response was cut off ({}); try a larger --max-tokens", stop_reason.as_str());
+                }
+                println!();
+            }
+        }
+    }
+    if !found {
+        return Err(anyhow::anyhow!("Function '{}' not found under {}", function, root.display()));
+    }
+    Ok(())
 }
 
 /// List all available checks
@@ -854,38 +2743,204 @@ fn list_all_checks(cli: &Cli) -> Result<()> {
     Ok(())
 }
 
-#[derive(Clone, Serialize)]
+#[derive(Clone, Default, Serialize, Deserialize)]
 struct CheckResult {
     check_key: String,
     check_name: String,
     has_issue: bool,
     analysis: String,
     solution: Option<String>,
+    /// Tokens spent on this check for this function (empty for cached or skipped results)
+    stats: TokenStats,
+    /// Why a proposed solution was dropped, when one was
+    rejection: Option<Rejection>,
+    /// How the detection response ended (None for skipped, rule-based or older cached results)
+    stop_reason: Option<StopReason>,
+    /// Below --warn-below-confidence: reported, but not counted by the failure gates
+    advisory: bool,
+    /// Suggested pytest/timeit snippet for the solution (--with-tests)
+    test: Option<String>,
+    /// Further verified solutions, ranked below `solution` (see `solution_candidates`)
+    #[serde(default)]
+    alternatives: Vec<String>,
 }
 
-fn dedupe_check_results(mut results: Vec<CheckResult>, rules: &[DedupeRule]) -> Vec<CheckResult> {
-    for rule in rules {
-        if rule.prefer.is_empty() || rule.drop.is_empty() {
+impl CheckResult {
+    /// A result for `check` with no solution, token usage or stop reason yet
+    fn new(check: &CheckConfig, has_issue: bool, analysis: String) -> Self {
+        CheckResult {
+            check_key: check.key.to_string(),
+            check_name: check.name.to_string(),
+            has_issue,
+            analysis,
+            ..Default::default()
+        }
+    }
+}
+
+/// Phase of the solution pipeline that dropped a proposed solution
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum RejectionStage {
+    Extract,
+    ValidateOptimization,
+    Verifier,
+}
+
+impl RejectionStage {
+    fn as_str(self) -> &'static str {
+        match self {
+            RejectionStage::Extract => "extract",
+            RejectionStage::ValidateOptimization => "validate_optimization",
+            RejectionStage::Verifier => "verifier",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        [RejectionStage::Extract, RejectionStage::ValidateOptimization, RejectionStage::Verifier]
+            .into_iter()
+            .find(|stage| stage.as_str() == value)
+    }
+}
+
+/// Machine-readable counterpart of the `[No safe change suggested: ...]` and `[Verifier rejected: ...]` notes
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Rejection {
+    stage: RejectionStage,
+    reason: String,
+}
+
+impl Rejection {
+    fn new(stage: RejectionStage, reason: impl Into<String>) -> Self {
+        Rejection { stage, reason: reason.into() }
+    }
+}
+
+/// Findings whose proposed solution the verifier rejected, with the verifier's reason
+fn verifier_rejected(results: &[AnalysisResult]) -> Vec<(&AnalysisResult, &CheckResult, &str)> {
+    results
+        .iter()
+        .flat_map(|r| r.check_results.iter().map(move |cr| (r, cr)))
+        .filter_map(|(r, cr)| match &cr.rejection {
+            Some(rejection) if cr.has_issue && rejection.stage == RejectionStage::Verifier => {
+                Some((r, cr, rejection.reason.as_str()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Lowercased words (3+ characters) of the DETAIL line, for comparing findings
+fn detail_words(analysis: &str) -> std::collections::HashSet<String> {
+    analysis
+        .lines()
+        .find_map(|line| line.trim_start().strip_prefix("DETAIL:"))
+        .unwrap_or("")
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|word| word.len() >= 3)
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Share of words two DETAIL lines have in common (Jaccard index); 0 when either is empty
+fn detail_overlap(a: &std::collections::HashSet<String>, b: &std::collections::HashSet<String>) -> f32 {
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+    a.intersection(b).count() as f32 / a.union(b).count() as f32
+}
+
+/// --collapse-similar: among one function's issues, fold those whose DETAIL overlaps the
+/// most confident one by at least `threshold`. The kept issue notes the others as related
+/// and takes over a solution if it has none.
+fn collapse_similar_findings(mut results: Vec<CheckResult>, threshold: f32) -> Vec<CheckResult> {
+    let mut order: Vec<usize> = (0..results.len()).filter(|&idx| results[idx].has_issue).collect();
+    // Most confident first; ties keep config order
+    order.sort_by_key(|&idx| std::cmp::Reverse(extract_confidence_percentage(&results[idx].analysis)));
+    let words: Vec<_> = results.iter().map(|r| detail_words(&r.analysis)).collect();
+
+    let mut collapsed = vec![false; results.len()];
+    for (pos, &keep) in order.iter().enumerate() {
+        if collapsed[keep] {
+            continue;
+        }
+        let related: Vec<usize> = order[pos + 1..].iter()
+            .copied()
+            .filter(|&other| !collapsed[other] && detail_overlap(&words[keep], &words[other]) >= threshold)
+            .collect();
+        if related.is_empty() {
             continue;
         }
-        let prefer_idx = results.iter().position(|r| r.has_issue && r.check_key == rule.prefer);
-        if let Some(idx) = prefer_idx {
-            // If the preferred result has no solution, steal one from a dropped result
+        for &other in &related {
+            collapsed[other] = true;
+        }
+        if results[keep].solution.is_none() {
+            results[keep].solution = related.iter().find_map(|&other| results[other].solution.clone());
+        }
+        let names: Vec<&str> = related.iter().map(|&other| results[other].check_name.as_str()).collect();
+        results[keep].analysis = format!("{}\n[Related: {}]", results[keep].analysis, names.join(", "));
+    }
+
+    let mut idx = 0;
+    results.retain(|_| {
+        idx += 1;
+        !collapsed[idx - 1]
+    });
+    results
+}
+
+fn dedupe_check_results(mut results: Vec<CheckResult>, rules: &[DedupeRule]) -> Vec<CheckResult> {
+    for rule in rules {
+        let (keep_key, drop_keys) = match rule.strategy {
+            DedupeStrategy::Prefer => {
+                if rule.prefer.is_empty() || rule.drop.is_empty() {
+                    continue;
+                }
+                if !results.iter().any(|r| r.has_issue && r.check_key == rule.prefer) {
+                    continue;
+                }
+                (rule.prefer.clone(), rule.drop.clone())
+            }
+            DedupeStrategy::HighestConfidence => {
+                if rule.group.len() < 2 {
+                    continue;
+                }
+                // Ties go to the check listed first in the results (config order)
+                let best = results.iter()
+                    .filter(|r| r.has_issue && rule.group.contains(&r.check_key))
+                    .fold(None::<&CheckResult>, |best, r| match best {
+                        Some(b) if extract_confidence_percentage(&b.analysis)
+                            >= extract_confidence_percentage(&r.analysis) => Some(b),
+                        _ => Some(r),
+                    });
+                let Some(best) = best else { continue };
+                let keep_key = best.check_key.clone();
+                let drop_keys: Vec<String> = rule.group.iter()
+                    .filter(|k| **k != keep_key)
+                    .cloned()
+                    .collect();
+                (keep_key, drop_keys)
+            }
+        };
+
+        let keep_idx = results.iter().position(|r| r.has_issue && r.check_key == keep_key);
+        if let Some(idx) = keep_idx {
+            // If the kept result has no solution, steal one from a dropped result
             if results[idx].solution.is_none() {
                 let stolen_solution = results.iter()
-                    .find(|r| r.has_issue && rule.drop.contains(&r.check_key) && r.solution.is_some())
+                    .find(|r| r.has_issue && drop_keys.contains(&r.check_key) && r.solution.is_some())
                     .and_then(|r| r.solution.clone());
                 if let Some(sol) = stolen_solution {
                     results[idx].solution = Some(sol);
                 }
             }
-            results.retain(|r| !(r.has_issue && rule.drop.contains(&r.check_key)));
+            results.retain(|r| !(r.has_issue && drop_keys.contains(&r.check_key)));
         }
     }
     results
 }
 
-#[derive(Clone, Serialize)]
+#[derive(Clone, Serialize, Deserialize)]
 struct AnalysisResult {
     function: FunctionInfo,
     check_results: Vec<CheckResult>,
@@ -897,10 +2952,31 @@ struct FileResults {
     results: Vec<AnalysisResult>,
 }
 
+const DEFAULT_CACHE_DIR: &str = ".loopsleuth_cache";
+
+/// Writes are committed in batches of this many results...
+const CACHE_BATCH_SIZE: usize = 64;
+/// ...or once the open batch is this old, so an interrupted run loses little
+const CACHE_BATCH_MAX_AGE: Duration = Duration::from_secs(5);
+
 /// Cache for storing LLM analysis results
 struct AnalysisCache {
     conn: Connection,
     enabled: bool,
+    /// Results written in the currently open transaction, and when it was opened
+    pending_writes: std::cell::Cell<usize>,
+    batch_started: std::cell::Cell<Option<Instant>>,
+    /// Mixed into result keys when prompts are overridden from the CLI (empty otherwise)
+    key_salt: String,
+    /// Prompt hash of each check; results stored under another hash are treated as misses
+    prompt_hashes: std::collections::HashMap<String, String>,
+    /// Checks whose prompts use {class_context}: their results also depend on `__init__`
+    class_context_checks: std::collections::HashSet<String>,
+    /// Checks whose prompts use {decorators}: their results also depend on the decorators
+    decorator_checks: std::collections::HashSet<String>,
+    /// --concurrency-safe-cache: advisory lock held until the cache is dropped (declared
+    /// last so the database is closed before it is released)
+    _lock: Option<fs::File>,
 }
 
 #[derive(Debug)]
@@ -908,29 +2984,62 @@ struct CachedResult {
     has_issue: bool,
     analysis: String,
     solution: Option<String>,
+    stop_reason: Option<StopReason>,
+    /// Lower-ranked solutions that also passed (see `solution_candidates`)
+    alternatives: Vec<String>,
+    /// --with-tests snippet for `solution`
+    test: Option<String>,
+    /// Why the proposed solution was dropped (None for older rows)
+    rejection: Option<Rejection>,
 }
 
 impl AnalysisCache {
     /// Create or open cache database
-    fn new(cache_dir: Option<PathBuf>, enabled: bool) -> Result<Self> {
+    /// `cache_file` names the SQLite database directly; otherwise it lives in `cache_dir`.
+    /// With `lock`, waits for exclusive use of the database (see `lock_cache`)
+    fn new(cache_dir: Option<PathBuf>, cache_file: Option<PathBuf>, enabled: bool, lock: bool) -> Result<Self> {
         if !enabled {
             // Return a dummy cache with an in-memory database
             return Ok(Self {
                 conn: Connection::open_in_memory()?,
                 enabled: false,
+                pending_writes: std::cell::Cell::new(0),
+                batch_started: std::cell::Cell::new(None),
+                key_salt: String::new(),
+                prompt_hashes: std::collections::HashMap::new(),
+                class_context_checks: std::collections::HashSet::new(),
+                decorator_checks: std::collections::HashSet::new(),
+                _lock: None,
             });
         }
 
-        let cache_dir = cache_dir.unwrap_or_else(|| PathBuf::from(".loopsleuth_cache"));
+        let db_path = match cache_file {
+            Some(path) => {
+                if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                    fs::create_dir_all(parent)
+                        .with_context(|| format!("Failed to create cache file directory: {}", parent.display()))?;
+                }
+                path
+            }
+            None => {
+                let cache_dir = cache_dir.unwrap_or_else(|| PathBuf::from(DEFAULT_CACHE_DIR));
 
-        // Create cache directory if it doesn't exist
-        fs::create_dir_all(&cache_dir)
-            .context("Failed to create cache directory")?;
+                // Create cache directory if it doesn't exist
+                fs::create_dir_all(&cache_dir)
+                    .context("Failed to create cache directory")?;
 
-        let db_path = cache_dir.join("analysis_cache.db");
+                cache_dir.join("analysis_cache.db")
+            }
+        };
+        let lock = if lock { Some(lock_cache(&db_path)?) } else { None };
         let conn = Connection::open(&db_path)
             .context("Failed to open cache database")?;
 
+        // WAL avoids an fsync per commit; a larger page cache helps big scans
+        conn.query_row("PRAGMA journal_mode = WAL", [], |row| row.get::<_, String>(0))
+            .context("Failed to enable WAL mode for cache database")?;
+        conn.execute_batch("PRAGMA synchronous = NORMAL; PRAGMA cache_size = -65536;")?;
+
         // Migrate old schema if it exists
         Self::migrate_schema(&conn)?;
 
@@ -943,17 +3052,73 @@ impl AnalysisCache {
                 analysis TEXT NOT NULL,
                 solution TEXT,
                 created_at INTEGER NOT NULL,
+                stop_reason TEXT,
+                prompt_hash TEXT,
+                loopsleuth_version TEXT,
+                alternatives TEXT,
+                suggested_test TEXT,
+                rejection_stage TEXT,
+                rejection_reason TEXT,
                 PRIMARY KEY (function_hash, check_key)
             )",
             [],
         )?;
+        // Caches created before these were recorded lack the columns (their rows read as NULL)
+        for column in ["stop_reason", "prompt_hash", "loopsleuth_version", "alternatives", "suggested_test", "rejection_stage", "rejection_reason"] {
+            Self::add_missing_column(&conn, column)?;
+        }
+        // Every finding a run reported, for `dismiss` and --feedback-report (kept by --clear-cache)
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS findings (
+                finding_id TEXT PRIMARY KEY,
+                check_key TEXT NOT NULL,
+                location TEXT NOT NULL,
+                first_seen INTEGER NOT NULL,
+                last_seen INTEGER NOT NULL,
+                dismissed_at INTEGER
+            )",
+            [],
+        )?;
 
         Ok(Self {
             conn,
             enabled: true,
+            pending_writes: std::cell::Cell::new(0),
+            batch_started: std::cell::Cell::new(None),
+            key_salt: String::new(),
+            prompt_hashes: std::collections::HashMap::new(),
+            class_context_checks: std::collections::HashSet::new(),
+            decorator_checks: std::collections::HashSet::new(),
+            _lock: lock,
         })
     }
 
+    /// Keep results produced with different prompt overrides apart
+    fn with_key_salt(mut self, salt: String) -> Self {
+        self.key_salt = salt;
+        self
+    }
+
+    /// Invalidate cached results whose check prompts have changed since they were stored
+    /// (and, for checks using {class_context} or {decorators}, whose class `__init__` or
+    /// decorators have changed)
+    fn with_prompt_hashes(mut self, checks: &[CheckConfig]) -> Self {
+        self.prompt_hashes = checks.iter().map(|check| (check.key.clone(), check.prompt_hash())).collect();
+        self.class_context_checks = checks.iter()
+            .filter(|check| [&check.detection_prompt, &check.solution_prompt, &check.verifier_prompt]
+                .iter()
+                .any(|prompt| prompt.contains("{class_context}")))
+            .map(|check| check.key.clone())
+            .collect();
+        self.decorator_checks = checks.iter()
+            .filter(|check| [&check.detection_prompt, &check.solution_prompt, &check.verifier_prompt]
+                .iter()
+                .any(|prompt| prompt.contains("{decorators}")))
+            .map(|check| check.key.clone())
+            .collect();
+        self
+    }
+
     /// Migrate from old schema to new schema
     fn migrate_schema(conn: &Connection) -> Result<()> {
         // Check if old table exists
@@ -1009,6 +3174,17 @@ impl AnalysisCache {
         Ok(())
     }
 
+    /// Add a nullable TEXT column to `check_results` unless the table already has it
+    fn add_missing_column(conn: &Connection, column: &str) -> Result<()> {
+        let has_column = conn
+            .prepare("SELECT name FROM pragma_table_info('check_results') WHERE name = ?1")?
+            .exists(params![column])?;
+        if !has_column {
+            conn.execute(&format!("ALTER TABLE check_results ADD COLUMN {} TEXT", column), [])?;
+        }
+        Ok(())
+    }
+
     /// Compute SHA256 hash of function source code
     fn hash_function(source: &str) -> String {
         let mut hasher = Sha256::new();
@@ -1016,23 +3192,51 @@ impl AnalysisCache {
         format!("{:x}", hasher.finalize())
     }
 
+    /// Key a function's cached results on its source (and the prompt overrides, class
+    /// context and decorators, if any)
+    fn result_hash(&self, func: &FunctionInfo, check_key: &str) -> String {
+        let mut key = func.source.clone();
+        if !self.key_salt.is_empty() {
+            key = format!("{}\0{}", key, self.key_salt);
+        }
+        if !func.class_context.is_empty() && self.class_context_checks.contains(check_key) {
+            key = format!("{}\0class:{}", key, func.class_context);
+        }
+        if !func.decorators.is_empty() && self.decorator_checks.contains(check_key) {
+            key = format!("{}\0decorators:{}", key, func.decorators.join("\n"));
+        }
+        Self::hash_function(&key)
+    }
+
     /// Check if analysis result exists in cache
     fn get(&self, func: &FunctionInfo, check_key: &str) -> Result<Option<CachedResult>> {
         if !self.enabled {
             return Ok(None);
         }
 
-        let hash = Self::hash_function(&func.source);
+        let hash = self.result_hash(func, check_key);
 
+        // Rows stored with other prompts (or before prompts were hashed) are misses
         let mut stmt = self.conn.prepare(
-            "SELECT has_issue, analysis, solution FROM check_results WHERE function_hash = ?1 AND check_key = ?2"
+            "SELECT has_issue, analysis, solution, stop_reason, alternatives, suggested_test, rejection_stage, rejection_reason
+             FROM check_results
+             WHERE function_hash = ?1 AND check_key = ?2 AND prompt_hash IS ?3"
         )?;
 
-        let result = stmt.query_row(params![hash, check_key], |row| {
+        let result = stmt.query_row(params![hash, check_key, self.prompt_hashes.get(check_key)], |row| {
             Ok(CachedResult {
                 has_issue: row.get::<_, i32>(0)? != 0,
                 analysis: row.get(1)?,
                 solution: row.get(2)?,
+                stop_reason: row.get::<_, Option<String>>(3)?.as_deref().and_then(StopReason::parse),
+                alternatives: row.get::<_, Option<String>>(4)?
+                    .and_then(|json| serde_json::from_str(&json).ok())
+                    .unwrap_or_default(),
+                test: row.get(5)?,
+                rejection: match (row.get::<_, Option<String>>(6)?.as_deref().and_then(RejectionStage::parse), row.get::<_, Option<String>>(7)?) {
+                    (Some(stage), Some(reason)) => Some(Rejection::new(stage, reason)),
+                    _ => None,
+                },
             })
         });
 
@@ -1044,66 +3248,507 @@ impl AnalysisCache {
     }
 
     /// Store analysis result in cache
-    fn put(&self, func: &FunctionInfo, check_key: &str, has_issue: bool, analysis: &str, solution: Option<&str>) -> Result<()> {
+    fn put(
+        &self,
+        func: &FunctionInfo,
+        check_key: &str,
+        has_issue: bool,
+        analysis: &str,
+        solution: Option<&str>,
+        stop_reason: Option<StopReason>,
+    ) -> Result<()> {
         if !self.enabled {
             return Ok(());
         }
 
-        let hash = Self::hash_function(&func.source);
+        let hash = self.result_hash(func, check_key);
         let timestamp = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)?
             .as_secs() as i64;
 
-        self.conn.execute(
-            "INSERT OR REPLACE INTO check_results (function_hash, check_key, has_issue, analysis, solution, created_at)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-            params![hash, check_key, has_issue as i32, analysis, solution, timestamp],
-        )?;
+        if self.batch_started.get().is_none() {
+            self.conn.execute_batch("BEGIN")?;
+            self.batch_started.set(Some(Instant::now()));
+        }
+
+        self.conn.prepare_cached(
+            "INSERT OR REPLACE INTO check_results
+                (function_hash, check_key, has_issue, analysis, solution, created_at, stop_reason, prompt_hash, loopsleuth_version)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        )?.execute(params![
+            hash,
+            check_key,
+            has_issue as i32,
+            analysis,
+            solution,
+            timestamp,
+            stop_reason.map(StopReason::as_str),
+            self.prompt_hashes.get(check_key),
+            env!("CARGO_PKG_VERSION"),
+        ])?;
+
+        self.pending_writes.set(self.pending_writes.get() + 1);
+        let batch_age = self.batch_started.get().map(|started| started.elapsed()).unwrap_or_default();
+        if self.pending_writes.get() >= CACHE_BATCH_SIZE || batch_age >= CACHE_BATCH_MAX_AGE {
+            self.flush()?;
+        }
 
         Ok(())
     }
 
-    /// Clear all cache entries
-    fn clear(&self) -> Result<()> {
-        if !self.enabled {
+    /// Attach the lower-ranked solution candidates to a result stored by `put`
+    fn put_alternatives(&self, func: &FunctionInfo, check_key: &str, alternatives: &[String]) -> Result<()> {
+        if !self.enabled || alternatives.is_empty() {
             return Ok(());
         }
 
-        self.conn.execute("DELETE FROM check_results", [])?;
+        self.conn.prepare_cached(
+            "UPDATE check_results SET alternatives = ?3 WHERE function_hash = ?1 AND check_key = ?2",
+        )?.execute(params![
+            self.result_hash(func, check_key),
+            check_key,
+            serde_json::to_string(alternatives)?,
+        ])?;
         Ok(())
     }
 
-    /// Get cache statistics
-    fn stats(&self) -> Result<(usize, usize)> {
+    /// Record why the solution of a result stored by `put` was dropped
+    fn put_rejection(&self, func: &FunctionInfo, check_key: &str, rejection: &Rejection) -> Result<()> {
         if !self.enabled {
-            return Ok((0, 0));
+            return Ok(());
         }
 
-        let total: usize = self.conn.query_row(
-            "SELECT COUNT(*) FROM check_results",
-            [],
-            |row| row.get(0)
-        )?;
+        self.conn.prepare_cached(
+            "UPDATE check_results SET rejection_stage = ?3, rejection_reason = ?4 WHERE function_hash = ?1 AND check_key = ?2",
+        )?.execute(params![
+            self.result_hash(func, check_key),
+            check_key,
+            rejection.stage.as_str(),
+            rejection.reason,
+        ])?;
+        Ok(())
+    }
 
-        let with_issues: usize = self.conn.query_row(
-            "SELECT COUNT(*) FROM check_results WHERE has_issue = 1",
-            [],
-            |row| row.get(0)
-        )?;
+    /// Attach the --with-tests snippet to a result stored by `put`
+    fn put_test(&self, func: &FunctionInfo, check_key: &str, test: &str) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
 
-        Ok((total, with_issues))
+        self.conn.prepare_cached(
+            "UPDATE check_results SET suggested_test = ?3 WHERE function_hash = ?1 AND check_key = ?2",
+        )?.execute(params![self.result_hash(func, check_key), check_key, test])?;
+        Ok(())
     }
-}
 
-/// RAII guard that redirects stderr to /dev/null and restores it on drop (Unix only)
-#[cfg(unix)]
-struct StderrSuppressor {
-    original_stderr: Option<i32>,
-}
+    /// Commit the open write batch, if any
+    fn flush(&self) -> Result<()> {
+        if self.batch_started.take().is_some() {
+            self.pending_writes.set(0);
+            self.conn.execute_batch("COMMIT")?;
+        }
+        Ok(())
+    }
 
-#[cfg(unix)]
-impl StderrSuppressor {
-    fn new() -> Result<Self> {
+    /// Whether any of `checks` has a result for this exact function that `get` would serve
+    fn contains_function(&self, func: &FunctionInfo, checks: &[CheckConfig]) -> Result<bool> {
+        if !self.enabled {
+            return Ok(false);
+        }
+
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT 1 FROM check_results WHERE function_hash = ?1 AND check_key = ?2 AND prompt_hash IS ?3"
+        )?;
+        for check in checks {
+            let hash = self.result_hash(func, &check.key);
+            if stmt.exists(params![hash, check.key, self.prompt_hashes.get(&check.key)])? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Clear all cache entries
+    fn clear(&self) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        self.conn.execute("DELETE FROM check_results", [])?;
+        Ok(())
+    }
+
+    /// Drop results older than `max_age_days` (if given), then VACUUM the database
+    fn vacuum(&self, max_age_days: Option<u64>) -> Result<VacuumStats> {
+        self.flush()?;
+        let size = || -> Result<u64> {
+            Ok(self.conn.query_row(
+                "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+                [],
+                |row| row.get::<_, i64>(0),
+            )? as u64)
+        };
+        let bytes_before = size()?;
+
+        let pruned = match max_age_days {
+            Some(days) => {
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)?
+                    .as_secs() as i64;
+                let cutoff = now.saturating_sub(days.saturating_mul(86_400) as i64);
+                self.conn.execute("DELETE FROM check_results WHERE created_at < ?1", params![cutoff])?
+            }
+            None => 0,
+        };
+        let kept: usize = self.conn.query_row("SELECT COUNT(*) FROM check_results", [], |row| row.get(0))?;
+
+        self.conn.execute_batch("VACUUM")?;
+        // In WAL mode the compacted pages only reach the main file on checkpoint
+        self.conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+
+        Ok(VacuumStats { pruned, kept, bytes_before, bytes_after: size()? })
+    }
+
+    /// Remember the findings of a run: (finding ID, check key, location) each
+    fn record_findings(&self, findings: &[(String, String, String)]) -> Result<()> {
+        if !self.enabled || findings.is_empty() {
+            return Ok(());
+        }
+        // Commit the open batch of cached results first; it holds a transaction
+        self.flush()?;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs() as i64;
+        let tx = self.conn.unchecked_transaction()?;
+        for (finding_id, check_key, location) in findings {
+            tx.execute(
+                "INSERT INTO findings (finding_id, check_key, location, first_seen, last_seen)
+                 VALUES (?1, ?2, ?3, ?4, ?4)
+                 ON CONFLICT(finding_id) DO UPDATE SET location = ?3, last_seen = ?4",
+                params![finding_id, check_key, location, now],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Mark a recorded finding as a false positive; returns its check key and location,
+    /// or None when no run reported that ID
+    fn dismiss(&self, finding_id: &str) -> Result<Option<(String, String)>> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs() as i64;
+        self.conn.execute(
+            "UPDATE findings SET dismissed_at = ?2 WHERE finding_id = ?1 AND dismissed_at IS NULL",
+            params![finding_id, now],
+        )?;
+        Ok(self.conn.query_row(
+            "SELECT check_key, location FROM findings WHERE finding_id = ?1",
+            params![finding_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ).optional()?)
+    }
+
+    /// Recorded and dismissed findings per check and per month they were first reported
+    fn feedback(&self) -> Result<Vec<FeedbackRow>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT check_key, strftime('%Y-%m', first_seen, 'unixepoch') AS month,
+                    COUNT(*), COUNT(dismissed_at)
+             FROM findings GROUP BY check_key, month ORDER BY check_key, month",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(FeedbackRow {
+                check_key: row.get(0)?,
+                month: row.get(1)?,
+                findings: row.get(2)?,
+                dismissed: row.get(3)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    /// Get cache statistics
+    fn stats(&self) -> Result<(usize, usize)> {
+        if !self.enabled {
+            return Ok((0, 0));
+        }
+
+        let total: usize = self.conn.query_row(
+            "SELECT COUNT(*) FROM check_results",
+            [],
+            |row| row.get(0)
+        )?;
+
+        let with_issues: usize = self.conn.query_row(
+            "SELECT COUNT(*) FROM check_results WHERE has_issue = 1",
+            [],
+            |row| row.get(0)
+        )?;
+
+        Ok((total, with_issues))
+    }
+}
+
+/// Take an exclusive advisory lock on `<db>.lock` next to the cache database, waiting (with a
+/// notice) while another LoopSleuth run holds it. Released when the returned file is closed.
+#[cfg(unix)]
+fn lock_cache(db_path: &std::path::Path) -> Result<fs::File> {
+    let mut lock_path = db_path.as_os_str().to_os_string();
+    lock_path.push(".lock");
+    let lock_path = PathBuf::from(lock_path);
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .with_context(|| format!("Failed to open cache lock file: {}", lock_path.display()))?;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::WouldBlock {
+            return Err(err).with_context(|| format!("Failed to lock cache: {}", lock_path.display()));
+        }
+        eprintln!("⏳ Cache {} is in use by another LoopSleuth run; waiting for it to finish...", db_path.display());
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Failed to lock cache: {}", lock_path.display()));
+        }
+    }
+    Ok(file)
+}
+
+/// Advisory locking relies on flock(2), which Windows lacks
+#[cfg(not(unix))]
+fn lock_cache(_db_path: &std::path::Path) -> Result<fs::File> {
+    anyhow::bail!("--concurrency-safe-cache is only supported on Unix")
+}
+
+/// What `--cache-vacuum` removed and reclaimed
+#[derive(Debug)]
+struct VacuumStats {
+    pruned: usize,
+    kept: usize,
+    bytes_before: u64,
+    bytes_after: u64,
+}
+
+/// Findings recorded for one check in one month (`YYYY-MM`), for --feedback-report
+#[derive(Debug, PartialEq)]
+struct FeedbackRow {
+    check_key: String,
+    month: String,
+    findings: usize,
+    dismissed: usize,
+}
+
+/// (finding ID, check key, location) of every issue in a run, as recorded for `dismiss`
+fn finding_records(file_results: &[FileResults], options: &ReportOptions) -> Vec<(String, String, String)> {
+    let mut records = Vec::new();
+    for file_result in file_results {
+        let path = options.display_path(&file_result.file_path);
+        for result in &file_result.results {
+            let qualified_name = result.function.qualified_name();
+            for issue in result.check_results.iter().filter(|cr| cr.has_issue) {
+                records.push((
+                    options.finding_id(&file_result.file_path, &qualified_name, &issue.check_key),
+                    issue.check_key.clone(),
+                    format!("{}:{} {}", path, result.function.line_number, qualified_name),
+                ));
+            }
+        }
+    }
+    records
+}
+
+/// Handle `loopsleuth dismiss`: record findings as false positives
+fn run_dismiss(cli: &Cli, finding_ids: &[String]) -> Result<()> {
+    let cache = AnalysisCache::new(cli.cache_dir.clone(), cli.cache_file.clone(), true, cli.concurrency_safe_cache)?;
+    let mut unknown = Vec::new();
+    for finding_id in finding_ids {
+        match cache.dismiss(finding_id.trim())? {
+            Some((check_key, location)) => println!("🙈 Dismissed {} [{}] {}", finding_id.trim(), check_key, location),
+            None => unknown.push(finding_id.trim()),
+        }
+    }
+    if !unknown.is_empty() {
+        anyhow::bail!(
+            "Unknown finding ID(s): {} (IDs are the `finding_id` of a cached run's JSON report)",
+            unknown.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Handle `--feedback-report`: dismissal rate per check, overall and per month
+fn run_feedback_report(cli: &Cli) -> Result<()> {
+    let cache = AnalysisCache::new(cli.cache_dir.clone(), cli.cache_file.clone(), true, cli.concurrency_safe_cache)?;
+    let rows = cache.feedback()?;
+    if rows.is_empty() {
+        println!("No findings recorded yet: run an analysis with the cache enabled first.");
+        return Ok(());
+    }
+    let rate = |dismissed: usize, findings: usize| dismissed as f64 / findings.max(1) as f64 * 100.0;
+
+    println!("📊 Dismissal rate per check (findings marked as false positives with `loopsleuth dismiss`)\n");
+    for check_rows in rows.chunk_by(|a, b| a.check_key == b.check_key) {
+        let findings: usize = check_rows.iter().map(|row| row.findings).sum();
+        let dismissed: usize = check_rows.iter().map(|row| row.dismissed).sum();
+        println!("  {:<28} {:>5} finding(s), {:>4} dismissed ({:.1}%)",
+            check_rows[0].check_key, findings, dismissed, rate(dismissed, findings));
+        for row in check_rows {
+            println!("      {:<24} {:>5} finding(s), {:>4} dismissed ({:.1}%)",
+                row.month, row.findings, row.dismissed, rate(row.dismissed, row.findings));
+        }
+    }
+    println!();
+    Ok(())
+}
+
+/// Handle `--cache-vacuum`: compact the cache database and report the space reclaimed
+fn run_cache_vacuum(cli: &Cli) -> Result<()> {
+    let cache = AnalysisCache::new(cli.cache_dir.clone(), cli.cache_file.clone(), true, cli.concurrency_safe_cache)?;
+    println!("🧹 Vacuuming cache...");
+    let stats = cache.vacuum(cli.cache_max_age)?;
+    if let Some(days) = cli.cache_max_age {
+        println!("   Pruned {} result(s) older than {} day(s)", stats.pruned, days);
+    }
+    println!("   Kept {} cached result(s)", stats.kept);
+    println!(
+        "   Size: {:.1} KiB → {:.1} KiB ({:.1} KiB reclaimed)",
+        stats.bytes_before as f64 / 1024.0,
+        stats.bytes_after as f64 / 1024.0,
+        stats.bytes_before.saturating_sub(stats.bytes_after) as f64 / 1024.0
+    );
+    Ok(())
+}
+
+impl Drop for AnalysisCache {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            eprintln!("Warning: failed to write cached results: {}", e);
+        }
+    }
+}
+
+/// Warnings when the context llama.cpp created differs from `--context-size`, or when the
+/// request goes beyond what the model was trained on (0 = unknown)
+fn context_size_warnings(requested: u32, effective: u32, trained: u32) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if effective < requested {
+        warnings.push(format!(
+            "Requested --context-size {} but the model context only has {} tokens; prompt limits use {}",
+            requested, effective, effective
+        ));
+    }
+    if trained > 0 && requested > trained {
+        warnings.push(format!(
+            "--context-size {} exceeds the {} tokens the model was trained with; quality may degrade past that",
+            requested, trained
+        ));
+    }
+    warnings
+}
+
+/// Everything that determines a run's results, written to `<cache_dir>/last_run.json`
+#[derive(Serialize)]
+struct RunManifest {
+    loopsleuth_version: String,
+    backend: String,
+    model: String,
+    model_fingerprint: Option<String>,
+    context_size: u32,
+    threads: u32,
+    max_tokens: i32,
+    temperature: f32,
+    seed: Option<u64>,
+    add_bos: String,
+    stabilize: u32,
+    checks: Vec<ManifestCheck>,
+}
+
+#[derive(Serialize)]
+struct ManifestCheck {
+    key: String,
+    config_hash: String,
+}
+
+impl RunManifest {
+    fn new(cli: &Cli, checks: &[CheckConfig], api: Option<&ApiConfig>) -> Result<Self> {
+        let (backend, model, model_fingerprint) = match (api, &cli.model) {
+            (Some(api), _) => ("api".to_string(), format!("{} ({})", api.model_id, api.url), None),
+            (None, Some(path)) => ("local".to_string(), path.display().to_string(), model_fingerprint(path).ok()),
+            (None, None) => ("local".to_string(), String::new(), None),
+        };
+
+        let mut manifest_checks = Vec::new();
+        for check in checks {
+            let serialized = serde_json::to_string(check)?;
+            manifest_checks.push(ManifestCheck {
+                key: check.key.clone(),
+                config_hash: AnalysisCache::hash_function(&serialized),
+            });
+        }
+
+        Ok(Self {
+            loopsleuth_version: env!("CARGO_PKG_VERSION").to_string(),
+            backend,
+            model,
+            model_fingerprint,
+            context_size: cli.context_size,
+            threads: cli.threads,
+            max_tokens: cli.max_tokens,
+            temperature: cli.temperature,
+            seed: cli.seed,
+            add_bos: cli.add_bos.clone(),
+            stabilize: cli.stabilize,
+            checks: manifest_checks,
+        })
+    }
+
+    fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Short hash identifying this manifest, embedded in report footers
+    fn short_hash(&self) -> Result<String> {
+        let full = AnalysisCache::hash_function(&self.to_json()?);
+        Ok(full[..12].to_string())
+    }
+
+    fn write(&self, cache_dir: &std::path::Path) -> Result<PathBuf> {
+        fs::create_dir_all(cache_dir).context("Failed to create cache directory")?;
+        let path = cache_dir.join("last_run.json");
+        fs::write(&path, self.to_json()?)
+            .with_context(|| format!("Failed to write run manifest: {}", path.display()))?;
+        Ok(path)
+    }
+}
+
+/// Fingerprint a model file by its size and the SHA256 of its first 16 MiB.
+/// Hashing a multi-GB GGUF in full on every run would dominate startup time.
+fn model_fingerprint(path: &std::path::Path) -> Result<String> {
+    use std::io::Read;
+
+    let file = fs::File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut head = Vec::new();
+    file.take(16 * 1024 * 1024).read_to_end(&mut head)?;
+
+    let mut hasher = Sha256::new();
+    hasher.update(size.to_le_bytes());
+    hasher.update(&head);
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// RAII guard that redirects stderr to /dev/null and restores it on drop (Unix only)
+#[cfg(unix)]
+struct StderrSuppressor {
+    original_stderr: Option<i32>,
+}
+
+#[cfg(unix)]
+impl StderrSuppressor {
+    fn new() -> Result<Self> {
         unsafe {
             // Duplicate stderr so we can restore it later
             let original_stderr = libc::dup(libc::STDERR_FILENO);
@@ -1208,106 +3853,649 @@ impl StdoutSuppressor {
     }
 }
 
-struct AnalysisOutput {
-    file_results: Vec<FileResults>,
-    total_functions: usize,
-    functions_with_issues: usize,
-    stats: TokenStats,
+/// Remaining-time estimate from an exponential moving average of per-function generation time.
+/// Cache hits record near-zero time, so the estimate speeds up naturally on warm caches.
+#[derive(Default)]
+struct EtaEstimator {
+    secs_per_function: Option<f64>,
 }
 
-fn run_analysis_loop<F>(
-    python_files: &[PathBuf],
-    checks: &[CheckConfig],
-    cache: &AnalysisCache,
-    dedupe_rules: &[DedupeRule],
-    filter_function: Option<&str>,
-    skip_large: usize,
-    max_tokens: i32,
-    verbose: bool,
-    quiet: bool,
-    total_functions_count: usize,
-    generate_fn: &mut F,
-) -> Result<AnalysisOutput>
-where
-    F: FnMut(&str, i32, bool) -> Result<(String, bool, TokenStats)>,
-{
-    // Macro to print progress: stdout in text mode, stderr in json mode
-    macro_rules! progress {
-        ($($arg:tt)*) => {
-            if quiet {
-                eprint!($($arg)*);
-                std::io::Write::flush(&mut std::io::stderr()).ok();
-            } else {
-                print!($($arg)*);
-                std::io::Write::flush(&mut std::io::stdout()).ok();
+impl EtaEstimator {
+    /// Weight of the newest sample; low enough that one slow function doesn't make the ETA jump
+    const ALPHA: f64 = 0.2;
+
+    fn record(&mut self, elapsed: Duration) {
+        let sample = elapsed.as_secs_f64();
+        self.secs_per_function = Some(match self.secs_per_function {
+            Some(avg) => Self::ALPHA * sample + (1.0 - Self::ALPHA) * avg,
+            None => sample,
+        });
+    }
+
+    fn remaining(&self, remaining_functions: usize) -> Option<Duration> {
+        let avg = self.secs_per_function?;
+        if avg <= 0.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(avg * remaining_functions as f64))
+    }
+}
+
+/// Look of the progress bar (--progress-width, --progress-ascii)
+#[derive(Clone, Copy)]
+struct ProgressStyle {
+    width: usize,
+    ascii: bool,
+}
+
+impl ProgressStyle {
+    /// Narrowest bar drawn when shrinking to fit the terminal
+    const MIN_WIDTH: usize = 10;
+
+    fn from_cli(cli: &Cli) -> Self {
+        ProgressStyle { width: cli.progress_width, ascii: cli.progress_ascii }
+    }
+
+    /// `[███░░]` for `done` of `total`, shrunk so that `rest` more characters still fit on a
+    /// line of `columns` (when known); never below MIN_WIDTH unless the configured width is
+    fn render(&self, done: usize, total: usize, rest: usize, columns: Option<usize>) -> String {
+        let width = match columns {
+            // +2 for the brackets
+            Some(columns) if self.width + 2 + rest > columns => {
+                columns.saturating_sub(rest + 2).max(Self::MIN_WIDTH.min(self.width))
             }
+            _ => self.width,
         };
+        let filled = ((done as f32 / total.max(1) as f32) * width as f32) as usize;
+        let filled = filled.min(width);
+        let (full, blank) = if self.ascii { ("#", "-") } else { ("█", "░") };
+        format!("[{}{}]", full.repeat(filled), blank.repeat(width - filled))
     }
+}
 
-    let mut all_file_results: Vec<FileResults> = Vec::new();
-    let mut total_functions = 0;
-    let mut current_func_num = 0;
-    let mut functions_with_issues = 0;
-    let mut total_stats = TokenStats::default();
+/// Current terminal width, if the output is a terminal
+fn terminal_columns() -> Option<usize> {
+    ratatui::crossterm::terminal::size().ok().map(|(columns, _)| columns as usize)
+}
 
-    for file_path in python_files {
-        let mut functions = extract_functions(&file_path)?;
+/// Format a duration compactly for the progress line (e.g. "4m20s", "1h05m", "35s")
+fn format_eta(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}h{:02}m", secs / 3600, (secs % 3600) / 60)
+    } else if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
 
-        if let Some(filter) = filter_function {
-            let filter_lower = filter.to_lowercase();
-            functions.retain(|func| func.name.to_lowercase().contains(&filter_lower));
-        }
+/// Rendering options for the report writers
+#[derive(Default)]
+struct ReportOptions {
+    no_cache: bool,
+    /// Short hash of the run manifest, shown in the report footer
+    manifest_hash: Option<String>,
+    /// Base directory that reported paths are shown relative to
+    relative_to: Option<PathBuf>,
+    /// Analyzed directory (a single file's own directory) that finding IDs are keyed on
+    finding_root: Option<PathBuf>,
+    /// Leave source code (original, context and diffs) out of reports
+    redact: bool,
+    /// Contents of --report-template, replacing the built-in HTML skeleton
+    report_template: Option<String>,
+    /// --emit-optimized: show the full optimized function alongside diffs
+    emit_optimized: bool,
+}
 
-        let mut file_results = Vec::new();
+impl ReportOptions {
+    fn display_path(&self, path: &std::path::Path) -> String {
+        display_path(path, self.relative_to.as_deref())
+    }
 
-        for func in functions {
-            total_functions += 1;
-            current_func_num += 1;
+    /// Finding ID keyed on the path below the analyzed directory, so it stays the same
+    /// whatever the working directory or --relative-to
+    fn finding_id(&self, path: &std::path::Path, qualified_name: &str, check_key: &str) -> String {
+        finding_id(&display_path(path, self.finding_root.as_deref()), qualified_name, check_key)
+    }
 
-            let progress_pct = (current_func_num as f32 / total_functions_count as f32 * 100.0) as usize;
-            let bar_width = 30;
-            let filled = (current_func_num as f32 / total_functions_count as f32 * bar_width as f32) as usize;
-            let empty = bar_width - filled;
-            let progress_bar = format!("[{}{}]", "█".repeat(filled), "░".repeat(empty));
+    /// Source lines just above the function, for reviewers only (never sent to the model)
+    fn context_above<'f>(&self, func: &'f FunctionInfo) -> Option<&'f str> {
+        if self.redact {
+            return None;
+        }
+        func.context_above.as_deref()
+    }
 
-            let filename = file_path.file_name()
-                .and_then(|n| n.to_str())
-                .unwrap_or("unknown");
+    /// The complete optimized function behind an accepted code fix, with --emit-optimized
+    fn optimized_code(&self, checks: &[CheckConfig], issue: &CheckResult) -> Option<String> {
+        if !self.emit_optimized || self.redact || solution_format_for(checks, &issue.check_key) == SolutionFormat::Prose {
+            return None;
+        }
+        issue.solution.as_deref().and_then(optimized_code_from_solution)
+    }
+}
 
-            let func_display = if let Some(ref class_name) = func.class_name {
-                format!("{}::{}::{}", filename, class_name, func.name)
-            } else {
-                format!("{}::{}", filename, func.name)
-            };
+/// Up to `count` lines preceding 1-based `line_number`, or None at the top of the file
+fn lines_above(content: &str, line_number: usize, count: usize) -> Option<String> {
+    let end = line_number.saturating_sub(1);
+    let start = end.saturating_sub(count);
+    if start == end {
+        return None;
+    }
+    let lines: Vec<&str> = content.lines().skip(start).take(end - start).collect();
+    if lines.is_empty() {
+        None
+    } else {
+        Some(lines.join("\n"))
+    }
+}
 
-            if skip_large > 0 {
-                let line_count = func.source.lines().count();
-                if line_count > skip_large {
-                    progress!("\r\x1b[K{} {}% [{}/{}] | Issues: {} | ⊗ Skipped: {} (too large)",
-                           progress_bar, progress_pct, current_func_num, total_functions_count,
-                           functions_with_issues, func_display);
-                    continue;
-                }
-            }
+/// Render `path` relative to `base` when it lies underneath it, otherwise unchanged
+fn display_path(path: &std::path::Path, base: Option<&std::path::Path>) -> String {
+    let Some(base) = base else {
+        return path.display().to_string();
+    };
 
-            let mut check_results = Vec::new();
+    let relative = path.strip_prefix(base).ok().map(|p| p.to_path_buf()).or_else(|| {
+        let path = path.canonicalize().ok()?;
+        let base = base.canonicalize().ok()?;
+        path.strip_prefix(&base).ok().map(|p| p.to_path_buf())
+    });
 
-            for check in checks {
-                if let Some(reason) = guard_skip_reason(check, &func)? {
-                    let analysis = format!(
-                        "VERDICT: OK\nCONFIDENCE: 0.00\nDETAIL: Skipped by guard ({})\nEND",
-                        reason
+    match relative {
+        Some(rel) if !rel.as_os_str().is_empty() => rel.display().to_string(),
+        // The base is the file itself: fall back to its name
+        Some(_) => path.file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| path.display().to_string()),
+        None => path.display().to_string(),
+    }
+}
+
+struct AnalysisOutput {
+    file_results: Vec<FileResults>,
+    total_functions: usize,
+    functions_with_issues: usize,
+    stats: TokenStats,
+    /// --fail-fast hit an issue before every function was analyzed
+    stopped_early: bool,
+    /// --max-functions was reached before every function was analyzed
+    capped: bool,
+    /// Functions for which every check was scoped, guarded, pre-filtered or filtered out
+    unchecked_functions: Vec<String>,
+    /// Per check key: functions skipped by its prefilter_regex (LLM calls saved)
+    prefiltered: std::collections::BTreeMap<String, usize>,
+    /// Files skipped because they failed to parse (filled in by the caller's first pass)
+    unparseable_files: Vec<PathBuf>,
+    /// Everything left out of the analysis, by stage (file-level skips are added by the caller)
+    skips: Vec<Skip>,
+}
+
+/// Where in the pipeline something was left out of the analysis
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum SkipStage {
+    /// The file failed to parse
+    Parse,
+    /// --staged: the file is not in the git index
+    Staged,
+    /// --skip-tests, or a check with `applies_to_tests = false` on a test file
+    Tests,
+    /// --skip-large / --skip-small
+    Size,
+    /// The body is only a stub (`pass`, `...`, docstring)
+    Stub,
+    /// A `# loopsleuth:` comment on the function (`ignore`, `only=`, `skip=`)
+    Directive,
+    /// A check's guard ruled the function out
+    Guard,
+    /// A check's structural filter ruled the function out
+    Structural,
+    /// A check's prefilter_regex did not match
+    Prefilter,
+    /// Generation failed (e.g. the function is too large for the context)
+    Error,
+}
+
+/// One file, function or function/check pair left out of the analysis, and why
+#[derive(Debug, Clone)]
+struct Skip {
+    file: PathBuf,
+    /// Qualified function name and line; `None` when the whole file was skipped
+    function: Option<(String, usize)>,
+    /// Set when only one check was skipped for the function
+    check: Option<String>,
+    stage: SkipStage,
+    reason: String,
+}
+
+impl Skip {
+    fn file(file: &std::path::Path, stage: SkipStage, reason: impl Into<String>) -> Self {
+        Skip { file: file.to_path_buf(), function: None, check: None, stage, reason: reason.into() }
+    }
+
+    fn function(func: &FunctionInfo, stage: SkipStage, reason: impl Into<String>) -> Self {
+        let name = func.qualified_name();
+        Skip {
+            file: func.file_path.clone(),
+            function: Some((name, func.line_number)),
+            check: None,
+            stage,
+            reason: reason.into(),
+        }
+    }
+
+    fn check(func: &FunctionInfo, check_key: &str, stage: SkipStage, reason: impl Into<String>) -> Self {
+        Skip { check: Some(check_key.to_string()), ..Skip::function(func, stage, reason) }
+    }
+}
+
+/// JSON form of the skips, shared by `--format json` and --skips-report
+fn skips_json(skips: &[Skip], options: &ReportOptions) -> serde_json::Value {
+    serde_json::Value::Array(skips.iter().map(|skip| {
+        serde_json::json!({
+            "file": options.display_path(&skip.file),
+            "function": skip.function.as_ref().map(|(name, _)| name),
+            "line_number": skip.function.as_ref().map(|(_, line)| line),
+            "check": skip.check,
+            "stage": skip.stage,
+            "reason": skip.reason,
+        })
+    }).collect())
+}
+
+/// The aggregate stats written by --summary-json. The health score is the percentage of
+/// analyzed functions without a (non-advisory) issue
+fn summary_json(output: &AnalysisOutput, file_count: usize, checks: &[CheckConfig]) -> serde_json::Value {
+    let issues: Vec<&CheckResult> = output.file_results.iter()
+        .flat_map(|fr| &fr.results)
+        .flat_map(|r| r.check_results.iter().filter(|cr| cr.has_issue))
+        .collect();
+    let mut issues_by_check: std::collections::BTreeMap<&str, usize> =
+        checks.iter().map(|check| (check.key.as_str(), 0)).collect();
+    for issue in &issues {
+        *issues_by_check.entry(&issue.check_key).or_default() += 1;
+    }
+    let blocked = output.file_results.iter()
+        .flat_map(|fr| &fr.results)
+        .filter(|r| r.check_results.iter().any(|cr| cr.has_issue && !cr.advisory))
+        .count();
+    let health_score = if output.total_functions == 0 {
+        100.0
+    } else {
+        let clean = output.total_functions.saturating_sub(blocked) as f64 / output.total_functions as f64;
+        (clean * 1000.0).round() / 10.0
+    };
+
+    serde_json::json!({
+        "files": file_count,
+        "total_functions": output.total_functions,
+        "functions_with_issues": output.functions_with_issues,
+        "issues": issues.len(),
+        "advisory_issues": issues.iter().filter(|cr| cr.advisory).count(),
+        "issues_by_check": issues_by_check,
+        "skipped": output.skips.len(),
+        "token_usage": {
+            "input_tokens": output.stats.input_tokens,
+            "output_tokens": output.stats.output_tokens,
+            "generation_secs": output.stats.generation_time.as_secs_f64(),
+        },
+        "health_score": health_score,
+        "stopped_early": output.stopped_early,
+        "capped": output.capped,
+    })
+}
+
+/// Appended to the analysis of issues found with --no-solution
+const NO_SOLUTION_NOTE: &str = "[Solution skipped: --no-solution]";
+
+/// Appended to the analysis of issues found by checks left out of --solutions-for
+const SOLUTIONS_FOR_NOTE: &str = "[Solution skipped: not in --solutions-for]";
+
+/// Analysis note for issues found in a function marked `# loopsleuth: no-solution`
+const NO_SOLUTION_DIRECTIVE_NOTE: &str = "[Solution skipped: # loopsleuth: no-solution]";
+
+/// Whether a (cached) analysis was produced without the solution phase
+fn is_detection_only(analysis: &str) -> bool {
+    [NO_SOLUTION_NOTE, SOLUTIONS_FOR_NOTE, NO_SOLUTION_DIRECTIVE_NOTE].iter().any(|note| analysis.ends_with(note))
+}
+
+/// Per-function overrides from `# loopsleuth: ...` comments right above a function, between
+/// its decorators or at the end of its `def` line, e.g. `# loopsleuth: only=quadratic no-solution`
+#[derive(Debug, Default, PartialEq)]
+struct FunctionDirectives {
+    /// `ignore`: leave the function out of the analysis
+    ignore: bool,
+    /// `only=KEYS`: run just these checks (same selectors as --checks)
+    only: Option<Vec<String>>,
+    /// `skip=KEYS`: don't run these checks
+    skip: Vec<String>,
+    /// `confidence-threshold=X`: findings below X are advisory, as with --warn-below-confidence
+    confidence_threshold: Option<f32>,
+    /// `no-solution`: report issues without generating a solution
+    no_solution: bool,
+    /// Directives that weren't understood, reported as warnings
+    unknown: Vec<String>,
+}
+
+impl FunctionDirectives {
+    /// Directives for `func`, read from the file `source` it was extracted from
+    fn for_function(source: &str, func: &FunctionInfo) -> Self {
+        let lines: Vec<&str> = source.lines().collect();
+        let start = func.line_number.saturating_sub(1).min(lines.len());
+        let indent = |line: &str| line.len() - line.trim_start().len();
+        let def_indent = lines.get(start).map_or(0, |line| indent(line));
+        // Comments above the decorators count too, but not a trailing comment indented into
+        // the previous function's body
+        let above = lines[..start].iter().rev().take_while(|line| {
+            line.trim_start().starts_with(['#', '@']) && indent(line) == def_indent
+        });
+        let header = lines[start..].iter().take(func.header_lines.max(1));
+        Self::parse(above.chain(header).copied())
+    }
+
+    fn parse<'a>(lines: impl Iterator<Item = &'a str>) -> Self {
+        let mut directives = Self::default();
+        let comments = lines.filter_map(|line| line.split_once('#').map(|(_, comment)| comment.trim()));
+        for comment in comments {
+            let Some(words) = comment.strip_prefix("loopsleuth:") else {
+                continue;
+            };
+            for word in words.split_whitespace() {
+                match word.split_once('=') {
+                    None if word == "ignore" => directives.ignore = true,
+                    None if word == "no-solution" => directives.no_solution = true,
+                    Some(("only", keys)) => directives.only = Some(parse_check_keys(keys)),
+                    Some(("skip", keys)) => directives.skip.extend(parse_check_keys(keys)),
+                    Some(("confidence-threshold", value)) => match value.parse::<f32>() {
+                        Ok(threshold) if (0.0..=1.0).contains(&threshold) => directives.confidence_threshold = Some(threshold),
+                        _ => directives.unknown.push(word.to_string()),
+                    },
+                    _ => directives.unknown.push(word.to_string()),
+                }
+            }
+        }
+        directives
+    }
+
+    /// Whether `only=`/`skip=` leave `check` to run on the function
+    fn runs(&self, check: &CheckConfig) -> bool {
+        let selected = |keys: &[String]| keys.iter().any(|key| check_selector_matches(key, check));
+        self.only.as_deref().is_none_or(selected) && !selected(&self.skip)
+    }
+}
+
+/// Reason recorded when no optimized function could be pulled out of a solution
+const EXTRACT_FAILURE_REASON: &str = "Could not extract optimized function";
+
+/// Functions that must have gone through the model before --min-throughput is enforced, so
+/// the first (often slower) generations don't abort the run on their own
+const MIN_THROUGHPUT_WARMUP: usize = 3;
+
+/// The --min-throughput diagnostic when the measured output speed is below `min_throughput`
+fn slow_generation_error(stats: &TokenStats, generated_functions: usize, min_throughput: f64) -> Option<String> {
+    let speed = stats.tokens_per_second();
+    if generated_functions < MIN_THROUGHPUT_WARMUP || speed >= min_throughput {
+        return None;
+    }
+    Some(format!(
+        "Generation is too slow: {:.2} tok/s over the first {} functions, below --min-throughput {}. \
+         Try fewer --threads (the number of physical cores is usually best), a smaller --context-size, \
+         a smaller or more quantized model, or a GPU-enabled build",
+        speed, generated_functions, min_throughput
+    ))
+}
+
+/// Settings of an analysis run, built once from the command line for `run_analysis_loop`
+#[derive(Clone, Copy)]
+struct LoopOptions<'a> {
+    filter_function: Option<&'a str>,
+    nested_functions: bool,
+    origin: SourceOrigin<'a>,
+    test_patterns: &'a [String],
+    context_lines: usize,
+    skip_large: usize,
+    skip_small: usize,
+    max_functions: Option<usize>,
+    /// Functions picked by --sample (None analyzes everything)
+    selected: Option<&'a SampledFunctions>,
+    max_tokens: i32,
+    temperature: f32,
+    retry_truncated: bool,
+    no_solution: bool,
+    solutions_for: Option<&'a [String]>,
+    with_tests: bool,
+    reverify: bool,
+    stabilize: u32,
+    verify_min_confidence: Option<f32>,
+    collapse_similar: Option<f32>,
+    blend_confidence: bool,
+    warn_below_confidence: Option<f32>,
+    fail_fast: bool,
+    min_throughput: Option<f64>,
+    verbose: bool,
+    /// Progress goes to stderr (JSON output on stdout)
+    quiet: bool,
+    progress_style: ProgressStyle,
+}
+
+impl<'a> LoopOptions<'a> {
+    fn from_cli(
+        cli: &'a Cli,
+        selected: Option<&'a SampledFunctions>,
+        solutions_for: Option<&'a [String]>,
+        quiet: bool,
+    ) -> Self {
+        LoopOptions {
+            filter_function: cli.filter_function.as_deref(),
+            nested_functions: cli.nested_functions,
+            origin: SourceOrigin::from_cli(cli),
+            test_patterns: &cli.test_pattern,
+            context_lines: cli.include_context_lines,
+            skip_large: cli.skip_large,
+            skip_small: cli.skip_small,
+            max_functions: cli.max_functions,
+            selected,
+            max_tokens: cli.max_tokens,
+            temperature: cli.temperature,
+            retry_truncated: cli.retry_truncated,
+            no_solution: cli.no_solution,
+            solutions_for,
+            with_tests: cli.with_tests,
+            reverify: cli.reverify,
+            stabilize: cli.stabilize,
+            verify_min_confidence: cli.verify_min_confidence,
+            collapse_similar: cli.collapse_similar,
+            blend_confidence: cli.blend_confidence,
+            warn_below_confidence: cli.warn_below_confidence,
+            fail_fast: cli.fail_fast,
+            min_throughput: cli.min_throughput,
+            verbose: cli.verbose,
+            quiet,
+            progress_style: ProgressStyle::from_cli(cli),
+        }
+    }
+}
+
+fn run_analysis_loop<F>(
+    python_files: &[PathBuf],
+    root: &std::path::Path,
+    checks: &[CheckConfig],
+    cache: &AnalysisCache,
+    dedupe_rules: &[DedupeRule],
+    options: &LoopOptions,
+    stream_report: Option<&ReportOptions>,
+    mut live_report: Option<&mut LiveReport>,
+    total_functions_count: usize,
+    generate_fn: &mut F,
+) -> Result<AnalysisOutput>
+where
+    F: FnMut(&Prompt, i32, f32, bool) -> Result<(String, StopReason, TokenStats)>,
+{
+    let LoopOptions {
+        filter_function, nested_functions, origin, test_patterns, context_lines, skip_large, skip_small, max_functions,
+        selected, max_tokens, temperature, retry_truncated, no_solution, solutions_for, with_tests,
+        reverify, stabilize, verify_min_confidence, collapse_similar, blend_confidence,
+        warn_below_confidence, fail_fast, min_throughput, verbose, quiet, progress_style,
+    } = *options;
+
+    // Macro to print progress: stdout in text mode, stderr in json mode
+    macro_rules! progress {
+        ($($arg:tt)*) => {
+            if quiet {
+                eprint!($($arg)*);
+                std::io::Write::flush(&mut std::io::stderr()).ok();
+            } else {
+                print!($($arg)*);
+                std::io::Write::flush(&mut std::io::stdout()).ok();
+            }
+        };
+    }
+
+    let mut all_file_results: Vec<FileResults> = Vec::new();
+    let longest_check_key = checks.iter().map(|c| c.key.chars().count()).max().unwrap_or(0);
+    let mut total_functions = 0;
+    let mut current_func_num = 0;
+    let mut functions_with_issues = 0;
+    let mut total_stats = TokenStats::default();
+    let mut eta = EtaEstimator::default();
+    let mut stopped_early = false;
+    let mut capped = false;
+    let mut generated_functions = 0;
+    let mut unchecked_functions: Vec<String> = Vec::new();
+    let mut prefiltered: std::collections::BTreeMap<String, usize> = std::collections::BTreeMap::new();
+    let mut skips: Vec<Skip> = Vec::new();
+
+    let include_classes = checks.iter().any(|c| c.scope == CheckScope::Class);
+
+    for file_path in python_files {
+        let source = read_python_source(file_path, origin)?;
+        let mut functions = extract_functions_from_source(&source, file_path, root, include_classes, nested_functions)?;
+        let in_test_file = is_test_file(file_path, root, test_patterns);
+        for func in &mut functions {
+            // Taken from the source just analyzed, which may not be the working tree
+            func.context_above = lines_above(&source, func.line_number, context_lines);
+        }
+
+        if let Some(filter) = filter_function {
+            let filter_lower = filter.to_lowercase();
+            functions.retain(|func| func.name.to_lowercase().contains(&filter_lower));
+        }
+
+        if let Some(selected) = selected {
+            functions.retain(|func| selected.contains(&sample_key(func)));
+        }
+
+        let mut file_results = Vec::new();
+
+        for func in functions {
+            if max_functions.is_some_and(|max| total_functions >= max) {
+                capped = true;
+                break;
+            }
+
+            total_functions += 1;
+            current_func_num += 1;
+
+            let _function_span = tracing::info_span!(
+                "analyze_function",
+                file = %file_path.display(),
+                function = %func.name,
+                line = func.line_number
+            )
+            .entered();
+
+            let progress_pct = (current_func_num as f32 / total_functions_count as f32 * 100.0) as usize;
+            let remaining_functions = total_functions_count.saturating_sub(current_func_num) + 1;
+            let eta_display = eta.remaining(remaining_functions)
+                .map(|left| format!(" ~{} left", format_eta(left)))
+                .unwrap_or_default();
+            let generation_time_before = total_stats.generation_time;
+
+            let filename = file_path.file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or("unknown");
+
+            let func_display = format!("{}::{}", filename, func.qualified_name());
+
+            // Everything after the bar: counters, then a status of roughly `⚠️  [check] func`
+            let progress_rest = format!(" {}% [{}/{}]{} | Issues: {} | ",
+                progress_pct, current_func_num, total_functions_count, eta_display, functions_with_issues)
+                .chars().count() + longest_check_key + func_display.chars().count() + 6;
+            let progress_bar = progress_style.render(
+                current_func_num, total_functions_count, progress_rest, terminal_columns());
+
+            let directives = FunctionDirectives::for_function(&source, &func);
+            for word in &directives.unknown {
+                tracing::warn!("Ignoring unknown loopsleuth directive '{}' at {}:{}", word, file_path.display(), func.line_number);
+            }
+            if directives.ignore {
+                skips.push(Skip::function(&func, SkipStage::Directive, "# loopsleuth: ignore"));
+                progress!("\r\x1b[K{} {}% [{}/{}]{} | Issues: {} | ⊗ Skipped: {} (loopsleuth: ignore)",
+                       progress_bar, progress_pct, current_func_num, total_functions_count, eta_display,
+                       functions_with_issues, func_display);
+                continue;
+            }
+
+            if skip_large > 0 {
+                let line_count = func.source.lines().count();
+                if line_count > skip_large {
+                    skips.push(Skip::function(&func, SkipStage::Size,
+                        format!("{} lines, over --skip-large {}", line_count, skip_large)));
+                    progress!("\r\x1b[K{} {}% [{}/{}]{} | Issues: {} | ⊗ Skipped: {} (too large)",
+                           progress_bar, progress_pct, current_func_num, total_functions_count, eta_display,
+                           functions_with_issues, func_display);
+                    continue;
+                }
+            }
+
+            if skip_small > 0 {
+                let line_count = func.source.lines().count();
+                if line_count < skip_small {
+                    skips.push(Skip::function(&func, SkipStage::Size,
+                        format!("{} lines, under --skip-small {}", line_count, skip_small)));
+                    progress!("\r\x1b[K{} {}% [{}/{}]{} | Issues: {} | ⊗ Skipped: {} (too small)",
+                           progress_bar, progress_pct, current_func_num, total_functions_count, eta_display,
+                           functions_with_issues, func_display);
+                    continue;
+                }
+            }
+
+            if func.is_stub {
+                skips.push(Skip::function(&func, SkipStage::Stub, "stub body"));
+                progress!("\r\x1b[K{} {}% [{}/{}]{} | Issues: {} | ⊗ Skipped: {} (stub body)",
+                       progress_bar, progress_pct, current_func_num, total_functions_count, eta_display,
+                       functions_with_issues, func_display);
+                continue;
+            }
+
+            let mut check_results = Vec::new();
+            let mut applicable_checks = 0;
+
+            for check in checks {
+                if !check.applies_to_unit(&func) {
+                    continue;
+                }
+                if in_test_file && !check.applies_to_tests {
+                    skips.push(Skip::check(&func, &check.key, SkipStage::Tests, "applies_to_tests = false"));
+                    continue;
+                }
+                if !directives.runs(check) {
+                    skips.push(Skip::check(&func, &check.key, SkipStage::Directive, "excluded by # loopsleuth: only=/skip="));
+                    continue;
+                }
+
+                if let Some(reason) = guard_skip_reason(check, &func)? {
+                    let analysis = format!(
+                        "VERDICT: OK\nCONFIDENCE: 0.00\nDETAIL: Skipped by guard ({})\nEND",
+                        reason
                     );
-                    let _ = cache.put(&func, &check.key, false, &analysis, None);
-                    check_results.push(CheckResult {
-                        check_key: check.key.to_string(),
-                        check_name: check.name.to_string(),
-                        has_issue: false,
-                        analysis,
-                        solution: None,
-                    });
-                    progress!("\r\x1b[K{} {}% [{}/{}] | Issues: {} | ⏭️  [{}] {}",
-                           progress_bar, progress_pct, current_func_num, total_functions_count,
+                    skips.push(Skip::check(&func, &check.key, SkipStage::Guard, reason));
+                    let _ = cache.put(&func, &check.key, false, &analysis, None, None);
+                    check_results.push(CheckResult::new(check, false, analysis));
+                    progress!("\r\x1b[K{} {}% [{}/{}]{} | Issues: {} | ⏭️  [{}] {}",
+                           progress_bar, progress_pct, current_func_num, total_functions_count, eta_display,
                            functions_with_issues, check.key, func_display);
                     continue;
                 }
@@ -1317,233 +4505,503 @@ where
                         "VERDICT: OK\nCONFIDENCE: 0.00\nDETAIL: Skipped by structural filter ({})\nEND",
                         reason
                     );
-                    let _ = cache.put(&func, &check.key, false, &analysis, None);
-                    check_results.push(CheckResult {
-                        check_key: check.key.to_string(),
-                        check_name: check.name.to_string(),
-                        has_issue: false,
-                        analysis,
-                        solution: None,
-                    });
-                    progress!("\r\x1b[K{} {}% [{}/{}] | Issues: {} | ⏭️  [{}] {}",
-                           progress_bar, progress_pct, current_func_num, total_functions_count,
+                    skips.push(Skip::check(&func, &check.key, SkipStage::Structural, reason));
+                    let _ = cache.put(&func, &check.key, false, &analysis, None, None);
+                    check_results.push(CheckResult::new(check, false, analysis));
+                    progress!("\r\x1b[K{} {}% [{}/{}]{} | Issues: {} | ⏭️  [{}] {}",
+                           progress_bar, progress_pct, current_func_num, total_functions_count, eta_display,
+                           functions_with_issues, check.key, func_display);
+                    continue;
+                }
+
+                if is_prefiltered(check, &func) {
+                    *prefiltered.entry(check.key.clone()).or_default() += 1;
+                    skips.push(Skip::check(&func, &check.key, SkipStage::Prefilter, "prefilter_regex did not match"));
+                    let analysis = "VERDICT: OK\nCONFIDENCE: 0.00\nDETAIL: Skipped by prefilter_regex\nEND".to_string();
+                    check_results.push(CheckResult::new(check, false, analysis));
+                    progress!("\r\x1b[K{} {}% [{}/{}]{} | Issues: {} | ⏩ [{}] {}",
+                           progress_bar, progress_pct, current_func_num, total_functions_count, eta_display,
                            functions_with_issues, check.key, func_display);
                     continue;
                 }
 
-                if let Ok(Some(cached)) = cache.get(&func, &check.key) {
+                applicable_checks += 1;
+
+                let skip_note = if no_solution {
+                    Some(NO_SOLUTION_NOTE)
+                } else if directives.no_solution {
+                    Some(NO_SOLUTION_DIRECTIVE_NOTE)
+                } else if solutions_for.is_some_and(|keys| !keys.iter().any(|key| check_selector_matches(key, check))) {
+                    Some(SOLUTIONS_FOR_NOTE)
+                } else {
+                    None
+                };
+
+                // Detection-only results from --no-solution or --solutions-for don't satisfy a full run
+                let cached = cache.get(&func, &check.key).ok().flatten().filter(|cached| {
+                    skip_note.is_some() || !is_detection_only(&cached.analysis)
+                });
+                if let Some(mut cached) = cached {
+                    let stats_before = total_stats.clone();
+                    // Prose recommendations carry no code for the verifier to check
+                    let reverifiable = check.solution_format != SolutionFormat::Prose && cached.solution.is_some();
+                    if reverify && !check.verifier_prompt.is_empty() && reverifiable {
+                        progress!("\r\x1b[K{} {}% [{}/{}]{} | Issues: {} | 🔁 [{}] Re-verifying cached solution...",
+                               progress_bar, progress_pct, current_func_num, total_functions_count, eta_display,
+                               functions_with_issues, check.key);
+
+                        // The first candidate the verifier still accepts becomes the solution
+                        let cached_solutions = (cached.solution.clone(), cached.alternatives.clone());
+                        let mut candidates = cached.solution.take().into_iter()
+                            .chain(std::mem::take(&mut cached.alternatives))
+                            .collect::<Vec<_>>()
+                            .into_iter();
+                        let mut first_rejection = None;
+                        let mut accepted_confidence = None;
+                        let mut reverified = true;
+                        for candidate in candidates.by_ref() {
+                            let verifier_result = optimized_code_from_solution(&candidate).and_then(|optimized_code| {
+                                verify_solution(check, &func, &optimized_code, max_tokens, temperature, verbose, generate_fn)
+                            });
+                            let Some((verification, stats)) = verifier_result else {
+                                tracing::warn!(
+                                    "Could not re-verify cached solution for {} ({}); keeping the cached result",
+                                    check.key, func.name
+                                );
+                                reverified = false;
+                                break;
+                            };
+                            total_stats.add(&stats);
+                            if let Some(reason) = verifier_rejection(&verification, verify_min_confidence) {
+                                tracing::debug!(
+                                    "Re-verification rejected cached solution for {} ({}): {}",
+                                    check.key, func.name, reason
+                                );
+                                first_rejection.get_or_insert(reason);
+                                continue;
+                            }
+                            accepted_confidence = verification.confidence;
+                            cached.solution = Some(candidate);
+                            break;
+                        }
+                        cached.alternatives = candidates.collect();
+
+                        if !reverified {
+                            (cached.solution, cached.alternatives) = cached_solutions;
+                        } else {
+                            // Replace the stale verifier confidence with the new verdict
+                            let analysis: Vec<&str> = cached.analysis
+                                .lines()
+                                .filter(|line| !line.starts_with("[Verifier confidence: "))
+                                .collect();
+                            let analysis = match (&cached.solution, accepted_confidence, &first_rejection) {
+                                (None, _, Some(reason)) => format!("{}\n\n[Verifier rejected: {}]", analysis.join("\n"), reason),
+                                (Some(_), Some(conf), _) => format!("{}\n[Verifier confidence: {:.2}]", analysis.join("\n"), conf),
+                                _ => analysis.join("\n"),
+                            };
+                            if analysis != cached.analysis || first_rejection.is_some() {
+                                cached.analysis = analysis;
+                                let _ = cache.put(&func, &check.key, true, &cached.analysis, cached.solution.as_deref(), cached.stop_reason);
+                                let _ = cache.put_alternatives(&func, &check.key, &cached.alternatives);
+                                match first_rejection {
+                                    // The test was written for the rejected solution
+                                    Some(_) => cached.test = None,
+                                    // `put` resets the extras, so the test of the kept solution goes back in
+                                    None => if let Some(test) = &cached.test {
+                                        let _ = cache.put_test(&func, &check.key, test);
+                                    },
+                                }
+                            }
+                            if let (None, Some(reason)) = (&cached.solution, first_rejection) {
+                                let rejection = Rejection::new(RejectionStage::Verifier, reason);
+                                let _ = cache.put_rejection(&func, &check.key, &rejection);
+                                cached.rejection = Some(rejection);
+                            }
+                        }
+                    }
                     check_results.push(CheckResult {
-                        check_key: check.key.to_string(),
-                        check_name: check.name.to_string(),
-                        has_issue: cached.has_issue,
-                        analysis: cached.analysis,
                         solution: cached.solution,
+                        stats: total_stats.since(&stats_before),
+                        rejection: cached.rejection,
+                        stop_reason: cached.stop_reason,
+                        test: cached.test,
+                        alternatives: cached.alternatives,
+                        ..CheckResult::new(check, cached.has_issue, cached.analysis)
                     });
-                    progress!("\r\x1b[K{} {}% [{}/{}] | Issues: {} | 💾 [{}] {}",
-                           progress_bar, progress_pct, current_func_num, total_functions_count,
+                    progress!("\r\x1b[K{} {}% [{}/{}]{} | Issues: {} | 💾 [{}] {}",
+                           progress_bar, progress_pct, current_func_num, total_functions_count, eta_display,
                            functions_with_issues, check.key, func_display);
                     continue;
                 }
 
                 // Cache miss - run detection
+                let stats_before = total_stats.clone();
                 let rule_based_analysis = rule_based_detection(check, &func);
-                progress!("\r\x1b[K{} {}% [{}/{}] | Issues: {} | {} [{}] {}",
-                       progress_bar, progress_pct, current_func_num, total_functions_count,
+                let is_rule_based = rule_based_analysis.is_some();
+                progress!("\r\x1b[K{} {}% [{}/{}]{} | Issues: {} | {} [{}] {}",
+                       progress_bar, progress_pct, current_func_num, total_functions_count, eta_display,
                        functions_with_issues,
                        if rule_based_analysis.is_some() { "⚙️" } else { "🔍" },
                        check.key, func_display);
 
+                let mut detection_vote = None;
                 let detection_result = if let Some(analysis) = rule_based_analysis {
-                    Ok(Ok((analysis, false, TokenStats::default())))
+                    Ok(Ok((analysis, StopReason::StopSequence, TokenStats::default())))
                 } else {
                     let detection_prompt = check.format_detection_prompt(&func);
+                    let detection_temperature = check.detection_temperature.unwrap_or(temperature);
                     std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
-                        generate_fn(&detection_prompt, max_tokens, verbose)
+                        if stabilize > 1 {
+                            stabilized_detection(check, &detection_prompt, stabilize, max_tokens, detection_temperature, verbose, generate_fn)
+                                .map(|(analysis, stop_reason, stats, vote)| {
+                                    detection_vote = Some(vote);
+                                    (analysis, stop_reason, stats)
+                                })
+                        } else {
+                            generate_fn(&detection_prompt, max_tokens, detection_temperature, verbose)
+                        }
                     }))
                 };
 
                 let detection_result = match detection_result {
                     Ok(res) => res,
                     Err(_) => {
-                        progress!("\r\x1b[K{} {}% [{}/{}] | Issues: {} | 💥 [{}] Error",
-                               progress_bar, progress_pct, current_func_num, total_functions_count,
+                        skips.push(Skip::check(&func, &check.key, SkipStage::Error, "generation panicked"));
+                        progress!("\r\x1b[K{} {}% [{}/{}]{} | Issues: {} | 💥 [{}] Error",
+                               progress_bar, progress_pct, current_func_num, total_functions_count, eta_display,
                                functions_with_issues, check.key);
                         continue;
                     }
                 };
 
                 match detection_result {
-                    Ok((analysis, _truncated, stats)) => {
+                    Ok((analysis, stop_reason, stats)) => {
                         total_stats.add(&stats);
-                        let detection = check.parse_detection(&analysis);
+                        // Rule-based verdicts weren't generated, so there's no stop reason to record
+                        let detection_stop = (!is_rule_based).then_some(stop_reason);
+                        // Rule-based analyses are always written in the verdict format
+                        let mut detection = if is_rule_based {
+                            check.parse_verdict_detection(&analysis)
+                        } else {
+                            check.parse_detection(&analysis)
+                        };
+                        if let Some(vote) = &detection_vote {
+                            detection.has_issue = vote.has_issue();
+                            detection.confidence = vote.confidence;
+                        }
                         let has_issue = detection.has_issue;
 
-                        let enhanced_analysis = if let Some(conf) = detection.confidence {
+                        let mut enhanced_analysis = if let Some(conf) = detection.confidence {
                             format!("{}\n[Confidence: {:.2}]", analysis, conf)
                         } else {
                             analysis.clone()
                         };
+                        if let Some(vote) = &detection_vote {
+                            enhanced_analysis = format!("{}\n{}", enhanced_analysis, vote.note());
+                        }
+                        let hotspot_lines = validated_detection_lines(&detection.lines, &func);
+                        if !hotspot_lines.is_empty() {
+                            let lines: Vec<String> = hotspot_lines.iter().map(|line| line.to_string()).collect();
+                            enhanced_analysis = format!("{}\n[Lines: {}]", enhanced_analysis, lines.join(", "));
+                        }
 
-                        if has_issue {
-                            progress!("\r\x1b[K{} {}% [{}/{}] | Issues: {} | 💡 [{}] Solution...",
-                                   progress_bar, progress_pct, current_func_num, total_functions_count,
-                                   functions_with_issues, check.key);
-
+                        if let (true, Some(note)) = (has_issue, skip_note) {
+                            let analysis = format!("{}\n\n{}", enhanced_analysis, note);
+                            let _ = cache.put(&func, &check.key, true, &analysis, None, detection_stop);
+                            check_results.push(CheckResult {
+                                stats: total_stats.since(&stats_before),
+                                stop_reason: detection_stop,
+                                ..CheckResult::new(check, true, analysis)
+                            });
+                        } else if has_issue {
                             let solution_prompt = check.format_solution_prompt(&func);
-                            let solution_result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
-                                generate_fn(&solution_prompt, max_tokens, verbose)
-                            }))
-                            .ok()
-                            .and_then(|r| r.ok());
+                            let candidates = if check.solution_format == SolutionFormat::Prose {
+                                1
+                            } else {
+                                check.solution_candidates.max(1)
+                            };
+                            // Identical greedy draws would just repeat the first candidate
+                            let solution_temperature = check.solution_temperature.unwrap_or(temperature);
+                            let solution_temperature = if candidates > 1 {
+                                solution_temperature.max(SOLUTION_CANDIDATE_TEMPERATURE)
+                            } else {
+                                solution_temperature
+                            };
+                            let mut accepted: Vec<(String, Option<f32>)> = Vec::new();
+                            let mut first_rejection: Option<(Rejection, String)> = None;
+                            let mut prose_solution = None;
+
+                            for candidate in 1..=candidates {
+                                let step = if candidates > 1 {
+                                    format!("Solution {}/{}...", candidate, candidates)
+                                } else {
+                                    "Solution...".to_string()
+                                };
+                                progress!("\r\x1b[K{} {}% [{}/{}]{} | Issues: {} | 💡 [{}] {}",
+                                       progress_bar, progress_pct, current_func_num, total_functions_count, eta_display,
+                                       functions_with_issues, check.key, step);
+
+                                let mut solution_result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                                    generate_fn(&solution_prompt, max_tokens, solution_temperature, verbose)
+                                }))
+                                .ok()
+                                .and_then(|r| r.ok());
 
-                            let solution_text = solution_result.as_ref().map(|(text, _truncated, _stats)| text.clone());
+                                if retry_truncated {
+                                    if let Some((_text, _stop, stats)) = solution_result.as_ref().filter(|(_, stop, _)| stop.is_truncated()) {
+                                        total_stats.add(stats);
+                                        progress!("\r\x1b[K{} {}% [{}/{}]{} | Issues: {} | 💡 [{}] Retrying truncated solution...",
+                                               progress_bar, progress_pct, current_func_num, total_functions_count, eta_display,
+                                               functions_with_issues, check.key);
+                                        solution_result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                                            generate_fn(&solution_prompt, max_tokens.saturating_mul(2), solution_temperature, verbose)
+                                        }))
+                                        .ok()
+                                        .and_then(|r| r.ok());
+                                    }
+                                }
 
-                            if let Some((_text, _truncated, stats)) = solution_result {
-                                total_stats.add(&stats);
-                            }
+                                let solution_truncated = matches!(solution_result, Some((_, stop, _)) if stop.is_truncated());
+                                let solution_text = solution_result.as_ref().map(|(text, _stop, _stats)| text.clone());
 
-                            let optimized_and_diff = solution_text.as_ref()
-                                .and_then(|sol| {
-                                    let optimized = extract_optimized_function(sol)?;
-                                    if let Err(reason) = validate_optimization(&func.source_no_docstring, &optimized) {
-                                        return Some(Err(reason));
-                                    }
-                                    let diff = generate_diff(&func.source_no_docstring, &optimized);
-                                    Some(Ok((optimized, diff)))
-                                });
+                                if let Some((_text, _stop, stats)) = solution_result {
+                                    total_stats.add(&stats);
+                                }
 
-                            let (optimized_code, diff) = match optimized_and_diff {
-                                Some(Ok(pair)) => pair,
-                                Some(Err(reason)) => {
-                                    let failure_note = format!(
-                                        "{}\n\n[No safe change suggested: {}]",
-                                        enhanced_analysis, reason
-                                    );
-                                    if verbose {
-                                        eprintln!(
-                                            "Verifier/validation: rejected solution for {} ({}): {}",
-                                            check.key, func.name, reason
-                                        );
-                                    }
-                                    let _ = cache.put(&func, &check.key, true, &failure_note, None);
-                                    check_results.push(CheckResult {
-                                        check_key: check.key.to_string(),
-                                        check_name: check.name.to_string(),
-                                        has_issue: true,
-                                        analysis: failure_note,
-                                        solution: None,
-                                    });
-                                    continue;
+                                // Prose needs no extraction, validation or verification
+                                if check.solution_format == SolutionFormat::Prose {
+                                    prose_solution = solution_text.as_deref()
+                                        .map(str::trim)
+                                        .filter(|text| !text.is_empty())
+                                        .map(str::to_string);
+                                    break;
                                 }
-                                None => {
-                                    let failure_note = format!(
-                                        "{}\n\n[No safe change suggested: Could not extract optimized function]",
-                                        enhanced_analysis
-                                    );
-                                    if verbose {
-                                        eprintln!(
-                                            "Verifier/validation: rejected solution for {} ({}): could not extract optimized function",
-                                            check.key, func.name
+
+                                // A truncated solution is at best half a function; don't let it reach validation
+                                let optimized_and_diff = if solution_truncated {
+                                    Some(Err(Rejection::new(
+                                        RejectionStage::Extract,
+                                        "solution truncated — increase --max-tokens",
+                                    )))
+                                } else {
+                                    solution_text.as_ref()
+                                    .and_then(|sol| {
+                                        let optimized = extract_optimized_function(sol)?;
+                                        if let Err(reason) = validate_optimization(&func.source_no_docstring, &optimized) {
+                                            return Some(Err(Rejection::new(RejectionStage::ValidateOptimization, reason)));
+                                        }
+                                        let diff = generate_diff(&func.source_no_docstring, &optimized);
+                                        Some(Ok((optimized, diff)))
+                                    })
+                                };
+
+                                let rejected = match optimized_and_diff {
+                                    Some(Ok(pair)) => Ok(pair),
+                                    Some(Err(rejection)) => Err(rejection),
+                                    None => Err(Rejection::new(RejectionStage::Extract, EXTRACT_FAILURE_REASON)),
+                                };
+                                let (optimized_code, diff) = match rejected {
+                                    Ok(pair) => pair,
+                                    Err(rejection) => {
+                                        tracing::debug!(
+                                            "Verifier/validation: rejected solution for {} ({}): {}",
+                                            check.key, func.name, rejection.reason
                                         );
+                                        let note = format!("[No safe change suggested: {}]", rejection.reason);
+                                        first_rejection.get_or_insert((rejection, note));
+                                        continue;
                                     }
-                                    let _ = cache.put(&func, &check.key, true, &failure_note, None);
-                                    check_results.push(CheckResult {
-                                        check_key: check.key.to_string(),
-                                        check_name: check.name.to_string(),
-                                        has_issue: true,
-                                        analysis: failure_note,
-                                        solution: None,
-                                    });
+                                };
+                                let solution = match check.solution_format {
+                                    SolutionFormat::Full => format!("```python\n{}\n```", optimized_code.trim_end()),
+                                    SolutionFormat::Diff | SolutionFormat::Prose => format!("```diff\n{}\n```", diff),
+                                };
+                                // A repeat of an accepted candidate isn't worth another verifier call
+                                if accepted.iter().any(|(kept, _)| *kept == solution) {
                                     continue;
                                 }
-                            };
-                            let solution = Some(format!("```diff\n{}\n```", diff));
 
-                            if !check.verifier_prompt.is_empty() {
-                                progress!("\r\x1b[K{} {}% [{}/{}] | Issues: {} | 🔍 [{}] Verifying solution...",
-                                       progress_bar, progress_pct, current_func_num, total_functions_count,
-                                       functions_with_issues, check.key);
+                                let mut verifier_confidence = None;
+                                if !check.verifier_prompt.is_empty() {
+                                    progress!("\r\x1b[K{} {}% [{}/{}]{} | Issues: {} | 🔍 [{}] Verifying solution...",
+                                           progress_bar, progress_pct, current_func_num, total_functions_count, eta_display,
+                                           functions_with_issues, check.key);
 
-                                let verifier_input = format!("```python\n{}\n```", optimized_code);
-                                let verifier_prompt = check.format_verifier_prompt(&func, &verifier_input);
-                                let verifier_result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
-                                    generate_fn(&verifier_prompt, max_tokens, verbose)
-                                }))
-                                .ok()
-                                .and_then(|r| r.ok());
+                                    let verifier_result = verify_solution(check, &func, &optimized_code, max_tokens, temperature, verbose, generate_fn);
 
-                                if let Some((verifier_output, _truncated, stats)) = verifier_result {
-                                    total_stats.add(&stats);
-                                    let verification = parse_verification_result(&verifier_output);
+                                    if let Some((verification, stats)) = verifier_result {
+                                        total_stats.add(&stats);
 
-                                    if !verification.is_valid {
-                                        let rejection_note = format!("{}\n\n[Verifier rejected: {}]",
-                                                                    enhanced_analysis, verification.reason);
-                                        if verbose {
-                                            eprintln!(
+                                        if let Some(reason) = verifier_rejection(&verification, verify_min_confidence) {
+                                            tracing::debug!(
                                                 "Verifier rejected solution for {} ({}): {}",
-                                                check.key, func.name, verification.reason
+                                                check.key, func.name, reason
                                             );
+                                            let note = format!("[Verifier rejected: {}]", reason);
+                                            first_rejection.get_or_insert((Rejection::new(RejectionStage::Verifier, reason), note));
+                                            continue;
                                         }
-                                        let _ = cache.put(&func, &check.key, true, &rejection_note, None);
-                                        check_results.push(CheckResult {
-                                            check_key: check.key.to_string(),
-                                            check_name: check.name.to_string(),
-                                            has_issue: true,
-                                            analysis: rejection_note,
-                                            solution: None,
-                                        });
-                                                continue;
+                                        verifier_confidence = verification.confidence;
                                     }
                                 }
+                                accepted.push((solution, verifier_confidence));
+                            }
+
+                            if check.solution_format == SolutionFormat::Prose {
+                                let solution = prose_solution;
+                                let _ = cache.put(&func, &check.key, true, &enhanced_analysis, solution.as_deref(), detection_stop);
+                                check_results.push(CheckResult {
+                                    solution,
+                                    stats: total_stats.since(&stats_before),
+                                    stop_reason: detection_stop,
+                                    ..CheckResult::new(check, true, enhanced_analysis)
+                                });
+                                continue;
+                            }
+
+                            let Some((solution, verifier_confidence, alternatives)) = rank_solution_candidates(accepted) else {
+                                let (rejection, note) = first_rejection.unwrap_or_else(|| {
+                                    let rejection = Rejection::new(RejectionStage::Extract, EXTRACT_FAILURE_REASON);
+                                    let note = format!("[No safe change suggested: {}]", rejection.reason);
+                                    (rejection, note)
+                                });
+                                let failure_note = format!("{}\n\n{}", enhanced_analysis, note);
+                                let _ = cache.put(&func, &check.key, true, &failure_note, None, detection_stop);
+                                let _ = cache.put_rejection(&func, &check.key, &rejection);
+                                check_results.push(CheckResult {
+                                    stats: total_stats.since(&stats_before),
+                                    rejection: Some(rejection),
+                                    stop_reason: detection_stop,
+                                    ..CheckResult::new(check, true, failure_note)
+                                });
+                                continue;
+                            };
+                            if let Some(conf) = verifier_confidence {
+                                enhanced_analysis = format!("{}\n[Verifier confidence: {:.2}]", enhanced_analysis, conf);
                             }
+                            let solution = Some(solution);
 
-                            let _ = cache.put(&func, &check.key, true, &enhanced_analysis, solution.as_deref());
+                            let _ = cache.put(&func, &check.key, true, &enhanced_analysis, solution.as_deref(), detection_stop);
+                            let _ = cache.put_alternatives(&func, &check.key, &alternatives);
                             check_results.push(CheckResult {
-                                check_key: check.key.to_string(),
-                                check_name: check.name.to_string(),
-                                has_issue: true,
-                                analysis: enhanced_analysis,
                                 solution,
+                                stats: total_stats.since(&stats_before),
+                                stop_reason: detection_stop,
+                                alternatives,
+                                ..CheckResult::new(check, true, enhanced_analysis)
                             });
                         } else {
-                            let _ = cache.put(&func, &check.key, false, &enhanced_analysis, None);
+                            let _ = cache.put(&func, &check.key, false, &enhanced_analysis, None, detection_stop);
                             check_results.push(CheckResult {
-                                check_key: check.key.to_string(),
-                                check_name: check.name.to_string(),
-                                has_issue: false,
-                                analysis: enhanced_analysis,
-                                solution: None,
+                                stats: total_stats.since(&stats_before),
+                                stop_reason: detection_stop,
+                                ..CheckResult::new(check, false, enhanced_analysis)
                             });
                         }
                     }
                     Err(e) => {
                         let error_msg = e.to_string();
-                        progress!("\r\x1b[K{} {}% [{}/{}] | Issues: {} | ⚠️  [{}] {}",
-                               progress_bar, progress_pct, current_func_num, total_functions_count,
+                        progress!("\r\x1b[K{} {}% [{}/{}]{} | Issues: {} | ⚠️  [{}] {}",
+                               progress_bar, progress_pct, current_func_num, total_functions_count, eta_display,
                                functions_with_issues, check.key,
                                if error_msg.contains("too large") { "Too large" } else { "Error" });
-                        if quiet {
-                            eprintln!("\n   Debug: Error in {}: {}", func.name, error_msg);
-                        } else {
-                            println!("\n   Debug: Error in {}: {}", func.name, error_msg);
-                        }
+                        tracing::warn!("Error in {}: {}", func.name, error_msg);
+                        skips.push(Skip::check(&func, &check.key, SkipStage::Error, error_msg));
                     }
                 }
             }
 
-            let check_results = dedupe_check_results(check_results, dedupe_rules);
+            eta.record(total_stats.generation_time.saturating_sub(generation_time_before));
+            if total_stats.generation_time > generation_time_before {
+                generated_functions += 1;
+            }
+            if let Some(message) = min_throughput.and_then(|min| slow_generation_error(&total_stats, generated_functions, min)) {
+                progress!("\r\x1b[K");
+                return Err(anyhow::anyhow!(message));
+            }
+
+            if applicable_checks == 0 {
+                let qualified_name = func.qualified_name();
+                unchecked_functions.push(format!("{} ({}:{})", qualified_name, file_path.display(), func.line_number));
+            }
+
+            if blend_confidence {
+                for result in check_results.iter_mut().filter(|r| r.has_issue) {
+                    if let Some(blended) = blended_confidence_analysis(&result.analysis) {
+                        result.analysis = blended;
+                    }
+                }
+            }
+            let mut check_results = dedupe_check_results(check_results, dedupe_rules);
+            if let Some(threshold) = collapse_similar {
+                check_results = collapse_similar_findings(check_results, threshold);
+            }
+            // Tests are generated last so findings dropped by dedupe don't cost a generation;
+            // cached results bring theirs along
+            if with_tests {
+                for result in check_results.iter_mut().filter(|r| r.rejection.is_none() && r.test.is_none()) {
+                    let Some(solution) = result.solution.clone() else { continue };
+                    let Some(check) = checks.iter().find(|c| c.key == result.check_key) else { continue };
+                    if check.test_prompt.trim().is_empty() {
+                        continue;
+                    }
+                    progress!("\r\x1b[K{} {}% [{}/{}]{} | Issues: {} | 🧪 [{}] Writing test...",
+                           progress_bar, progress_pct, current_func_num, total_functions_count, eta_display,
+                           functions_with_issues, check.key);
+                    match generate_test(check, &func, &solution, max_tokens, temperature, verbose, generate_fn) {
+                        Ok((test, stats)) => {
+                            total_stats.add(&stats);
+                            result.stats.add(&stats);
+                            let _ = cache.put_test(&func, &check.key, &test);
+                            result.test = Some(test);
+                        }
+                        Err(e) => tracing::warn!("Test generation failed for {} ({}): {}", func.name, check.key, e),
+                    }
+                }
+            }
+            if let Some(cutoff) = directives.confidence_threshold.or(warn_below_confidence) {
+                for result in check_results.iter_mut().filter(|r| r.has_issue) {
+                    result.advisory = is_below_confidence(&result.analysis, cutoff);
+                }
+            }
             let actually_has_issues = check_results.iter().any(|r| r.has_issue);
+            let has_blocking_issues = check_results.iter().any(|r| r.has_issue && !r.advisory);
             if actually_has_issues {
                 functions_with_issues += 1;
             }
 
             if !check_results.is_empty() {
-                file_results.push(AnalysisResult {
+                let result = AnalysisResult {
                     function: func,
                     check_results,
-                });
-            }
+                };
+                if let (Some(options), true) = (stream_report, actually_has_issues) {
+                    // Clear the progress line; it is redrawn below the block on the next update
+                    progress!("\r\x1b[K");
+                    if functions_with_issues == 1 {
+                        print_detailed_report_header();
+                    }
+                    print_function_report(functions_with_issues, &result, checks, options);
+                    println!("───────────────────────────────────────────────────────────────");
+                    println!();
+                }
+                if let Some(live_report) = live_report.as_deref_mut() {
+                    live_report.append(&result)?;
+                    if actually_has_issues {
+                        live_report.write(checks, cache)?;
+                    }
+                }
+                file_results.push(result);
+            }
+
+            // Results are already in the cache, so stopping here loses nothing
+            if fail_fast && has_blocking_issues {
+                progress!("\r\x1b[K{} {}% [{}/{}]{} | Issues: {} | 🛑 Stopped at first issue (--fail-fast)",
+                       progress_bar, progress_pct, current_func_num, total_functions_count, eta_display,
+                       functions_with_issues);
+                stopped_early = true;
+                break;
+            }
         }
 
         if !file_results.is_empty() {
@@ -1552,6 +5010,10 @@ where
                 results: file_results,
             });
         }
+
+        if stopped_early || capped {
+            break;
+        }
     }
 
     Ok(AnalysisOutput {
@@ -1559,10 +5021,57 @@ where
         total_functions,
         functions_with_issues,
         stats: total_stats,
+        stopped_early,
+        capped,
+        unchecked_functions,
+        prefiltered,
+        unparseable_files: Vec::new(),
+        skips,
     })
 }
 
-fn main() -> Result<()> {
+/// Route tracing events to stderr or --log-file, with span timings on close
+fn init_logging(cli: &Cli) -> Result<()> {
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    let level = cli.log_level.unwrap_or(if cli.verbose { tracing::Level::DEBUG } else { tracing::Level::WARN });
+    // Our own events at the requested level; dependencies (e.g. the parser's lexer) only at warn
+    let filter = tracing_subscriber::filter::Targets::new()
+        .with_target(module_path!(), level)
+        .with_default(tracing::Level::WARN);
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::TRACE)
+        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE);
+
+    if let Some(ref path) = cli.log_file {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open log file: {}", path.display()))?;
+        builder.with_ansi(false).with_writer(std::sync::Mutex::new(file)).finish().with(filter).init();
+    } else {
+        builder.with_writer(std::io::stderr).finish().with(filter).init();
+    }
+    Ok(())
+}
+
+fn main() -> std::process::ExitCode {
+    match run() {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            if e.is::<NoPythonFiles>() {
+                std::process::ExitCode::from(EXIT_NO_PYTHON_FILES)
+            } else {
+                std::process::ExitCode::FAILURE
+            }
+        }
+    }
+}
+
+fn run() -> Result<()> {
     // Set up panic hook to provide better error messages
     std::panic::set_hook(Box::new(|panic_info| {
         eprintln!("\n❌ Fatal error occurred!");
@@ -1576,7 +5085,8 @@ fn main() -> Result<()> {
         eprintln!("   Or set RUST_BACKTRACE=1 for full backtrace\n");
     }));
 
-    let mut cli = Cli::parse();
+    let mut cli = Cli::parse_tracking_sources(std::env::args_os());
+    init_logging(&cli)?;
 
     // Handle --list-checks flag
     if cli.list_checks {
@@ -1584,6 +5094,29 @@ fn main() -> Result<()> {
         return Ok(());
     }
 
+    // Handle --config-check flag
+    if cli.config_check {
+        return run_config_check(&cli);
+    }
+
+    // Handle --compare flag
+    if let Some(reports) = &cli.compare {
+        return run_compare(&reports[0], &reports[1], cli.format == "json");
+    }
+
+    // Handle --cache-vacuum flag
+    if cli.cache_vacuum {
+        return run_cache_vacuum(&cli);
+    }
+
+    // Handle `dismiss` and --feedback-report
+    if let Some(Command::Dismiss { finding_ids }) = &cli.command {
+        return run_dismiss(&cli, finding_ids);
+    }
+    if cli.feedback_report {
+        return run_feedback_report(&cli);
+    }
+
     // Handle --print-default-config flag
     if cli.print_default_config {
         print!("{}", get_default_config_toml());
@@ -1591,26 +5124,66 @@ fn main() -> Result<()> {
     }
 
     // Load config and apply default settings (CLI args take precedence)
-    let config = load_checks_config(cli.config.clone())?;
-    apply_config_settings(&mut cli, &config);
+    let config = load_checks_config(cli.config.clone(), &cli.config_overrides)?;
+    apply_config_settings(&mut cli, &config)?;
 
-    // Validate PATH (always required for analysis)
-    let python_path = cli.python_path.as_ref()
-        .ok_or_else(|| anyhow::anyhow!("PATH argument is required (unless using --list-checks)"))?;
+    // Handle --show-config flag
+    if cli.show_config {
+        let (config_source, _, _) = read_checks_config_source(cli.config.clone())?;
+        print!("{}", effective_config_toml(&cli, &config, &config_source, &get_checks_to_run(&cli)?));
+        return Ok(());
+    }
+
+    // Handle --print-prompt-for flag
+    if let Some(target) = &cli.print_prompt_for {
+        return print_prompts_for(&cli, std::path::Path::new(&target[0]), &target[1]);
+    }
+
+    // Validate PATH (always required for analysis, unless a --code snippet stands in for it)
+    let inline_path = cli.code.is_some().then(|| PathBuf::from(INLINE_PATH));
+    let python_path = cli.python_path.as_ref().or(inline_path.as_ref())
+        .ok_or_else(|| anyhow::anyhow!("PATH argument is required (unless using --list-checks or --code)"))?;
+
+    // Archives are analyzed from a temporary extraction that is removed when the run ends;
+    // reported paths are then relative to the archive root
+    let extracted_archive = if is_source_archive(python_path) {
+        Some(extract_source_archive(python_path)?)
+    } else {
+        None
+    };
+    let extracted_root = extracted_archive.as_ref().map(|dir| dir.path().to_path_buf());
+    let python_path = extracted_root.as_ref().unwrap_or(python_path);
 
     // Get checks to run
     let checks = get_checks_to_run(&cli)?;
     if checks.is_empty() {
         return Err(anyhow::anyhow!("No checks selected. Use --checks to specify checks or --list-checks to see available checks."));
     }
+    let solutions_for = cli.solutions_for.as_deref().map(parse_check_keys);
+    if let Some(keys) = &solutions_for {
+        validate_check_keys("--solutions-for", keys, &checks, cli.strict)?;
+    }
+
+    if cli.list_functions {
+        return list_functions(&cli, python_path, &checks);
+    }
+
+    // Checked before loading a model: a typo'd path would otherwise produce a clean "0 functions" report
+    let mut python_files = collect_sources(&cli, python_path)?;
+    if python_files.is_empty() {
+        if cli.strict {
+            return Err(NoPythonFiles(python_path.clone()).into());
+        }
+        eprintln!("⚠️  Warning: {}", NoPythonFiles(python_path.clone()));
+        return Ok(());
+    }
 
-    // Build API config if requested
+    // Build API config if requested (the only way LoopSleuth reaches the network)
     let api_config: Option<ApiConfig> = if let Some(ref url) = cli.api_url {
+        ensure_network_allowed(cli.no_network, url)?;
         let token = std::env::var("HF_TOKEN").ok();
         let mut api = ApiConfig {
-            client: reqwest::blocking::Client::builder()
-                .timeout(Duration::from_secs(300))
-                .build()?,
+            client: api_client(cli.no_network)?,
             url: url.trim_end_matches('/').to_string(),
             token,
             model_id: String::new(),
@@ -1621,7 +5194,8 @@ fn main() -> Result<()> {
         None
     };
 
-    let json_mode = cli.format == "json";
+    // Progress and setup messages go to stderr when stdout carries JSON or bare diffs
+    let json_mode = matches!(cli.format.as_str(), "json" | "codeclimate") || cli.diff_only;
 
     if json_mode {
         eprintln!("🔧 Initializing LoopSleuth...");
@@ -1630,7 +5204,17 @@ fn main() -> Result<()> {
     }
 
     // Initialize cache
-    let cache = AnalysisCache::new(cli.cache_dir.clone(), !cli.no_cache)?;
+    let cache = AnalysisCache::new(cli.cache_dir.clone(), cli.cache_file.clone(), !cli.no_cache, cli.concurrency_safe_cache)?
+        .with_prompt_hashes(&checks);
+    // Results produced with CLI prompt overrides must not mix with the stock prompts' ones
+    let cache = match prompt_overrides(&cli)? {
+        (None, None) => cache,
+        (system, suffix) => cache.with_key_salt(format!(
+            "system:{}\0suffix:{}",
+            system.unwrap_or_default(),
+            suffix.unwrap_or_default()
+        )),
+    };
 
     if cli.clear_cache {
         if json_mode { eprintln!("🗑️  Clearing cache..."); }
@@ -1638,8 +5222,49 @@ fn main() -> Result<()> {
         cache.clear()?;
     }
 
-    // Collect Python files
-    let python_files = collect_python_files(python_path)?;
+    // Record what produced this run so reports can be reproduced or explained
+    let manifest = RunManifest::new(&cli, &checks, api_config.as_ref())?;
+    let manifest_hash = manifest.short_hash()?;
+    let manifest_path = if cli.no_cache {
+        None
+    } else {
+        // With only --cache-file, the manifest sits next to the database
+        let cache_dir = match (&cli.cache_dir, &cli.cache_file) {
+            (Some(dir), _) => dir.clone(),
+            (None, Some(file)) => file.parent().map(|parent| parent.to_path_buf()).unwrap_or_default(),
+            (None, None) => PathBuf::from(DEFAULT_CACHE_DIR),
+        };
+        Some(manifest.write(&cache_dir)?)
+    };
+
+    let mut file_skips: Vec<Skip> = Vec::new();
+    if cli.staged {
+        // Untracked files have no staged version to analyze
+        let before = python_files.len();
+        python_files.retain(|path| {
+            let indexed = is_in_git_index(path);
+            if !indexed {
+                file_skips.push(Skip::file(path, SkipStage::Staged, "not in the git index"));
+            }
+            indexed
+        });
+        if python_files.len() < before {
+            eprintln!("📌 --staged: skipping {} file(s) not in the git index", before - python_files.len());
+        }
+    }
+    if cli.skip_tests {
+        let before = python_files.len();
+        python_files.retain(|path| {
+            let is_test = is_test_file(path, python_path, &cli.test_pattern);
+            if is_test {
+                file_skips.push(Skip::file(path, SkipStage::Tests, "test file (--skip-tests)"));
+            }
+            !is_test
+        });
+        if python_files.len() < before {
+            eprintln!("🧪 --skip-tests: skipping {} test file(s)", before - python_files.len());
+        }
+    }
     let file_count = python_files.len();
 
     if json_mode {
@@ -1656,17 +5281,113 @@ fn main() -> Result<()> {
         );
     }
 
+    if cli.tui && !std::io::IsTerminal::is_terminal(&std::io::stdout()) {
+        return Err(anyhow::anyhow!("--tui needs an interactive terminal (stdout is not a tty)"));
+    }
+    if cli.lines.is_some() && !python_path.is_file() {
+        return Err(anyhow::anyhow!("--lines requires PATH to be a single Python file"));
+    }
+    if cli.only_changed_functions && cli.no_cache {
+        return Err(anyhow::anyhow!("--only-changed-functions compares against the cache and can't be used with --no-cache"));
+    }
+
     // First pass: count total functions
+    let include_classes = checks.iter().any(|c| c.scope == CheckScope::Class);
     let mut total_functions_count = 0;
+    let mut population: Vec<(PathBuf, usize, String)> = Vec::new();
+    let mut unparseable_files: Vec<PathBuf> = Vec::new();
+    let mut unchanged_count = 0;
     for path in &python_files {
-        if let Ok(mut functions) = extract_functions(path) {
-            if let Some(ref filter) = cli.filter_function {
-                let filter_lower = filter.to_lowercase();
-                functions.retain(|func| func.name.to_lowercase().contains(&filter_lower));
+        let mut functions = match extract_functions(path, &python_path, include_classes, cli.nested_functions, SourceOrigin::from_cli(&cli)) {
+            Ok(functions) => functions,
+            Err(e) if cli.strict_parse => {
+                return Err(e.context(format!("Failed to extract functions from {}", path.display())));
+            }
+            Err(e) => {
+                // One bad file (e.g. Python 2 code) shouldn't abort a directory scan
+                eprintln!("⚠️  Skipping unparseable file {}: {}", path.display(), e);
+                file_skips.push(Skip::file(path, SkipStage::Parse, format!("{:#}", e)));
+                unparseable_files.push(path.clone());
+                continue;
             }
-            total_functions_count += functions.len();
+        };
+        if let Some(ref filter) = cli.filter_function {
+            let filter_lower = filter.to_lowercase();
+            functions.retain(|func| func.name.to_lowercase().contains(&filter_lower));
+        }
+        if let Some((start, end)) = cli.lines {
+            functions.retain(|func| function_overlaps_lines(func, start, end));
+        }
+        if cli.only_changed_functions {
+            let before = functions.len();
+            functions.retain(|func| !cache.contains_function(func, &checks).unwrap_or(false));
+            unchanged_count += before - functions.len();
+        }
+        total_functions_count += functions.len();
+        if cli.sample.is_some() || cli.lines.is_some() || cli.only_changed_functions {
+            population.extend(functions.iter().map(sample_key));
+        }
+    }
+    python_files.retain(|path| !unparseable_files.contains(path));
+
+    let population_size = total_functions_count;
+    let sampled: Option<SampledFunctions> = cli.sample.map(|n| {
+        let seed = cli.seed.unwrap_or(0);
+        let picked = sample_indices(population.len(), n, seed);
+        total_functions_count = picked.len();
+        picked.into_iter().map(|i| population[i].clone()).collect()
+    });
+    if let Some(ref sampled) = sampled {
+        let message = format!(
+            "🎲 Sampling {} of {} function(s) ({:.1}%) with seed {}",
+            sampled.len(),
+            population_size,
+            sample_percentage(sampled.len(), population_size),
+            cli.seed.unwrap_or(0)
+        );
+        if json_mode {
+            eprintln!("{}", message);
+        } else {
+            println!("{}", message);
+        }
+    }
+    let line_selection: Option<SampledFunctions> = cli.lines.map(|(start, end)| {
+        let names: Vec<&str> = population.iter().map(|(_, _, name)| name.as_str()).collect();
+        let message = format!(
+            "📏 Lines {}:{} selected {} function(s){}{}",
+            start,
+            end,
+            names.len(),
+            if names.is_empty() { "" } else { ": " },
+            names.join(", ")
+        );
+        if json_mode {
+            eprintln!("{}", message);
+        } else {
+            println!("{}", message);
+        }
+        population.iter().cloned().collect()
+    });
+    if cli.only_changed_functions {
+        let message = format!(
+            "♻️  Skipping {} unchanged function(s) already in the cache; {} new or changed",
+            unchanged_count,
+            population.len()
+        );
+        if json_mode {
+            eprintln!("{}", message);
+        } else {
+            println!("{}", message);
         }
     }
+    // A sample is drawn from the filtered population, so it already respects --lines and
+    // --only-changed-functions
+    let selection = sampled.clone()
+        .or(line_selection)
+        .or_else(|| cli.only_changed_functions.then(|| population.iter().cloned().collect()));
+    if let Some(max) = cli.max_functions {
+        total_functions_count = total_functions_count.min(max);
+    }
 
     if json_mode {
         eprintln!("📊 Analyzing {} function(s)...", total_functions_count);
@@ -1677,8 +5398,37 @@ fn main() -> Result<()> {
         println!("📊 Analyzing {} function(s)...\n", total_functions_count);
     }
 
+    let report_options = ReportOptions {
+        no_cache: cli.no_cache,
+        manifest_hash: Some(manifest_hash.clone()),
+        relative_to: cli.relative_to.clone().or_else(|| {
+            if python_path.is_dir() {
+                Some(python_path.clone())
+            } else {
+                std::env::current_dir().ok()
+            }
+        }),
+        finding_root: if python_path.is_dir() {
+            Some(python_path.clone())
+        } else {
+            python_path.parent().map(std::path::Path::to_path_buf)
+        },
+        redact: cli.redact,
+        report_template: cli.report_template.as_ref()
+            .map(|path| fs::read_to_string(path)
+                .with_context(|| format!("Failed to read report template: {}", path.display())))
+            .transpose()?,
+        emit_optimized: cli.emit_optimized,
+    };
+    let mut live_report = match &cli.output {
+        Some(path) if cli.live_report => Some(LiveReport::new(&report_output_path(path, cli.compress), &report_options)?),
+        _ => None,
+    };
+
+    let loop_options = LoopOptions::from_cli(&cli, selection.as_ref(), solutions_for.as_deref(), json_mode);
+
     // Run analysis with appropriate backend
-    let output = if let Some(ref api) = api_config {
+    let mut output = if let Some(ref api) = api_config {
         if json_mode {
             eprintln!("   🌐 API endpoint: {} (model: {})", api.url, api.model_id);
         } else {
@@ -1692,11 +5442,17 @@ fn main() -> Result<()> {
             println!("   ✅ Ready!\n");
         }
 
+        let mut generate = |prompt: &Prompt, max_tokens: i32, temperature: f32, verbose: bool| {
+            generate_response_api(api, &prompt.text, max_tokens, temperature, verbose)
+        };
+        if let Some(function) = &cli.explain_clean {
+            return explain_clean(&cli, &python_files, python_path, &checks, function, &mut generate);
+        }
+
         run_analysis_loop(
-            &python_files, &checks, &cache, &config.dedupe,
-            cli.filter_function.as_deref(), cli.skip_large, cli.max_tokens, cli.verbose,
-            cli.format == "json", total_functions_count,
-            &mut |prompt, max_tokens, verbose| generate_response_api(api, prompt, max_tokens, verbose),
+            &python_files, python_path, &checks, &cache, &config.dedupe, &loop_options,
+            cli.stream_report.then_some(&report_options), live_report.as_mut(), total_functions_count,
+            &mut generate,
         )?
     } else {
         let model_path = cli.model.as_ref()
@@ -1711,8 +5467,12 @@ fn main() -> Result<()> {
             None
         };
 
+        let model_load_span = tracing::info_span!("model_load", model = %model_path.display()).entered();
         let backend = LlamaBackend::init()?;
-        let model_params = LlamaModelParams::default();
+        let mut model_params = LlamaModelParams::default();
+        if let Some(gpu_layers) = cli.gpu_layers {
+            model_params = model_params.with_n_gpu_layers(gpu_layers);
+        }
         let model = LlamaModel::load_from_file(&backend, model_path, &model_params)
             .with_context(|| {
                 format!(
@@ -1723,9 +5483,16 @@ fn main() -> Result<()> {
 
         let n_ctx = NonZeroU32::new(cli.context_size)
             .context("Invalid context size")?;
+        let (batch_size, batch_warning) = resolve_batch_size(cli.batch_size, cli.context_size);
+        if let Some(warning) = batch_warning {
+            tracing::warn!("{}", warning);
+            if !json_mode {
+                println!("   ⚠️  {}", warning);
+            }
+        }
         let ctx_params = LlamaContextParams::default()
             .with_n_ctx(Some(n_ctx))
-            .with_n_batch(4096)
+            .with_n_batch(batch_size)
             .with_n_threads(cli.threads as i32);
 
         let mut ctx = model.new_context(&backend, ctx_params)
@@ -1735,60 +5502,215 @@ fn main() -> Result<()> {
                     model_path.display()
                 )
             })?;
+        drop(model_load_span);
+
+        // llama.cpp may hand back a smaller context than asked for; the prompt limits follow ctx.n_ctx()
+        let context_size = ctx.n_ctx();
+        for warning in context_size_warnings(cli.context_size, context_size, model.n_ctx_train()) {
+            tracing::warn!("{}", warning);
+            if !json_mode {
+                println!("   ⚠️  {}", warning);
+            }
+        }
 
         if !json_mode {
-            println!("   ✅ Ready! (context: {} tokens)\n", cli.context_size);
+            println!("   ✅ Ready! (context: {} tokens, threads: {})\n", context_size, cli.threads);
+        }
+
+        // Tokens of each check's static prompt prefix, reused across functions
+        let add_bos = resolve_add_bos(&model, &cli.add_bos);
+        tracing::debug!(?add_bos, "resolved BOS handling");
+        let mut tokenizer = PromptTokenizer::new(add_bos);
+        let seed = cli.seed.unwrap_or(0);
+        // How often each prompt (by digest) has been sampled so far
+        let mut prompt_rounds: std::collections::HashMap<[u8; 32], u32> = std::collections::HashMap::new();
+
+        let mut generate = |prompt: &Prompt, max_tokens: i32, temperature: f32, verbose: bool| {
+            // Re-sampling the same prompt (verifier rounds) must not replay the same draw
+            let digest: [u8; 32] = Sha256::digest(prompt.text.as_bytes()).into();
+            let round = prompt_rounds.entry(digest).or_insert(0);
+            let sampling = Sampling { temperature, seed: draw_seed(seed, &digest, *round) };
+            *round += 1;
+            generate_response(&model, &mut ctx, prompt, &mut tokenizer, max_tokens, sampling, verbose)
+        };
+        if let Some(function) = &cli.explain_clean {
+            return explain_clean(&cli, &python_files, python_path, &checks, function, &mut generate);
         }
 
         run_analysis_loop(
-            &python_files, &checks, &cache, &config.dedupe,
-            cli.filter_function.as_deref(), cli.skip_large, cli.max_tokens, cli.verbose,
-            cli.format == "json", total_functions_count,
-            &mut |prompt, max_tokens, verbose| generate_response(&model, &mut ctx, prompt, max_tokens, verbose),
+            &python_files, python_path, &checks, &cache, &config.dedupe, &loop_options,
+            cli.stream_report.then_some(&report_options), live_report.as_mut(), total_functions_count,
+            &mut generate,
         )?
     };
+    output.unparseable_files = unparseable_files;
+    output.skips.splice(0..0, file_skips);
+    cache.record_findings(&finding_records(&output.file_results, &report_options))?;
+    // Ranked before pruning so clean files still count towards the cost profile
+    let top_token_functions = cli.top_token_functions
+        .map(|n| top_functions_by_tokens(&output.file_results, n));
+    if cli.only_files_with_issues {
+        output.file_results.retain(|file_result| {
+            file_result.results.iter().any(|r| r.check_results.iter().any(|c| c.has_issue))
+        });
+    }
 
     // Clear the progress line and show completion
     if !json_mode {
         print!("\r\x1b[K");
     }
 
-    if cli.format == "json" {
+    if let Some(skips_path) = &cli.skips_report {
+        fs::write(skips_path, serde_json::to_string_pretty(&skips_json(&output.skips, &report_options))?)
+            .with_context(|| format!("Failed to write skips report: {}", skips_path.display()))?;
+        if json_mode {
+            eprintln!("🧾 Skips report saved to: {}", skips_path.display());
+        } else {
+            println!("🧾 Skips report saved to: {}", skips_path.display());
+        }
+    }
+
+    if let Some(summary_path) = &cli.summary_json {
+        fs::write(summary_path, serde_json::to_string_pretty(&summary_json(&output, python_files.len(), &checks))?)
+            .with_context(|| format!("Failed to write summary: {}", summary_path.display()))?;
+        if json_mode {
+            eprintln!("📈 Summary saved to: {}", summary_path.display());
+        } else {
+            println!("📈 Summary saved to: {}", summary_path.display());
+        }
+    }
+
+    let output_path = cli.output.as_deref().map(|path| report_output_path(path, cli.compress));
+    if cli.format == "codeclimate" {
+        let issues = serde_json::to_string_pretty(&codeclimate_issues(&output.file_results, &checks, &report_options))?;
+        match &output_path {
+            Some(output_path) => {
+                write_report_file(output_path, &issues)?;
+                eprintln!("📄 Report saved to: {}", output_path.display());
+            }
+            None => println!("{}", issues),
+        }
+    } else if cli.format == "json" {
         // JSON output mode — structured output for programmatic consumption
         let json_report = serde_json::json!({
             "total_functions": output.total_functions,
             "functions_with_issues": output.functions_with_issues,
+            "functions_without_applicable_checks": output.unchecked_functions.len(),
+            "prefiltered": output.prefiltered,
+            "skips": skips_json(&output.skips, &report_options),
+            "unparseable_files": output.unparseable_files.iter()
+                .map(|path| report_options.display_path(path))
+                .collect::<Vec<_>>(),
             "files": output.file_results.iter().map(|fr| {
+                let path = report_options.display_path(&fr.file_path);
                 serde_json::json!({
-                    "path": fr.file_path,
+                    "path": path,
                     "results": fr.results.iter().map(|r| {
+                        let qualified_name = r.function.qualified_name();
                         serde_json::json!({
                             "function_name": r.function.name,
                             "class_name": r.function.class_name,
+                            "enclosing_function": r.function.enclosing_function,
                             "line_number": r.function.line_number,
                             "issues": r.check_results.iter().filter(|cr| cr.has_issue).map(|cr| {
                                 serde_json::json!({
+                                    "finding_id": report_options.finding_id(&fr.file_path, &qualified_name, &cr.check_key),
                                     "check_key": cr.check_key,
                                     "check_name": cr.check_name,
                                     "confidence": extract_confidence_percentage(&cr.analysis),
+                                    "verifier_confidence": extract_verifier_confidence_percentage(&cr.analysis),
                                     "analysis": cr.analysis,
-                                    "solution": cr.solution,
+                                    "solution": if report_options.redact { None } else { cr.solution.as_ref() },
+                                    "test": if report_options.redact { None } else { cr.test.as_ref() },
+                                    "optimized_code": report_options.optimized_code(&checks, cr),
+                                    "alternatives": if report_options.redact { &[][..] } else { &cr.alternatives[..] },
+                                    "rejection": cr.rejection,
+                                    "stop_reason": cr.stop_reason,
+                                    "advisory": cr.advisory,
+                                    "token_usage": {
+                                        "input_tokens": cr.stats.input_tokens,
+                                        "output_tokens": cr.stats.output_tokens,
+                                        "generation_secs": cr.stats.generation_time.as_secs_f64(),
+                                    },
                                 })
                             }).collect::<Vec<_>>(),
                         })
                     }).collect::<Vec<_>>(),
                 })
             }).collect::<Vec<_>>(),
+            "verifier_rejected": output.file_results.iter().flat_map(|fr| {
+                let path = report_options.display_path(&fr.file_path);
+                let report_options = &report_options;
+                verifier_rejected(&fr.results).into_iter().map(move |(r, cr, reason)| {
+                    let qualified_name = r.function.qualified_name();
+                    serde_json::json!({
+                        "finding_id": report_options.finding_id(&fr.file_path, &qualified_name, &cr.check_key),
+                        "path": path,
+                        "function_name": r.function.name,
+                        "class_name": r.function.class_name,
+                        "enclosing_function": r.function.enclosing_function,
+                        "line_number": r.function.line_number,
+                        "check_key": cr.check_key,
+                        "reason": reason,
+                    })
+                })
+            }).collect::<Vec<_>>(),
             "model": if let Some(ref api) = api_config { &api.model_id } else { "local" },
             "token_usage": {
                 "input_tokens": output.stats.input_tokens,
                 "output_tokens": output.stats.output_tokens,
             },
+            "run_manifest": manifest_hash,
+            "stopped_early": output.stopped_early,
+            "capped": output.capped,
+            "redacted": report_options.redact,
+            "top_token_functions": top_token_functions.as_ref().map(|ranked| ranked.iter().map(|(func, stats)| {
+                serde_json::json!({
+                    "path": report_options.display_path(&func.file_path),
+                    "function_name": func.name,
+                    "class_name": func.class_name,
+                    "enclosing_function": func.enclosing_function,
+                    "line_number": func.line_number,
+                    "input_tokens": stats.input_tokens,
+                    "output_tokens": stats.output_tokens,
+                    "generation_secs": stats.generation_time.as_secs_f64(),
+                })
+            }).collect::<Vec<_>>()),
+            "sample": sampled.as_ref().map(|sampled| serde_json::json!({
+                "functions": sampled.len(),
+                "population": population_size,
+                "seed": cli.seed.unwrap_or(0),
+            })),
         });
-        println!("{}", serde_json::to_string_pretty(&json_report)?);
+        // JSON goes to stdout unless --output asks for it compressed
+        match output_path.as_deref().filter(|path| is_gzip_path(path)) {
+            Some(output_path) => {
+                write_report_file(output_path, &serde_json::to_string_pretty(&json_report)?)?;
+                eprintln!("📄 Report saved to: {}", output_path.display());
+            }
+            None => println!("{}", serde_json::to_string_pretty(&json_report)?),
+        }
+    } else if cli.diff_only {
+        print_diff_only(&output, &report_options);
+        if let Some(output_path) = &output_path {
+            let all_results: Vec<AnalysisResult> = output.file_results
+                .iter()
+                .flat_map(|fr| fr.results.iter())
+                .cloned()
+                .collect();
+            write_report_to_file(output_path, &all_results, output.total_functions, output.functions_with_issues, &checks, &cache, &report_options)?;
+            eprintln!("📄 Report saved to: {}", output_path.display());
+        }
     } else {
         // Text output mode (default)
         println!("✅ Analysis complete!\n");
+        if output.capped {
+            println!("✂️  Scan capped at {} function(s) (--max-functions)\n", output.total_functions);
+        }
+        if let Some(ref sampled) = sampled {
+            println!("🎲 Sampled {} of {} function(s) ({:.1}%); issue rates are estimates\n",
+                sampled.len(), population_size, sample_percentage(sampled.len(), population_size));
+        }
 
         let all_results: Vec<AnalysisResult> = output.file_results
             .iter()
@@ -1796,36 +5718,126 @@ fn main() -> Result<()> {
             .cloned()
             .collect();
 
-        print_summary(&output.file_results, file_count, output.total_functions, output.functions_with_issues, &checks, &cache, cli.no_cache, &output.stats);
+        if cli.format == "terminal-table" {
+            print_terminal_table(&output, &report_options);
+        } else {
+            print_summary(&output, python_files.len(), &checks, &cache, &report_options, cli.verbose);
+
+            match &manifest_path {
+                Some(path) => println!("🧾 Run manifest: {} ({})\n", manifest_hash, path.display()),
+                None => println!("🧾 Run manifest: {}\n", manifest_hash),
+            }
+        }
+
+        if let Some(ref ranked) = top_token_functions {
+            print_top_token_functions(ranked, &report_options);
+        }
 
-        if output.functions_with_issues > 0 && cli.details {
-            print_detailed_report(&all_results);
+        if cli.tui {
+            run_tui(&all_results, &checks, &report_options)?;
+        } else if cli.stream_report {
+            // Every block was already printed as its function finished
+        } else if output.functions_with_issues > 0 && (cli.details || output.stopped_early) {
+            print_detailed_report(&all_results, &checks, &report_options);
         } else if output.functions_with_issues > 0 && !cli.details && cli.output.is_none() {
             println!("💡 Tip: Use --details to see full analysis or --output FILE to save report");
             println!();
         }
 
-        if let Some(output_path) = &cli.output {
-            write_report_to_file(output_path, &all_results, output.total_functions, output.functions_with_issues, &checks, &cache, cli.no_cache)?;
+        if let Some(output_path) = &output_path {
+            write_report_to_file(output_path, &all_results, output.total_functions, output.functions_with_issues, &checks, &cache, &report_options)?;
             println!("📄 Report saved to: {}", output_path.display());
         }
     }
 
+    if let Some(output_dir) = &cli.output_dir {
+        let index_path = write_report_dir(output_dir, &output, &checks, &cache, &report_options)?;
+        if matches!(cli.format.as_str(), "json" | "codeclimate") {
+            eprintln!("📂 Reports written to: {}", index_path.display());
+        } else {
+            println!("📂 Reports written to: {}", index_path.display());
+        }
+    }
+
+    if output.stopped_early {
+        drop(cache);
+        drop(extracted_archive);
+        std::process::exit(1);
+    }
+
     Ok(())
 }
 
+/// Exit code when --strict finds no Python files under PATH, so CI can tell it apart from
+/// other errors (1)
+const EXIT_NO_PYTHON_FILES: u8 = 3;
+
+/// PATH holds no Python files; an error only with --strict
+#[derive(Debug)]
+struct NoPythonFiles(PathBuf);
+
+impl std::fmt::Display for NoPythonFiles {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "no Python files found at {}", self.0.display())
+    }
+}
+
+impl std::error::Error for NoPythonFiles {}
+
+/// `.zip`, `.tar.gz` and `.tgz` inputs are extracted before analysis
+fn is_source_archive(path: &std::path::Path) -> bool {
+    let name = path.file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    path.is_file() && (name.ends_with(".zip") || name.ends_with(".tar.gz") || name.ends_with(".tgz"))
+}
+
+/// Extract a source archive into a temporary directory (deleted when the handle is dropped)
+fn extract_source_archive(path: &std::path::Path) -> Result<tempfile::TempDir> {
+    let dir = tempfile::Builder::new()
+        .prefix("loopsleuth-")
+        .tempdir()
+        .context("Failed to create a temporary directory for the archive")?;
+    let file = fs::File::open(path)
+        .with_context(|| format!("Failed to open archive: {}", path.display()))?;
+
+    let is_zip = path.extension().and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("zip"));
+    if is_zip {
+        zip::ZipArchive::new(file)
+            .and_then(|mut archive| archive.extract(dir.path()))
+            .with_context(|| format!("Failed to extract zip archive: {}", path.display()))?;
+    } else {
+        tar::Archive::new(flate2::read::GzDecoder::new(file))
+            .unpack(dir.path())
+            .with_context(|| format!("Failed to extract tar.gz archive: {}", path.display()))?;
+    }
+
+    Ok(dir)
+}
+
+/// Only plain `.py` sources are analyzed; `.pyi` type stubs have no executable bodies
+fn is_analyzable_python_file(path: &std::path::Path) -> bool {
+    match path.extension().and_then(|s| s.to_str()) {
+        Some("py") => true,
+        Some("pyi") => false,
+        _ => false,
+    }
+}
+
 fn collect_python_files(path: &PathBuf) -> Result<Vec<PathBuf>> {
     let mut files = Vec::new();
 
     if path.is_file() {
-        if path.extension().and_then(|s| s.to_str()) == Some("py") {
+        if is_analyzable_python_file(path) {
             files.push(path.clone());
         }
     } else if path.is_dir() {
         for entry in WalkDir::new(path)
             .into_iter()
             .filter_map(|e| e.ok())
-            .filter(|e| e.path().extension().and_then(|s| s.to_str()) == Some("py"))
+            .filter(|e| is_analyzable_python_file(e.path()))
         {
             files.push(entry.path().to_path_buf());
         }
@@ -1834,29 +5846,265 @@ fn collect_python_files(path: &PathBuf) -> Result<Vec<PathBuf>> {
     Ok(files)
 }
 
-fn extract_functions(file_path: &PathBuf) -> Result<Vec<FunctionInfo>> {
-    let source = std::fs::read_to_string(file_path)
-        .context("Failed to read Python file")?;
+/// Identity of a function for --sample: file, line and name
+type SampledFunctions = std::collections::HashSet<(PathBuf, usize, String)>;
+
+/// Parse `--lines START:END` (1-based, inclusive)
+fn parse_line_range(value: &str) -> Result<(usize, usize), String> {
+    let (start, end) = value
+        .split_once(':')
+        .ok_or_else(|| format!("expected START:END, got '{}'", value))?;
+    let start: usize = start.trim().parse().map_err(|_| format!("invalid start line '{}'", start))?;
+    let end: usize = end.trim().parse().map_err(|_| format!("invalid end line '{}'", end))?;
+    if start == 0 || end < start {
+        return Err(format!("invalid line range {}:{} (lines are 1-based and START <= END)", start, end));
+    }
+    Ok((start, end))
+}
+
+/// Parse a fraction in 0.0-1.0 (e.g. `--collapse-similar`)
+fn parse_ratio(value: &str) -> Result<f32, String> {
+    match value.trim().parse::<f32>() {
+        Ok(ratio) if (0.0..=1.0).contains(&ratio) => Ok(ratio),
+        _ => Err(format!("expected a number between 0.0 and 1.0, got '{}'", value)),
+    }
+}
+
+/// Whether any line of the function's source falls within `start..=end`
+fn function_overlaps_lines(func: &FunctionInfo, start: usize, end: usize) -> bool {
+    let last_line = func.line_number + func.source.lines().count().saturating_sub(1);
+    func.line_number <= end && last_line >= start
+}
+
+fn sample_key(func: &FunctionInfo) -> (PathBuf, usize, String) {
+    (func.file_path.clone(), func.line_number, func.name.clone())
+}
+
+fn sample_percentage(sampled: usize, population: usize) -> f64 {
+    if population == 0 { 0.0 } else { sampled as f64 / population as f64 * 100.0 }
+}
+
+/// Pick `n` of `total` indices uniformly at random, reproducibly for a given seed
+/// (partial Fisher-Yates driven by splitmix64)
+fn sample_indices(total: usize, n: usize, seed: u64) -> Vec<usize> {
+    let mut state = seed;
+    let mut next = || {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    };
+
+    let mut indices: Vec<usize> = (0..total).collect();
+    let n = n.min(total);
+    for i in 0..n {
+        let j = i + (next() % (total - i) as u64) as usize;
+        indices.swap(i, j);
+    }
+    indices.truncate(n);
+    indices.sort_unstable();
+    indices
+}
+
+/// `file_path` below the analyzed root: a directory, or a single file whose own directory is
+/// the root. Paths outside the root are returned unchanged
+fn root_relative_path<'a>(file_path: &'a std::path::Path, root: &std::path::Path) -> &'a std::path::Path {
+    let base = if root.is_file() { root.parent().unwrap_or(root) } else { root };
+    file_path.strip_prefix(base).unwrap_or(file_path)
+}
+
+/// Dotted module path of `file_path` relative to the analyzed root: `pkg/models/user.py`
+/// becomes `pkg.models.user` and a package's `__init__.py` maps to the package itself.
+/// A single analyzed file is its own root, so its module path is the file stem.
+fn module_path_for(file_path: &std::path::Path, root: &std::path::Path) -> String {
+    let relative = root_relative_path(file_path, root);
+
+    let mut parts: Vec<String> = relative
+        .with_extension("")
+        .components()
+        .filter_map(|c| match c {
+            std::path::Component::Normal(part) => Some(part.to_string_lossy().to_string()),
+            _ => None,
+        })
+        .collect();
+    if parts.len() > 1 && parts.last().map(String::as_str) == Some("__init__") {
+        parts.pop();
+    }
+    parts.join(".")
+}
+
+/// Extract analysis units from a file; `include_classes` also emits one unit per class.
+/// `root` is the analyzed PATH, which module paths are relative to
+fn extract_functions(
+    file_path: &PathBuf,
+    root: &std::path::Path,
+    include_classes: bool,
+    nested_functions: bool,
+    origin: SourceOrigin,
+) -> Result<Vec<FunctionInfo>> {
+    let source = read_python_source(file_path, origin)?;
+    extract_functions_from_source(&source, file_path, root, include_classes, nested_functions)
+}
+
+/// Path that `--code` snippets are analyzed and reported under
+const INLINE_PATH: &str = "<inline>";
+
+/// Where analyzed sources are read from
+#[derive(Clone, Copy)]
+enum SourceOrigin<'a> {
+    /// The working tree
+    Files,
+    /// The git index (--staged)
+    Staged,
+    /// A `--code` snippet, standing in for the file `<inline>`
+    Inline(&'a str),
+}
+
+impl<'a> SourceOrigin<'a> {
+    fn from_cli(cli: &'a Cli) -> Self {
+        match (&cli.code, cli.staged) {
+            (Some(code), _) => SourceOrigin::Inline(code),
+            (None, true) => SourceOrigin::Staged,
+            (None, false) => SourceOrigin::Files,
+        }
+    }
+}
+
+/// The files to analyze under PATH, or just `<inline>` for a `--code` snippet
+fn collect_sources(cli: &Cli, path: &PathBuf) -> Result<Vec<PathBuf>> {
+    if cli.code.is_some() {
+        Ok(vec![PathBuf::from(INLINE_PATH)])
+    } else {
+        collect_python_files(path)
+    }
+}
+
+/// Turn the `\n`/`\t` escapes of a single-line `--code` argument into real line breaks and
+/// tabs, so snippets can be passed without shell-specific quoting
+fn unescape_inline_code(code: &str) -> String {
+    if code.contains('\n') {
+        code.to_string()
+    } else {
+        code.replace("\\n", "\n").replace("\\t", "\t")
+    }
+}
+
+/// Read a Python file from the working tree, its staged version from the git index, or
+/// the `--code` snippet
+fn read_python_source(file_path: &std::path::Path, origin: SourceOrigin) -> Result<String> {
+    match origin {
+        SourceOrigin::Files => return std::fs::read_to_string(file_path).context("Failed to read Python file"),
+        SourceOrigin::Inline(code) => return Ok(unescape_inline_code(code)),
+        SourceOrigin::Staged => {}
+    }
+
+    let (dir, name) = git_location(file_path)?;
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .arg("show")
+        .arg(format!(":./{}", name.to_string_lossy()))
+        .output()
+        .context("Failed to run git (required by --staged)")?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "Failed to read staged version of {}: {}",
+            file_path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    String::from_utf8(output.stdout)
+        .with_context(|| format!("Staged version of {} is not valid UTF-8", file_path.display()))
+}
+
+/// Whether a file is tracked in the git index (so --staged has a version to read)
+fn is_in_git_index(file_path: &std::path::Path) -> bool {
+    let Ok((dir, name)) = git_location(file_path) else {
+        return false;
+    };
+    std::process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["ls-files", "--error-unmatch", "--"])
+        .arg(name)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Directory to run git in and the file name relative to it
+fn git_location(file_path: &std::path::Path) -> Result<(&std::path::Path, &std::ffi::OsStr)> {
+    let name = file_path.file_name()
+        .with_context(|| format!("Not a file path: {}", file_path.display()))?;
+    let dir = file_path.parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(std::path::Path::new("."));
+    Ok((dir, name))
+}
 
-    let parsed = parse(&source, Mode::Module, "<embedded>")
+fn extract_functions_from_source(
+    source: &str,
+    file_path: &PathBuf,
+    root: &std::path::Path,
+    include_classes: bool,
+    nested_functions: bool,
+) -> Result<Vec<FunctionInfo>> {
+    let parsed = parse(source, Mode::Module, "<embedded>")
         .map_err(|e| anyhow::anyhow!("Failed to parse Python: {:?}", e))?;
 
     let mut functions = Vec::new();
 
     if let Mod::Module(module) = parsed {
-        extract_functions_from_body(&module.body, &source, file_path, None, &mut functions);
+        extract_functions_from_body(&module.body, source, file_path, &ExtractionScope::default(), include_classes, nested_functions, &mut functions);
+    }
+
+    let module_path = module_path_for(file_path, root);
+    for func in &mut functions {
+        func.module_path = module_path.clone();
     }
 
     Ok(functions)
 }
 
+/// Where extraction is: the class whose body is walked, and the function it sits in
+#[derive(Clone, Default)]
+struct ExtractionScope {
+    class_name: Option<String>,
+    enclosing_function: Option<String>,
+}
+
+impl ExtractionScope {
+    /// Scope for the body of function `name` defined here (--nested-functions); it is no
+    /// longer inside a class body, so nested helpers aren't methods
+    fn function(&self, name: &str) -> Self {
+        let qualified = self.enclosing_function.iter()
+            .chain(&self.class_name)
+            .map(String::as_str)
+            .chain(std::iter::once(name))
+            .collect::<Vec<_>>()
+            .join("::");
+        ExtractionScope { class_name: None, enclosing_function: Some(qualified) }
+    }
+
+    /// Scope for the body of class `name` defined here
+    fn class(&self, name: &str) -> Self {
+        ExtractionScope { class_name: Some(name.to_string()), enclosing_function: self.enclosing_function.clone() }
+    }
+}
+
 fn extract_functions_from_body(
     body: &[Stmt],
     source: &str,
     file_path: &PathBuf,
-    class_name: Option<String>,
+    scope: &ExtractionScope,
+    include_classes: bool,
+    nested_functions: bool,
     functions: &mut Vec<FunctionInfo>,
 ) {
+    let class_name = &scope.class_name;
+
     for stmt in body {
         match stmt {
             Stmt::FunctionDef(func_def) => {
@@ -1871,7 +6119,28 @@ fn extract_functions_from_body(
                     file_path: file_path.clone(),
                     line_number,
                     class_name: class_name.clone(),
+                    enclosing_function: scope.enclosing_function.clone(),
+                    is_stub: is_stub_body(&func_def.body),
+                    is_class_scope: false,
+                    is_recursive: is_recursive(&func_def.name, &func_def.args, &func_def.body, class_name.as_deref()),
+                    class_context: String::new(),
+                    decorators: decorator_lines(&func_def.decorator_list, source),
+                    module_path: String::new(),
+                    context_above: None,
+                    header_lines: header_line_count(source, line_number, &func_def.body),
                 });
+
+                if nested_functions {
+                    extract_functions_from_body(
+                        &func_def.body,
+                        source,
+                        file_path,
+                        &scope.function(func_def.name.as_str()),
+                        include_classes,
+                        nested_functions,
+                        functions
+                    );
+                }
             }
             Stmt::AsyncFunctionDef(func_def) => {
                 let func_source = extract_source_from_range(&source, func_def.range.start(), func_def.range.end());
@@ -1885,37 +6154,235 @@ fn extract_functions_from_body(
                     file_path: file_path.clone(),
                     line_number,
                     class_name: class_name.clone(),
+                    enclosing_function: scope.enclosing_function.clone(),
+                    is_stub: is_stub_body(&func_def.body),
+                    is_class_scope: false,
+                    is_recursive: is_recursive(&func_def.name, &func_def.args, &func_def.body, class_name.as_deref()),
+                    class_context: String::new(),
+                    decorators: decorator_lines(&func_def.decorator_list, source),
+                    module_path: String::new(),
+                    context_above: None,
+                    header_lines: header_line_count(source, line_number, &func_def.body),
                 });
+
+                if nested_functions {
+                    extract_functions_from_body(
+                        &func_def.body,
+                        source,
+                        file_path,
+                        &scope.function(func_def.name.as_str()),
+                        include_classes,
+                        nested_functions,
+                        functions
+                    );
+                }
             }
             Stmt::ClassDef(class_def) => {
+                if include_classes {
+                    let class_source = extract_source_from_range(source, class_def.range.start(), class_def.range.end());
+                    let line_number = count_lines_to_offset(source, class_def.range.start());
+                    let class_source_no_docstring = strip_docstring(&class_source);
+
+                    functions.push(FunctionInfo {
+                        name: class_def.name.to_string(),
+                        source: class_source,
+                        source_no_docstring: class_source_no_docstring,
+                        file_path: file_path.clone(),
+                        line_number,
+                        class_name: class_name.clone(),
+                        enclosing_function: scope.enclosing_function.clone(),
+                        is_stub: false,
+                        is_class_scope: true,
+                        is_recursive: false,
+                        class_context: String::new(),
+                        decorators: decorator_lines(&class_def.decorator_list, source),
+                        module_path: String::new(),
+                        context_above: None,
+                        header_lines: header_line_count(source, line_number, &class_def.body),
+                    });
+                }
+
                 // Recursively extract functions from class bodies
+                let first_method = functions.len();
                 extract_functions_from_body(
                     &class_def.body,
                     source,
                     file_path,
-                    Some(class_def.name.to_string()),
+                    &scope.class(class_def.name.as_str()),
+                    include_classes,
+                    nested_functions,
                     functions
                 );
+
+                // Methods see what `__init__` stores on `self` through {class_context}
+                let class_context = init_attribute_summary(&class_def.body, source);
+                if !class_context.is_empty() {
+                    for method in &mut functions[first_method..] {
+                        if method.class_name.as_deref() == Some(class_def.name.as_str()) {
+                            method.class_context = class_context.clone();
+                        }
+                    }
+                }
             }
             _ => {}
         }
     }
 }
 
-fn extract_source_from_range(source: &str, start: impl Into<usize>, end: impl Into<usize>) -> String {
-    let start_usize: usize = start.into();
-    let end_usize: usize = end.into();
-    source.get(start_usize..end_usize)
-        .unwrap_or("")
-        .to_string()
+/// Number of lines from a definition's first line to the start of its body (at least one,
+/// for one-liners such as `def f(): pass`)
+fn header_line_count(source: &str, line_number: usize, body: &[Stmt]) -> usize {
+    use rustpython_ast::Ranged;
+
+    body.first()
+        .map(|stmt| count_lines_to_offset(source, stmt.range().start()))
+        .map_or(1, |body_line| body_line.saturating_sub(line_number).max(1))
 }
 
-fn count_lines_to_offset(source: &str, offset: impl Into<usize>) -> usize {
-    let offset_usize: usize = offset.into();
-    source[..offset_usize.min(source.len())]
-        .lines()
-        .count()
-        + 1
+/// Source text of each decorator, as an `@...` line
+fn decorator_lines(decorators: &[Expr], source: &str) -> Vec<String> {
+    use rustpython_ast::Ranged;
+
+    decorators.iter()
+        .map(|decorator| format!("@{}", extract_source_from_range(source, decorator.range().start(), decorator.range().end())))
+        .collect()
+}
+
+/// Collects the ranges of statements that assign to `self` attributes
+struct SelfAttributeAssignments {
+    ranges: Vec<rustpython_parser::text_size::TextRange>,
+}
+
+impl SelfAttributeAssignments {
+    fn is_self_attribute(target: &Expr) -> bool {
+        match target {
+            Expr::Attribute(attribute) => matches!(attribute.value.as_ref(), Expr::Name(name) if name.id.as_str() == "self"),
+            Expr::Tuple(tuple) => tuple.elts.iter().any(Self::is_self_attribute),
+            Expr::List(list) => list.elts.iter().any(Self::is_self_attribute),
+            _ => false,
+        }
+    }
+}
+
+impl Visitor for SelfAttributeAssignments {
+    fn visit_stmt_assign(&mut self, node: rustpython_ast::StmtAssign) {
+        if node.targets.iter().any(Self::is_self_attribute) {
+            self.ranges.push(node.range);
+        }
+    }
+
+    fn visit_stmt_ann_assign(&mut self, node: rustpython_ast::StmtAnnAssign) {
+        if Self::is_self_attribute(&node.target) {
+            self.ranges.push(node.range);
+        }
+    }
+}
+
+/// The `self.x = ...` statements of a class's `__init__` (nested blocks included), one per
+/// line, or an empty string when the class has no `__init__` or it sets no attributes
+fn init_attribute_summary(class_body: &[Stmt], source: &str) -> String {
+    let Some(init) = class_body.iter().find_map(|stmt| match stmt {
+        Stmt::FunctionDef(func_def) if func_def.name.as_str() == "__init__" => Some(func_def),
+        _ => None,
+    }) else {
+        return String::new();
+    };
+
+    let mut assignments = SelfAttributeAssignments { ranges: Vec::new() };
+    for stmt in &init.body {
+        assignments.visit_stmt(stmt.clone());
+    }
+    assignments.ranges.iter()
+        .map(|range| extract_source_from_range(source, range.start(), range.end()).trim().to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Detect stub bodies: only docstrings, `...`, `pass`, or `raise NotImplementedError`
+fn is_stub_body(body: &[Stmt]) -> bool {
+    !body.is_empty() && body.iter().all(|stmt| match stmt {
+        Stmt::Pass(_) => true,
+        Stmt::Expr(expr_stmt) => matches!(
+            expr_stmt.value.as_ref(),
+            Expr::Constant(c) if matches!(c.value, Constant::Ellipsis | Constant::Str(_))
+        ),
+        Stmt::Raise(raise) => raise.exc.as_deref().is_some_and(is_not_implemented_error),
+        _ => false,
+    })
+}
+
+/// Looks for calls of a function to itself while walking its body
+struct SelfCallFinder<'a> {
+    name: &'a str,
+    /// Receivers of attribute calls that reach the function (`self`, `cls`, the class name)
+    receivers: Vec<&'a str>,
+    /// Whether a bare `name(...)` call refers to the function (not for methods)
+    bare_calls: bool,
+    found: bool,
+}
+
+impl Visitor for SelfCallFinder<'_> {
+    fn visit_expr_call(&mut self, node: rustpython_ast::ExprCall) {
+        self.found |= match node.func.as_ref() {
+            Expr::Name(callee) => self.bare_calls && callee.id.as_str() == self.name,
+            Expr::Attribute(callee) => callee.attr.as_str() == self.name
+                && matches!(callee.value.as_ref(), Expr::Name(receiver) if self.receivers.contains(&receiver.id.as_str())),
+            _ => false,
+        };
+        self.generic_visit_expr_call(node);
+    }
+}
+
+/// Whether a function calls itself directly. Methods (first parameter `self` or `cls`)
+/// recurse through that parameter or the class name; other functions by bare name.
+/// `class_name` is the class whose body defines the function, if any.
+fn is_recursive(name: &str, args: &rustpython_ast::Arguments, body: &[Stmt], class_name: Option<&str>) -> bool {
+    let first_param = args.posonlyargs.first().or(args.args.first()).map(|param| param.def.arg.as_str());
+    let method_receiver = first_param.filter(|param| class_name.is_some() && matches!(*param, "self" | "cls"));
+    let mut finder = SelfCallFinder {
+        name,
+        receivers: method_receiver.into_iter()
+            .chain(class_name)
+            .collect(),
+        bare_calls: method_receiver.is_none(),
+        found: false,
+    };
+    for stmt in body {
+        finder.visit_stmt(stmt.clone());
+        if finder.found {
+            return true;
+        }
+    }
+    false
+}
+
+fn is_not_implemented_error(expr: &Expr) -> bool {
+    match expr {
+        Expr::Name(name) => name.id.as_str() == "NotImplementedError",
+        Expr::Call(call) => is_not_implemented_error(&call.func),
+        _ => false,
+    }
+}
+
+fn extract_source_from_range(source: &str, start: impl Into<usize>, end: impl Into<usize>) -> String {
+    let start_usize: usize = start.into();
+    let end_usize: usize = end.into();
+    source.get(start_usize..end_usize)
+        .unwrap_or("")
+        .to_string()
+}
+
+/// 1-based line of a byte offset, as an editor shows it: the line the offset falls on.
+/// `\n`, `\r\n` and lone `\r` all end a line.
+fn count_lines_to_offset(source: &str, offset: impl Into<usize>) -> usize {
+    let bytes = source.as_bytes();
+    let end = offset.into().min(bytes.len());
+    let line_breaks = bytes[..end]
+        .iter()
+        .enumerate()
+        .filter(|&(idx, &byte)| byte == b'\n' || (byte == b'\r' && bytes.get(idx + 1) != Some(&b'\n')))
+        .count();
+    line_breaks + 1
 }
 
 /// Strip docstrings from Python function source to reduce token usage
@@ -1978,26 +6445,170 @@ fn strip_docstring(source: &str) -> String {
 /// Extract confidence percentage from analysis text
 /// Looks for "[Confidence: X.XX]" pattern and converts to percentage
 fn extract_confidence_percentage(analysis: &str) -> u32 {
-    // Look for [Confidence: X.XX] pattern
-    if let Some(start) = analysis.find("[Confidence: ") {
-        if let Some(end) = analysis[start..].find(']') {
-            let conf_str = &analysis[start + 13..start + end];
-            if let Ok(conf_float) = conf_str.parse::<f32>() {
-                return (conf_float * 100.0).round() as u32;
+    // Look for [Confidence: X.XX] pattern, default to 0 if not found
+    extract_bracketed_percentage(analysis, "[Confidence: ").unwrap_or(0)
+}
+
+/// Extract the verifier confidence recorded as [Verifier confidence: X.XX], if any
+fn extract_verifier_confidence_percentage(analysis: &str) -> Option<u32> {
+    extract_bracketed_percentage(analysis, "[Verifier confidence: ")
+}
+
+fn extract_bracketed_percentage(analysis: &str, marker: &str) -> Option<u32> {
+    let start = analysis.find(marker)? + marker.len();
+    let end = analysis[start..].find(']')?;
+    let conf_float = analysis[start..start + end].parse::<f32>().ok()?;
+    Some((conf_float * 100.0).round() as u32)
+}
+
+/// --blend-confidence: when the verifier recorded its confidence in a VALID solution, replace
+/// the detection confidence `d` with `d + (1 - d) * v / 2` (the verifier closes up to half of
+/// the remaining gap, so the result never drops below `d` nor exceeds 1.0). The original is
+/// kept as [Detection confidence: X.XX].
+fn blended_confidence_analysis(analysis: &str) -> Option<String> {
+    const MARKER: &str = "[Confidence: ";
+    let start = analysis.find(MARKER)?;
+    let end = start + analysis[start..].find(']')? + 1;
+    let detection: f32 = analysis[start + MARKER.len()..end - 1].parse().ok()?;
+    let verifier = extract_bracketed_percentage(analysis, "[Verifier confidence: ")? as f32 / 100.0;
+    let blended = (detection + (1.0 - detection) * verifier / 2.0).min(1.0);
+    Some(format!(
+        "{}[Confidence: {:.2}]\n[Detection confidence: {:.2}]{}",
+        &analysis[..start], blended, detection, &analysis[end..]
+    ))
+}
+
+/// Heading label for an issue's confidence, including the verifier's when recorded
+fn confidence_label(issue: &CheckResult) -> String {
+    let confidence_pct = extract_confidence_percentage(&issue.analysis);
+    let label = match extract_verifier_confidence_percentage(&issue.analysis) {
+        Some(verifier_pct) => format!("confidence: {}%, verifier: {}%", confidence_pct, verifier_pct),
+        None => format!("confidence: {}%", confidence_pct),
+    };
+    if issue.advisory {
+        format!("{}, advisory", label)
+    } else {
+        label
+    }
+}
+
+/// Detection confidence recorded below `cutoff` (issues without one are never below it)
+fn is_below_confidence(analysis: &str, cutoff: f32) -> bool {
+    extract_bracketed_percentage(analysis, "[Confidence: ")
+        .is_some_and(|pct| pct < (cutoff * 100.0).round() as u32)
+}
+
+/// Resolve `--add-bos`; `auto` follows the GGUF `tokenizer.ggml.add_bos_token` flag
+fn resolve_add_bos(model: &LlamaModel, mode: &str) -> llama_cpp_2::model::AddBos {
+    use llama_cpp_2::model::AddBos;
+
+    match mode {
+        "always" => AddBos::Always,
+        "never" => AddBos::Never,
+        _ => match model.meta_val_str("tokenizer.ggml.add_bos_token").as_deref() {
+            Ok("false") => AddBos::Never,
+            _ => AddBos::Always,
+        },
+    }
+}
+
+/// Tokenizes prompts for one model, caching the tokens of static prompt prefixes
+struct PromptTokenizer {
+    add_bos: llama_cpp_2::model::AddBos,
+    /// Keyed on the prefix text (one model per run)
+    prefix_tokens: std::collections::HashMap<String, Vec<llama_cpp_2::token::LlamaToken>>,
+}
+
+impl PromptTokenizer {
+    fn new(add_bos: llama_cpp_2::model::AddBos) -> Self {
+        Self { add_bos, prefix_tokens: std::collections::HashMap::new() }
+    }
+
+    /// Tokenize a prompt, reusing the cached tokens of its static prefix when possible
+    fn tokenize(&mut self, model: &LlamaModel, prompt: &Prompt) -> Result<Vec<llama_cpp_2::token::LlamaToken>> {
+        if prompt.prefix_len == 0 {
+            return Ok(model.str_to_token(&prompt.text, self.add_bos)?);
+        }
+
+        let mut tokens = match self.prefix_tokens.get(prompt.prefix()) {
+            Some(cached) => cached.clone(),
+            None => {
+                let tokens = model.str_to_token(prompt.prefix(), self.add_bos)?;
+                self.prefix_tokens.insert(prompt.prefix().to_string(), tokens.clone());
+                tokens
             }
+        };
+        tokens.extend(model.str_to_token(prompt.suffix(), llama_cpp_2::model::AddBos::Never)?);
+        Ok(tokens)
+    }
+}
+
+/// Seed for the `round`-th draw of a prompt. It depends only on --seed, the prompt and the
+/// round, so a run reproduces whatever other prompts are sampled in between
+fn draw_seed(seed: u64, prompt_digest: &[u8], round: u32) -> u32 {
+    let mut hasher = Sha256::new();
+    hasher.update(seed.to_le_bytes());
+    hasher.update(prompt_digest);
+    hasher.update(round.to_le_bytes());
+    let digest = hasher.finalize();
+    u32::from_le_bytes([digest[0], digest[1], digest[2], digest[3]])
+}
+
+/// Sampling settings for one local generation
+#[derive(Debug, Clone, Copy)]
+struct Sampling {
+    /// 0 (or below) means greedy decoding
+    temperature: f32,
+    seed: u32,
+}
+
+/// Why a generation ended
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum StopReason {
+    /// The model emitted an end-of-generation token
+    Eog,
+    /// The `END` stop line was reached
+    StopSequence,
+    /// The --max-tokens budget (or the context) ran out
+    MaxTokens,
+    /// The backend gave up on time (reported by some API servers)
+    Timeout,
+}
+
+impl StopReason {
+    /// The response was cut off rather than finished
+    fn is_truncated(self) -> bool {
+        matches!(self, StopReason::MaxTokens | StopReason::Timeout)
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            StopReason::Eog => "eog",
+            StopReason::StopSequence => "stop_sequence",
+            StopReason::MaxTokens => "max_tokens",
+            StopReason::Timeout => "timeout",
         }
     }
-    // Default to 0 if not found
-    0
+
+    fn parse(value: &str) -> Option<Self> {
+        [StopReason::Eog, StopReason::StopSequence, StopReason::MaxTokens, StopReason::Timeout]
+            .into_iter()
+            .find(|reason| reason.as_str() == value)
+    }
 }
 
 fn generate_response(
     model: &LlamaModel,
     ctx: &mut LlamaContext,
-    prompt: &str,
+    prompt: &Prompt,
+    tokenizer: &mut PromptTokenizer,
     max_tokens: i32,
+    sampling: Sampling,
     verbose: bool,
-) -> Result<(String, bool, TokenStats)> {  // Returns (response, was_truncated, token_stats)
+) -> Result<(String, StopReason, TokenStats)> {  // Returns (response, stop_reason, token_stats)
+    let _span = tracing::debug_span!("generate", max_tokens).entered();
+
     // Start timing
     let start_time = Instant::now();
 
@@ -2006,7 +6617,7 @@ fn generate_response(
         println!("\n╔════════════════════════════════════════════════════════════════");
         println!("║ PROMPT");
         println!("╚════════════════════════════════════════════════════════════════");
-        println!("{}", prompt);
+        println!("{}", prompt.text);
         println!("────────────────────────────────────────────────────────────────\n");
     }
 
@@ -2017,8 +6628,8 @@ fn generate_response(
         (None, None)
     };
 
-    // Tokenize the prompt (AddBos::Always adds BOS token)
-    let tokens = model.str_to_token(prompt, llama_cpp_2::model::AddBos::Always)?;
+    // Tokenize the prompt (BOS handling follows --add-bos)
+    let tokens = tokenizer.tokenize(model, prompt)?;
     let input_token_count = tokens.len();
 
     // Get context size from context
@@ -2030,53 +6641,75 @@ fn generate_response(
 
     // Check if prompt is too large
     if tokens.len() > max_prompt_size {
+        // Only suggest a bigger context when the model can actually use one
+        let suggested_ctx = ctx_size * 2;
+        let trained_ctx = model.n_ctx_train() as usize;
+        let hint = if trained_ctx == 0 || suggested_ctx <= trained_ctx {
+            format!("Use --context-size {} or --skip-large.", suggested_ctx)
+        } else {
+            format!("The model was trained with {} tokens of context; use --skip-large.", trained_ctx)
+        };
         return Err(anyhow::anyhow!(
-            "Function too large ({} tokens, context allows {}). Use --context-size {} or --skip-large.",
+            "Function too large ({} tokens, context allows {}). {}",
             tokens.len(),
             max_prompt_size,
-            ctx_size * 2
+            hint
         ));
     }
 
     // Clear context and add tokens
     ctx.clear_kv_cache();
 
-    // Use larger batch size to accommodate big functions
-    let mut batch = LlamaBatch::new(4096, 1);
+    // Sized like the context's n_batch (--batch-size)
+    let batch_size = ctx.n_batch() as usize;
+    let mut batch = LlamaBatch::new(batch_size, 1);
 
     // Dynamically adjust max_tokens if prompt is large to avoid context overflow
     let available_tokens = ctx_size.saturating_sub(tokens.len()).saturating_sub(safety_margin);
     let actual_max_tokens = max_tokens.min(available_tokens as i32);
 
-    // Add all tokens to the batch. Only request logits for the last token
-    for (i, token) in tokens.iter().enumerate() {
-        let is_last = i == tokens.len() - 1;
-        batch.add(*token, i as i32, &[0], is_last)?;
+    // Evaluate the prompt one batch at a time. Only request logits for the last token
+    for (chunk_idx, chunk) in tokens.chunks(batch_size).enumerate() {
+        batch.clear();
+        for (offset, token) in chunk.iter().enumerate() {
+            let position = chunk_idx * batch_size + offset;
+            batch.add(*token, position as i32, &[0], position == tokens.len() - 1)?;
+        }
+        ctx.decode(&mut batch)?;
     }
 
-    ctx.decode(&mut batch)?;
-
     // Generate response
     let mut response = String::new();
     let mut n_cur = tokens.len() as i32;
-    let mut hit_eog = false;
+    let mut stop_reason = StopReason::MaxTokens;
     let mut output_token_count = 0;
 
+    // Seeded so that a given prompt, temperature and --seed always sample the same way
+    let mut sampler = (sampling.temperature > 0.0).then(|| {
+        LlamaSampler::chain_simple([LlamaSampler::temp(sampling.temperature), LlamaSampler::dist(sampling.seed)])
+    });
+
     for _ in 0..actual_max_tokens {
-        let mut candidates: Vec<_> = ctx.candidates().collect();
+        let new_token = if let Some(sampler) = sampler.as_mut() {
+            let token = sampler.sample(ctx, batch.n_tokens() - 1);
+            sampler.accept(token);
+            token
+        } else {
+            let mut candidates: Vec<_> = ctx.candidates().collect();
 
-        if candidates.is_empty() {
-            break;
-        }
+            if candidates.is_empty() {
+                break;
+            }
 
-        // Sort by probability (descending) for greedy sampling
-        candidates.sort_by(|a, b| b.logit().partial_cmp(&a.logit()).unwrap());
+            // Sort by probability (descending) for greedy sampling
+            candidates.sort_by(|a, b| b.logit().partial_cmp(&a.logit()).unwrap());
 
-        // Greedy sampling - pick the token with highest probability
-        let new_token = candidates[0].id();
+            // Greedy sampling - pick the token with highest probability
+            candidates[0].id()
+        };
 
         if model.is_eog_token(new_token) {
-            hit_eog = true;
+            stop_reason = StopReason::Eog;
             break;
         }
 
@@ -2102,7 +6735,7 @@ fn generate_response(
                 if let Some(pos) = response.rfind('\n') {
                     response.truncate(pos);
                 }
-                hit_eog = true;
+                stop_reason = StopReason::StopSequence;
                 break;
             }
         }
@@ -2118,7 +6751,7 @@ fn generate_response(
     let generation_time = start_time.elapsed();
     drop(stdout_suppressor);
     drop(stderr_suppressor);
-    let was_truncated = !hit_eog;
+    let was_truncated = stop_reason.is_truncated();
 
     // If truncated, clean up any unclosed markdown code blocks
     let cleaned_response = if was_truncated {
@@ -2136,15 +6769,24 @@ fn generate_response(
         println!("╚════════════════════════════════════════════════════════════════");
         println!("{}", cleaned_response);
         println!("────────────────────────────────────────────────────────────────");
-        println!("📊 Tokens: {} in, {} out | Speed: {:.1} tok/s | Time: {:.1}s{}",
+        println!("📊 Tokens: {} in, {} out | Speed: {:.1} tok/s | Time: {:.1}s | Stop: {}{}",
             input_token_count, output_token_count,
             stats.tokens_per_second(), generation_time.as_secs_f64(),
+            stop_reason.as_str(),
             if was_truncated { " | ⚠️ TRUNCATED" } else { "" }
         );
         println!("────────────────────────────────────────────────────────────────\n");
     }
 
-    Ok((cleaned_response, was_truncated, stats))
+    tracing::debug!(
+        input_tokens = input_token_count,
+        output_tokens = output_token_count,
+        stop_reason = stop_reason.as_str(),
+        "generation finished in {:.1}s",
+        generation_time.as_secs_f64()
+    );
+
+    Ok((cleaned_response, stop_reason, stats))
 }
 
 /// Fix truncated markdown by closing unclosed code blocks and adding truncation notice
@@ -2169,8 +6811,10 @@ fn generate_response_api(
     api: &ApiConfig,
     prompt: &str,
     max_tokens: i32,
+    temperature: f32,
     verbose: bool,
-) -> Result<(String, bool, TokenStats)> {
+) -> Result<(String, StopReason, TokenStats)> {
+    let _span = tracing::debug_span!("generate", max_tokens, backend = "api").entered();
     let start_time = Instant::now();
 
     if verbose {
@@ -2189,7 +6833,7 @@ fn generate_response_api(
         "model": api.model_id,
         "messages": messages,
         "max_tokens": max_tokens,
-        "temperature": 0,
+        "temperature": temperature,
         "stop": ["END"]
     });
 
@@ -2241,13 +6885,22 @@ fn generate_response_api(
         .and_then(|v| v.as_u64())
         .unwrap_or(0) as usize;
 
-    let was_truncated = finish_reason == "length";
+    // vLLM reports the matched stop string in `stop_reason`; TGI uses a "stop_sequence" finish reason
+    let mut stop_reason = match finish_reason {
+        "length" => StopReason::MaxTokens,
+        "timeout" => StopReason::Timeout,
+        "stop_sequence" => StopReason::StopSequence,
+        _ if choice.get("stop_reason").is_some_and(|v| v.is_string()) => StopReason::StopSequence,
+        _ => StopReason::Eog,
+    };
+    let was_truncated = stop_reason.is_truncated();
     let generation_time = start_time.elapsed();
 
     // Strip trailing END line if present (some configs include the stop sequence)
     let mut cleaned = generated_text;
     if let Some(last_line) = cleaned.lines().last() {
         if last_line.trim() == "END" {
+            stop_reason = StopReason::StopSequence;
             if let Some(pos) = cleaned.rfind('\n') {
                 cleaned.truncate(pos);
             } else {
@@ -2272,16 +6925,25 @@ fn generate_response_api(
         println!("╚════════════════════════════════════════════════════════════════");
         println!("{}", cleaned);
         println!("────────────────────────────────────────────────────────────────");
-        println!("📊 Tokens: {} in, {} out | Speed: {:.1} tok/s | Time: {:.1}s{}",
+        println!("📊 Tokens: {} in, {} out | Speed: {:.1} tok/s | Time: {:.1}s | Stop: {}{}",
             input_token_count, output_token_count,
             if generation_time.as_secs_f64() > 0.0 { output_token_count as f64 / generation_time.as_secs_f64() } else { 0.0 },
             generation_time.as_secs_f64(),
+            stop_reason.as_str(),
             if was_truncated { " | ⚠️ TRUNCATED" } else { "" }
         );
         println!("────────────────────────────────────────────────────────────────\n");
     }
 
-    Ok((cleaned, was_truncated, stats))
+    tracing::debug!(
+        input_tokens = input_token_count,
+        output_tokens = output_token_count,
+        stop_reason = stop_reason.as_str(),
+        "generation finished in {:.1}s",
+        generation_time.as_secs_f64()
+    );
+
+    Ok((cleaned, stop_reason, stats))
 }
 
 /// Parse a ChatML-formatted prompt into OpenAI messages array.
@@ -2320,6 +6982,41 @@ fn parse_chatml_to_messages(prompt: &str) -> Vec<serde_json::Value> {
 }
 
 /// Discover the model ID from an OpenAI-compatible /v1/models endpoint
+/// `--no-network`: fail fast instead of contacting anything beyond this machine
+fn ensure_network_allowed(no_network: bool, url: &str) -> Result<()> {
+    if !no_network {
+        return Ok(());
+    }
+
+    let host = reqwest::Url::parse(url)
+        .ok()
+        .and_then(|parsed| parsed.host_str().map(str::to_string))
+        .unwrap_or_default();
+    let is_loopback = host.eq_ignore_ascii_case("localhost")
+        || host
+            .trim_matches(['[', ']'])
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback());
+    if is_loopback {
+        return Ok(());
+    }
+
+    Err(anyhow::anyhow!(
+        "--no-network is set: refusing to contact {} (only loopback endpoints are allowed)",
+        url
+    ))
+}
+
+/// HTTP client for the API backend. With --no-network it ignores proxy variables and doesn't
+/// follow redirects, so requests can't leave the loopback endpoint ensure_network_allowed checked
+fn api_client(no_network: bool) -> Result<reqwest::blocking::Client> {
+    let mut builder = reqwest::blocking::Client::builder().timeout(Duration::from_secs(300));
+    if no_network {
+        builder = builder.no_proxy().redirect(reqwest::redirect::Policy::none());
+    }
+    builder.build().context("Failed to build the HTTP client")
+}
+
 fn discover_api_model(api: &ApiConfig) -> Result<String> {
     let models_url = format!("{}/v1/models", api.url);
     let mut request = api.client.get(&models_url);
@@ -2484,7 +7181,10 @@ fn extract_optimized_function(solution: &str) -> Option<String> {
 
 /// Generate a unified diff from original and optimized code
 fn generate_diff(original: &str, optimized: &str) -> String {
-    let diff = TextDiff::from_lines(original, optimized);
+    // A missing final newline on one side would show up as a phantom change of the last line
+    let original = with_trailing_newline(original);
+    let optimized = with_trailing_newline(optimized);
+    let diff = TextDiff::from_lines(&original, &optimized);
     let mut result = String::new();
 
     for change in diff.iter_all_changes() {
@@ -2499,6 +7199,16 @@ fn generate_diff(original: &str, optimized: &str) -> String {
     result
 }
 
+/// `text` ending in exactly one newline (empty text stays empty)
+fn with_trailing_newline(text: &str) -> String {
+    let trimmed = text.trim_end_matches(['\r', '\n']);
+    if trimmed.is_empty() {
+        String::new()
+    } else {
+        format!("{}\n", trimmed)
+    }
+}
+
 /// Validate that optimized function is substantially different from original
 fn validate_optimization(original: &str, optimized: &str) -> Result<(), String> {
     // Must be different
@@ -2525,7 +7235,12 @@ fn validate_optimization(original: &str, optimized: &str) -> Result<(), String>
     }
 }
 
-fn print_summary(file_results: &[FileResults], file_count: usize, total: usize, functions_with_issues: usize, checks: &[CheckConfig], cache: &AnalysisCache, no_cache: bool, stats: &TokenStats) {
+fn print_summary(output: &AnalysisOutput, file_count: usize, checks: &[CheckConfig], cache: &AnalysisCache, options: &ReportOptions, verbose: bool) {
+    let file_results = &output.file_results;
+    let total = output.total_functions;
+    let functions_with_issues = output.functions_with_issues;
+    let stats = &output.stats;
+
     println!("\n╔═══════════════════════════════╗");
     println!("║ LOOPSLEUTH ANALYSIS SUMMARY   ║");
     println!("╚═══════════════════════════════╝");
@@ -2541,9 +7256,36 @@ fn print_summary(file_results: &[FileResults], file_count: usize, total: usize,
     );
     println!("⚠️  Functions with issues: {}", functions_with_issues);
     println!("✓  Functions clean: {}", total - functions_with_issues);
+    if !output.unchecked_functions.is_empty() {
+        // Every check was scoped/guarded/filtered out: "clean" here means "never looked at"
+        println!("∅  Functions with no applicable check: {}{}",
+            output.unchecked_functions.len(),
+            if verbose { "" } else { " (use --verbose to list them)" }
+        );
+        if verbose {
+            for name in &output.unchecked_functions {
+                println!("     • {}", name);
+            }
+        }
+    }
+    if !output.prefiltered.is_empty() {
+        println!("⏩ Pre-filtered (no LLM call): {} ({})",
+            output.prefiltered.values().sum::<usize>(),
+            output.prefiltered.iter()
+                .map(|(key, count)| format!("{}: {}", key, count))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    if !output.unparseable_files.is_empty() {
+        println!("⊘  Files skipped (failed to parse): {}", output.unparseable_files.len());
+        for path in &output.unparseable_files {
+            println!("     • {}", options.display_path(path));
+        }
+    }
 
     // Show cache statistics if enabled
-    if !no_cache {
+    if !options.no_cache {
         if let Ok((cache_total, cache_with_issues)) = cache.stats() {
             if cache_total > 0 {
                 let expected_total = total * checks.len();
@@ -2565,17 +7307,13 @@ fn print_summary(file_results: &[FileResults], file_count: usize, total: usize,
                     .collect();
 
                 if !functions_with_issues_in_file.is_empty() {
-                    println!("\n  📄 {}", file_result.file_path.display());
+                    println!("\n  📄 {}", options.display_path(&file_result.file_path));
                     for result in functions_with_issues_in_file {
                         let issues: Vec<_> = result.check_results.iter()
                             .filter(|cr| cr.has_issue)
                             .map(|cr| cr.check_name.as_str())
                             .collect();
-                        let func_name = if let Some(ref class_name) = result.function.class_name {
-                            format!("{}::{}", class_name, result.function.name)
-                        } else {
-                            result.function.name.clone()
-                        };
+                        let func_name = result.function.qualified_name();
                         println!(
                             "     • {} (line {})",
                             func_name,
@@ -2596,15 +7334,11 @@ fn print_summary(file_results: &[FileResults], file_count: usize, total: usize,
                         .collect();
 
                     if !issues.is_empty() {
-                        let func_name = if let Some(ref class_name) = result.function.class_name {
-                            format!("{}::{}", class_name, result.function.name)
-                        } else {
-                            result.function.name.clone()
-                        };
+                        let func_name = result.function.qualified_name();
                         println!(
                             "  • {} ({}:{})",
                             func_name,
-                            result.function.file_path.display(),
+                            options.display_path(&result.function.file_path),
                             result.function.line_number
                         );
                         for issue in issues {
@@ -2629,116 +7363,510 @@ fn print_summary(file_results: &[FileResults], file_count: usize, total: usize,
     println!();
 }
 
-fn print_detailed_report(results: &[AnalysisResult]) {
-    println!("═══════════════════════════════════════════════════════════════");
-    println!("                     DETAILED REPORT");
-    println!("═══════════════════════════════════════════════════════════════");
+/// Functions ordered by the input tokens their checks consumed this run, most expensive first.
+/// Functions answered entirely from the cache (no tokens spent) are left out.
+fn top_functions_by_tokens(file_results: &[FileResults], limit: usize) -> Vec<(FunctionInfo, TokenStats)> {
+    let mut ranked: Vec<(FunctionInfo, TokenStats)> = file_results.iter()
+        .flat_map(|fr| fr.results.iter())
+        .map(|result| {
+            let mut stats = TokenStats::default();
+            for check_result in &result.check_results {
+                stats.add(&check_result.stats);
+            }
+            (result.function.clone(), stats)
+        })
+        .filter(|(_, stats)| !stats.is_empty())
+        .collect();
+    ranked.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.input_tokens));
+    ranked.truncate(limit);
+    ranked
+}
+
+/// Table for --top-token-functions: where prompt budget went, to tune skip limits or prompts
+fn print_top_token_functions(ranked: &[(FunctionInfo, TokenStats)], options: &ReportOptions) {
+    const LOCATION_WIDTH: usize = 30;
+    const FUNCTION_WIDTH: usize = 24;
+
+    println!("💸 Top {} function(s) by input tokens:", ranked.len());
+    if ranked.is_empty() {
+        println!("   (no tokens spent; every result came from the cache)\n");
+        return;
+    }
+    println!("{:<lw$}  {:<nw$}  {:>8}  {:>8}  {:>7}",
+        "LOCATION", "FUNCTION", "IN", "OUT", "TIME",
+        lw = LOCATION_WIDTH, nw = FUNCTION_WIDTH);
+    println!("{}", "─".repeat(LOCATION_WIDTH + FUNCTION_WIDTH + 31));
+    for (func, stats) in ranked {
+        let func_name = func.qualified_name();
+        let location = format!("{}:{}", options.display_path(&func.file_path), func.line_number);
+        println!("{:<lw$}  {:<nw$}  {:>8}  {:>8}  {:>6.1}s",
+            truncate_left(&location, LOCATION_WIDTH),
+            truncate_right(&func_name, FUNCTION_WIDTH),
+            stats.input_tokens,
+            stats.output_tokens,
+            stats.generation_time.as_secs_f64(),
+            lw = LOCATION_WIDTH, nw = FUNCTION_WIDTH);
+    }
+    println!();
+}
+
+/// Unified diff body of a stored solution: ```diff blocks as-is, full rewrites diffed
+/// against the original. `None` for prose recommendations.
+fn solution_diff(func: &FunctionInfo, solution: &str) -> Option<String> {
+    let solution = solution.trim();
+    if let Some(body) = solution.strip_prefix("```diff").and_then(|rest| rest.strip_suffix("```")) {
+        let body = body.trim_matches('\n');
+        return (!body.trim().is_empty()).then(|| format!("{}\n", body));
+    }
+    let code = solution.strip_prefix("```python\n")?.strip_suffix("```")?;
+    let diff = generate_diff(&func.source, code);
+    Some(if diff.ends_with('\n') { diff } else { diff + "\n" })
+}
+
+/// `--diff-only`: every accepted fix as a bare diff, no analysis prose
+fn print_diff_only(output: &AnalysisOutput, options: &ReportOptions) {
+    for result in output.file_results.iter().flat_map(|fr| fr.results.iter()) {
+        let func_name = result.function.qualified_name();
+        for issue in result.check_results.iter().filter(|cr| cr.has_issue && cr.rejection.is_none()) {
+            let Some(diff) = issue.solution.as_deref().and_then(|s| solution_diff(&result.function, s)) else {
+                continue;
+            };
+            println!("# {}:{} ({})", options.display_path(&result.function.file_path), func_name, issue.check_key);
+            println!("{}", diff);
+        }
+    }
+}
+
+/// Shorten `text` to `width` characters, keeping the end (file names matter most in paths)
+fn truncate_left(text: &str, width: usize) -> String {
+    let len = text.chars().count();
+    if len <= width {
+        return text.to_string();
+    }
+    let tail: String = text.chars().skip(len - width + 1).collect();
+    format!("…{}", tail)
+}
+
+/// Shorten `text` to `width` characters, keeping the start
+fn truncate_right(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        return text.to_string();
+    }
+    let head: String = text.chars().take(width - 1).collect();
+    format!("{}…", head)
+}
+
+/// Compact 80-column view: one row per function with issues
+fn print_terminal_table(output: &AnalysisOutput, options: &ReportOptions) {
+    const FILE_WIDTH: usize = 24;
+    const LINE_WIDTH: usize = 5;
+    const FUNCTION_WIDTH: usize = 20;
+    const CHECKS_WIDTH: usize = 17;
+    const CONFIDENCE_WIDTH: usize = 5;
+
+    println!("{:<fw$}  {:>lw$}  {:<nw$}  {:<cw$}  {:>pw$}",
+        "FILE", "LINE", "FUNCTION", "CHECKS", "CONF",
+        fw = FILE_WIDTH, lw = LINE_WIDTH, nw = FUNCTION_WIDTH, cw = CHECKS_WIDTH, pw = CONFIDENCE_WIDTH);
+    println!("{}", "─".repeat(FILE_WIDTH + LINE_WIDTH + FUNCTION_WIDTH + CHECKS_WIDTH + CONFIDENCE_WIDTH + 8));
+
+    for file_result in &output.file_results {
+        let path = truncate_left(&options.display_path(&file_result.file_path), FILE_WIDTH);
+        for result in &file_result.results {
+            let issues: Vec<_> = result.check_results.iter().filter(|cr| cr.has_issue).collect();
+            if issues.is_empty() {
+                continue;
+            }
+
+            let func_name = result.function.qualified_name();
+            let checks = issues.iter().map(|cr| cr.check_key.as_str()).collect::<Vec<_>>().join(",");
+            let max_confidence = issues.iter()
+                .map(|cr| extract_confidence_percentage(&cr.analysis))
+                .max()
+                .unwrap_or(0);
+
+            println!("{:<fw$}  {:>lw$}  {:<nw$}  {:<cw$}  {:>pw$}",
+                path,
+                result.function.line_number,
+                truncate_right(&func_name, FUNCTION_WIDTH),
+                truncate_right(&checks, CHECKS_WIDTH),
+                format!("{}%", max_confidence),
+                fw = FILE_WIDTH, lw = LINE_WIDTH, nw = FUNCTION_WIDTH, cw = CHECKS_WIDTH, pw = CONFIDENCE_WIDTH);
+        }
+    }
+
     println!();
+    println!("{} of {} function(s) with issues", output.functions_with_issues, output.total_functions);
+    println!();
+}
+
+fn print_detailed_report(results: &[AnalysisResult], checks: &[CheckConfig], options: &ReportOptions) {
+    print_detailed_report_header();
 
     let results_with_issues: Vec<_> = results.iter()
         .filter(|r| r.check_results.iter().any(|cr| cr.has_issue))
         .collect();
 
     for (idx, result) in results_with_issues.iter().enumerate() {
-        let func_name = if let Some(ref class_name) = result.function.class_name {
-            format!("{}::{}", class_name, result.function.name)
-        } else {
-            result.function.name.clone()
-        };
-        println!("## {} - `{}`", idx + 1, func_name);
+        print_function_report(idx + 1, result, checks, options);
+
+        if idx < results_with_issues.len() - 1 {
+            println!("───────────────────────────────────────────────────────────────");
+            println!();
+        }
+    }
+
+    let rejected = verifier_rejected(results);
+    if !rejected.is_empty() {
+        println!("═══════════════════════════════════════════════════════════════");
         println!();
-        println!("**Location:** `{}:{}`",
-            result.function.file_path.display(),
-            result.function.line_number
-        );
+        println!("## 🚫 Verifier-rejected solutions ({})", rejected.len());
+        println!();
+        for (result, issue, reason) in rejected {
+            let func_name = result.function.qualified_name();
+            println!(
+                "- `{}` ({}:{}) - {}: {}",
+                func_name,
+                options.display_path(&result.function.file_path),
+                result.function.line_number,
+                issue.check_name,
+                reason
+            );
+        }
+        println!();
+    }
+
+    println!("═══════════════════════════════════════════════════════════════");
+    println!();
+    println!("📄 Copy this report to your code review or documentation!");
+    println!();
+}
+
+fn print_detailed_report_header() {
+    println!("═══════════════════════════════════════════════════════════════");
+    println!("                     DETAILED REPORT");
+    println!("═══════════════════════════════════════════════════════════════");
+    println!();
+}
+
+/// One function's block of the detailed report: location, context, highlighted source,
+/// then each issue's analysis and fix. `number` is its position among functions with issues.
+fn print_function_report(number: usize, result: &AnalysisResult, checks: &[CheckConfig], options: &ReportOptions) {
+    let func_name = result.function.qualified_name();
+    println!("## {} - `{}`", number, func_name);
+    println!();
+    println!("**Location:** `{}:{}`",
+        options.display_path(&result.function.file_path),
+        result.function.line_number
+    );
+    println!();
+
+    if let Some(context) = options.context_above(&result.function) {
+        println!("### 📄 Context (lines above)");
         println!();
+        println!("```python");
+        println!("{}", context);
+        println!("```");
+        println!();
+    }
 
+    if !options.redact {
         println!("### 📝 Original Code");
         println!();
-        let highlighted_source = highlight_source_for_issues(&result.function.source, &result.check_results);
+        let highlighted_source = highlight_source_for_issues(&result.function, &result.check_results, checks);
         println!("```python");
         println!("{}", highlighted_source);
         println!("```");
         println!("> Note: lines prefixed with '>>' are suspected hotspots.");
         println!();
+    }
 
-        // Show all issues for this function
-        let issues: Vec<_> = result.check_results.iter().filter(|cr| cr.has_issue).collect();
+    // Show all issues for this function
+    let issues: Vec<_> = result.check_results.iter().filter(|cr| cr.has_issue).collect();
 
-        for (issue_idx, issue) in issues.iter().enumerate() {
-            // Extract confidence from analysis
-            let confidence_pct = extract_confidence_percentage(&issue.analysis);
+    for (issue_idx, issue) in issues.iter().enumerate() {
+        // Extract confidence from analysis
+        let confidence = confidence_label(issue);
 
-            if issues.len() > 1 {
-                println!("### ⚠️ Issue {}: {} (confidence: {}%)", issue_idx + 1, issue.check_name, confidence_pct);
-            } else {
-                println!("### ⚠️ Issue: {} (confidence: {}%)", issue.check_name, confidence_pct);
-            }
+        if issues.len() > 1 {
+            println!("### ⚠️ Issue {}: {} ({})", issue_idx + 1, issue.check_name, confidence);
+        } else {
+            println!("### ⚠️ Issue: {} ({})", issue.check_name, confidence);
+        }
+        println!();
+        if let Some(usage) = issue.stats.usage_label() {
+            println!("**Tokens:** {}", usage);
             println!();
+        }
 
-            if let Some(solution) = &issue.solution {
-                // Show full analysis when we have a solution
-                println!("{}", issue.analysis.trim());
-                println!();
-                println!("### 💡 Suggested Optimization");
+        if let Some(solution) = &issue.solution {
+            // Show full analysis when we have a solution
+            println!("{}", issue.analysis.trim());
+            println!();
+            if !options.redact {
+                println!("### 💡 {}", solution_format_for(checks, &issue.check_key).heading());
                 println!();
                 println!("{}", solution.trim());
                 println!();
+                // Full rewrites already show the whole function
+                let is_diff = solution_format_for(checks, &issue.check_key) == SolutionFormat::Diff;
+                if let Some(code) = options.optimized_code(checks, issue).filter(|_| is_diff) {
+                    println!("### ✨ Optimized Function");
+                    println!();
+                    println!("```python");
+                    println!("{}", code.trim_end());
+                    println!("```");
+                    println!();
+                }
+                if let Some(test) = &issue.test {
+                    println!("### 🧪 Suggested Test");
+                    println!();
+                    println!("```python");
+                    println!("{}", test);
+                    println!("```");
+                    println!();
+                }
+                for (idx, alternative) in issue.alternatives.iter().enumerate() {
+                    println!("### 🔀 Alternative {}", idx + 1);
+                    println!();
+                    println!("{}", alternative.trim());
+                    println!();
+                }
             }
-            // When no solution, just show the simple warning above (no detailed analysis)
-        }
-
-        if idx < results_with_issues.len() - 1 {
-            println!("───────────────────────────────────────────────────────────────");
+        } else if is_detection_only(&issue.analysis) {
+            // Solution phase deliberately skipped: the analysis is all there is
+            println!("{}", issue.analysis.trim());
             println!();
         }
+        // Otherwise (solution rejected), just show the simple warning above (no detailed analysis)
     }
-
-    println!("═══════════════════════════════════════════════════════════════");
-    println!();
-    println!("📄 Copy this report to your code review or documentation!");
-    println!();
 }
 
-fn highlight_source_for_issues(source: &str, check_results: &[CheckResult]) -> String {
-    let mut tokens: Vec<String> = Vec::new();
-    for issue in check_results.iter().filter(|cr| cr.has_issue) {
-        tokens.extend(extract_detail_tokens(&issue.analysis));
-    }
-    tokens.sort();
-    tokens.dedup();
+/// Detail pane of the TUI: location, highlighted source, then each issue's analysis and fix
+fn tui_detail_lines(
+    result: &AnalysisResult,
+    checks: &[CheckConfig],
+    options: &ReportOptions,
+) -> Vec<ratatui::text::Line<'static>> {
+    use ratatui::style::{Color, Modifier, Style};
+    use ratatui::text::Line;
+
+    let heading = Style::default().add_modifier(Modifier::BOLD);
+    let mut lines = vec![
+        Line::styled(
+            format!("{}:{}", options.display_path(&result.function.file_path), result.function.line_number),
+            heading,
+        ),
+        Line::default(),
+    ];
 
-    if tokens.is_empty() {
-        return source.to_string();
+    let highlighted_source = highlight_source_for_issues(&result.function, &result.check_results, checks);
+    for line in highlighted_source.lines() {
+        let style = if line.starts_with(">>") {
+            Style::default().fg(Color::Yellow)
+        } else {
+            Style::default()
+        };
+        lines.push(Line::styled(line.to_string(), style));
     }
 
-    let mut out_lines = Vec::new();
-    for line in source.lines() {
-        if tokens.iter().any(|t| line.contains(t)) {
-            out_lines.push(format!(">> {}", line));
-        } else {
-            out_lines.push(format!("   {}", line));
+    for issue in result.check_results.iter().filter(|cr| cr.has_issue) {
+        lines.push(Line::default());
+        lines.push(Line::styled(
+            format!("⚠️  {} ({})", issue.check_name, confidence_label(issue)),
+            heading.fg(Color::Red),
+        ));
+        lines.push(Line::default());
+        for line in issue.analysis.trim().lines() {
+            lines.push(Line::raw(line.to_string()));
+        }
+        if let Some(solution) = &issue.solution {
+            let format = solution_format_for(checks, &issue.check_key);
+            let is_diff = format == SolutionFormat::Diff;
+            lines.push(Line::default());
+            lines.push(Line::styled(format!("💡 {}", format.heading()), heading));
+            for line in solution.trim().lines() {
+                let style = if is_diff && line.starts_with('+') && !line.starts_with("+++") {
+                    Style::default().fg(Color::Green)
+                } else if is_diff && line.starts_with('-') && !line.starts_with("---") {
+                    Style::default().fg(Color::Red)
+                } else {
+                    Style::default()
+                };
+                lines.push(Line::styled(line.to_string(), style));
+            }
+        }
+        if let Some(test) = &issue.test {
+            lines.push(Line::default());
+            lines.push(Line::styled("🧪 Suggested Test", heading));
+            for line in test.lines() {
+                lines.push(Line::raw(line.to_string()));
+            }
         }
     }
-    out_lines.join("\n")
+
+    lines
 }
 
-fn highlight_source_html(source: &str, check_results: &[CheckResult]) -> String {
+/// Interactive browser over the functions with issues; returns when the user quits
+fn run_tui(results: &[AnalysisResult], checks: &[CheckConfig], options: &ReportOptions) -> Result<()> {
+    use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+    use ratatui::layout::{Constraint, Layout};
+    use ratatui::style::{Modifier, Style};
+    use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph, Wrap};
+
+    let issues: Vec<&AnalysisResult> = results.iter()
+        .filter(|r| r.check_results.iter().any(|cr| cr.has_issue))
+        .collect();
+    if issues.is_empty() {
+        println!("✨ No issues to browse.");
+        return Ok(());
+    }
+
+    let items: Vec<ListItem> = issues.iter()
+        .map(|result| {
+            let func_name = result.function.qualified_name();
+            ListItem::new(format!(
+                "{} ({}:{})",
+                func_name,
+                options.display_path(&result.function.file_path),
+                result.function.line_number
+            ))
+        })
+        .collect();
+    let list = List::new(items)
+        .block(Block::bordered().title(format!(" Functions with issues ({}) ", issues.len())))
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+
+    let mut state = ListState::default().with_selected(Some(0));
+    let mut scroll: u16 = 0;
+    let mut terminal = ratatui::try_init().context("Failed to start the terminal UI")?;
+
+    let outcome = loop {
+        let selected = state.selected().unwrap_or(0);
+        let lines = tui_detail_lines(issues[selected], checks, options);
+        // The detail pane is 65% of the width, minus its borders
+        let detail_width = terminal.size().map(|size| size.width as usize * 65 / 100).unwrap_or(80).saturating_sub(2);
+        let max_scroll = wrapped_line_count(&lines, detail_width).saturating_sub(1).min(u16::MAX as usize) as u16;
+        let detail = Paragraph::new(lines)
+            .block(Block::bordered().title(" ↑/↓ select · PgUp/PgDn scroll · q quit "))
+            .wrap(Wrap { trim: false })
+            .scroll((scroll, 0));
+
+        if let Err(e) = terminal.draw(|frame| {
+            let [list_area, detail_area] =
+                Layout::horizontal([Constraint::Percentage(35), Constraint::Percentage(65)]).areas(frame.area());
+            frame.render_stateful_widget(&list, list_area, &mut state);
+            frame.render_widget(detail, detail_area);
+        }) {
+            break Err(e.into());
+        }
+
+        let key = match event::read() {
+            Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => key,
+            Ok(_) => continue,
+            Err(e) => break Err(e.into()),
+        };
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => break Ok(()),
+            KeyCode::Down | KeyCode::Char('j') => {
+                state.select(Some((selected + 1).min(issues.len() - 1)));
+                scroll = 0;
+            }
+            KeyCode::Up | KeyCode::Char('k') => {
+                state.select(Some(selected.saturating_sub(1)));
+                scroll = 0;
+            }
+            KeyCode::PageDown | KeyCode::Char(' ') => scroll = scroll.saturating_add(10).min(max_scroll),
+            KeyCode::PageUp => scroll = scroll.saturating_sub(10),
+            _ => {}
+        }
+    };
+
+    ratatui::restore();
+    outcome
+}
+
+/// Rows `lines` take when wrapped to `width` columns (an empty line still takes one)
+fn wrapped_line_count(lines: &[ratatui::text::Line], width: usize) -> usize {
+    lines.iter().map(|line| line.width().div_ceil(width.max(1)).max(1)).sum()
+}
+
+/// Collect hotspot tokens from the DETAIL lines of all issues, honoring per-check highlight_regex
+fn collect_hotspot_tokens(check_results: &[CheckResult], checks: &[CheckConfig]) -> Vec<String> {
     let mut tokens: Vec<String> = Vec::new();
     for issue in check_results.iter().filter(|cr| cr.has_issue) {
-        tokens.extend(extract_detail_tokens(&issue.analysis));
+        let highlight_regex = checks.iter()
+            .find(|c| c.key == issue.check_key)
+            .and_then(|c| c.highlight_regex.as_deref());
+        tokens.extend(extract_detail_tokens(&issue.analysis, highlight_regex));
     }
     tokens.sort();
     tokens.dedup();
+    tokens
+}
 
-    if tokens.is_empty() {
-        return escape_html(source);
+/// File lines of all issues' validated `[Lines: ...]` annotations
+fn collect_hotspot_lines(check_results: &[CheckResult]) -> Vec<usize> {
+    let mut lines: Vec<usize> = check_results.iter()
+        .filter(|cr| cr.has_issue)
+        .filter_map(|cr| {
+            let start = cr.analysis.find("[Lines: ")? + "[Lines: ".len();
+            let end = cr.analysis[start..].find(']')? + start;
+            Some(parse_line_list(&cr.analysis[start..end]))
+        })
+        .flatten()
+        .collect();
+    lines.sort_unstable();
+    lines.dedup();
+    lines
+}
+
+/// Hotspot test for each source line: validated LINES when any issue has them,
+/// otherwise DETAIL token matching
+fn hotspot_matcher(func: &FunctionInfo, check_results: &[CheckResult], checks: &[CheckConfig]) -> Option<impl Fn(usize, &str) -> bool> {
+    let lines = collect_hotspot_lines(check_results);
+    let tokens = if lines.is_empty() { collect_hotspot_tokens(check_results, checks) } else { Vec::new() };
+    if lines.is_empty() && tokens.is_empty() {
+        return None;
+    }
+
+    let first_line = func.line_number;
+    Some(move |idx: usize, line: &str| {
+        if lines.is_empty() {
+            tokens.iter().any(|t| line.contains(t))
+        } else {
+            lines.contains(&(first_line + idx))
+        }
+    })
+}
+
+fn highlight_source_for_issues(func: &FunctionInfo, check_results: &[CheckResult], checks: &[CheckConfig]) -> String {
+    let source = &func.source;
+    let Some(is_hotspot) = hotspot_matcher(func, check_results, checks) else {
+        return source.to_string();
+    };
+
+    let mut out_lines = Vec::new();
+    for (idx, line) in source.lines().enumerate() {
+        if is_hotspot(idx, line) {
+            out_lines.push(format!(">> {}", line));
+        } else {
+            out_lines.push(format!("   {}", line));
+        }
     }
+    out_lines.join("\n")
+}
+
+fn highlight_source_html(func: &FunctionInfo, check_results: &[CheckResult], checks: &[CheckConfig]) -> String {
+    let source = &func.source;
+    let Some(is_hotspot) = hotspot_matcher(func, check_results, checks) else {
+        return escape_html(source);
+    };
 
     let mut out_lines = Vec::new();
-    for line in source.lines() {
+    for (idx, line) in source.lines().enumerate() {
         let escaped = escape_html(line);
-        if tokens.iter().any(|t| line.contains(t)) {
+        if is_hotspot(idx, line) {
             out_lines.push(format!(
                 "<span class=\"hotspot\">{}</span>",
                 escaped
@@ -2759,7 +7887,7 @@ fn escape_html(input: &str) -> String {
         .replace('\'', "&#39;")
 }
 
-fn extract_detail_tokens(analysis: &str) -> Vec<String> {
+fn extract_detail_tokens(analysis: &str, highlight_regex: Option<&str>) -> Vec<String> {
     let detail_line = analysis
         .lines()
         .find(|line| line.trim_start().starts_with("DETAIL:"))
@@ -2770,6 +7898,16 @@ fn extract_detail_tokens(analysis: &str) -> Vec<String> {
         return Vec::new();
     }
 
+    // A check-specific pattern replaces the default call/dotted heuristics
+    if let Some(custom_re) = highlight_regex.and_then(|p| Regex::new(p).ok()) {
+        return custom_re
+            .captures_iter(detail_line)
+            .filter_map(|caps| caps.get(1).or_else(|| caps.get(0)))
+            .map(|m| m.as_str().trim().to_string())
+            .filter(|t| !t.is_empty())
+            .collect();
+    }
+
     let mut tokens = Vec::new();
     let call_re = Regex::new(r"[A-Za-z_][A-Za-z0-9_\.]*\s*\([^)]*\)").unwrap();
     let dotted_re = Regex::new(r"[A-Za-z_][A-Za-z0-9_]*\.[A-Za-z0-9_\.]+").unwrap();
@@ -2793,63 +7931,167 @@ fn extract_detail_tokens(analysis: &str) -> Vec<String> {
     tokens
 }
 
-fn write_report_to_file(
-    path: &PathBuf,
-    all_results: &[AnalysisResult],
-    total: usize,
-    functions_with_issues: usize,
+/// Report file name for a source file: its displayed path with separators flattened, plus
+/// a short hash of the path so `pkg/a_b.py` and `pkg_a/b.py` don't overwrite each other
+fn report_file_name(display_path: &str) -> String {
+    let flattened: String = display_path
+        .trim_start_matches(['/', '\\'])
+        .chars()
+        .map(|c| if matches!(c, '/' | '\\' | ':') { '_' } else { c })
+        .collect();
+    let digest = format!("{:x}", Sha256::digest(display_path.as_bytes()));
+    format!("{}-{}.html", flattened, &digest[..8])
+}
+
+/// Percent-encode a file name for use in an href, so `#`, `?` and `%` in a path
+/// don't turn into a fragment, a query or a broken escape
+fn percent_encode(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Write one report per analyzed file into `dir` plus an `index.html` linking them
+fn write_report_dir(
+    dir: &std::path::Path,
+    output: &AnalysisOutput,
     checks: &[CheckConfig],
     cache: &AnalysisCache,
-    no_cache: bool,
-) -> Result<()> {
+    options: &ReportOptions,
+) -> Result<PathBuf> {
     use std::io::Write;
 
-    let mut file = std::fs::File::create(path)?;
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create output directory {}", dir.display()))?;
+
+    let mut entries = Vec::new();
+    for file_result in &output.file_results {
+        let display_path = options.display_path(&file_result.file_path);
+        let file_name = report_file_name(&display_path);
+        let with_issues = file_result
+            .results
+            .iter()
+            .filter(|r| r.check_results.iter().any(|c| c.has_issue))
+            .count();
+        write_report_to_file(
+            &dir.join(&file_name),
+            &file_result.results,
+            file_result.results.len(),
+            with_issues,
+            checks,
+            cache,
+            options,
+        )?;
+        entries.push((display_path, file_name, file_result.results.len(), with_issues));
+    }
 
+    let index_path = dir.join("index.html");
+    let mut file = std::fs::File::create(&index_path)?;
     writeln!(file, "<!doctype html>")?;
     writeln!(file, "<html lang=\"en\">")?;
     writeln!(file, "<head>")?;
     writeln!(file, "  <meta charset=\"utf-8\">")?;
-    writeln!(file, "  <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">")?;
-    writeln!(file, "  <title>LoopSleuth Analysis Report</title>")?;
+    writeln!(file, "  <title>LoopSleuth Analysis Reports</title>")?;
     writeln!(file, "  <style>")?;
     writeln!(file, "    body {{ font-family: -apple-system, BlinkMacSystemFont, Segoe UI, sans-serif; margin: 24px; color: #111; }}")?;
-    writeln!(file, "    h1, h2, h3, h4 {{ margin: 16px 0 8px; }}")?;
+    writeln!(file, "    table {{ border-collapse: collapse; }}")?;
+    writeln!(file, "    th, td {{ text-align: left; padding: 4px 12px; border-bottom: 1px solid #eee; }}")?;
     writeln!(file, "    .meta {{ color: #555; margin-bottom: 16px; }}")?;
-    writeln!(file, "    .summary li {{ margin: 4px 0; }}")?;
-    writeln!(file, "    .issue-list li {{ margin: 4px 0; }}")?;
-    writeln!(file, "    code, pre {{ font-family: ui-monospace, SFMono-Regular, Menlo, monospace; }}")?;
-    writeln!(file, "    pre {{ background: #fafafa; border: 1px solid #eee; padding: 12px; overflow: auto; }}")?;
-    writeln!(file, "    .hotspot {{ background-color: #ffe6e6; }}")?;
-    writeln!(file, "    .note {{ color: #666; font-size: 0.9em; }}")?;
-    writeln!(file, "    hr {{ border: none; border-top: 1px solid #eee; margin: 20px 0; }}")?;
     writeln!(file, "  </style>")?;
     writeln!(file, "</head>")?;
     writeln!(file, "<body>")?;
-
-    writeln!(file, "<h1>LoopSleuth Analysis Report</h1>")?;
+    writeln!(file, "<h1>LoopSleuth Analysis Reports</h1>")?;
     writeln!(
         file,
-        "<div class=\"meta\">Generated: {}</div>",
-        chrono::Local::now().format("%Y-%m-%d %H:%M:%S")
+        "<div class=\"meta\">Generated: {} · {} functions analyzed, {} with issues</div>",
+        chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+        output.total_functions,
+        output.functions_with_issues
     )?;
+    writeln!(file, "<table>")?;
+    writeln!(file, "<tr><th>File</th><th>Functions</th><th>With issues</th></tr>")?;
+    for (display_path, file_name, total, with_issues) in &entries {
+        writeln!(
+            file,
+            "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>",
+            percent_encode(file_name),
+            escape_html(display_path),
+            total,
+            with_issues
+        )?;
+    }
+    writeln!(file, "</table>")?;
+    writeln!(file, "</body>")?;
+    writeln!(file, "</html>")?;
+
+    Ok(index_path)
+}
 
-    writeln!(file, "<h2>Summary</h2>")?;
-    writeln!(file, "<ul class=\"summary\">")?;
-    writeln!(file, "<li><strong>Total functions analyzed:</strong> {}</li>", total)?;
+fn write_report_to_file(
+    path: &std::path::Path,
+    all_results: &[AnalysisResult],
+    total: usize,
+    functions_with_issues: usize,
+    checks: &[CheckConfig],
+    cache: &AnalysisCache,
+    options: &ReportOptions,
+) -> Result<()> {
+    use std::fmt::Write;
+
+    let mut styles = String::new();
+    writeln!(styles, "  <style>")?;
+    writeln!(styles, "    body {{ font-family: -apple-system, BlinkMacSystemFont, Segoe UI, sans-serif; margin: 24px; color: #111; }}")?;
+    writeln!(styles, "    h1, h2, h3, h4 {{ margin: 16px 0 8px; }}")?;
+    writeln!(styles, "    .meta {{ color: #555; margin-bottom: 16px; }}")?;
+    writeln!(styles, "    .summary li {{ margin: 4px 0; }}")?;
+    writeln!(styles, "    .issue-list li {{ margin: 4px 0; }}")?;
+    writeln!(styles, "    code, pre {{ font-family: ui-monospace, SFMono-Regular, Menlo, monospace; }}")?;
+    writeln!(styles, "    pre {{ background: #fafafa; border: 1px solid #eee; padding: 12px; overflow: auto; }}")?;
+    writeln!(styles, "    .hotspot {{ background-color: #ffe6e6; }}")?;
+    writeln!(styles, "    .note {{ color: #666; font-size: 0.9em; }}")?;
+    writeln!(styles, "    hr {{ border: none; border-top: 1px solid #eee; margin: 20px 0; }}")?;
+    writeln!(styles, "    details.finding {{ border: 1px solid #eee; border-radius: 4px; margin: 8px 0; padding: 0 12px; }}")?;
+    writeln!(styles, "    details.finding > summary {{ cursor: pointer; padding: 8px 0; }}")?;
+    writeln!(styles, "    details.finding > summary .checks {{ color: #a00; }}")?;
+    writeln!(styles, "    .toggle-all {{ margin: 8px 0; }}")?;
+    writeln!(styles, "    .recommendation {{ white-space: pre-wrap; margin: 8px 0; }}")?;
+    writeln!(styles, "  </style>")?;
+    // Inline so the report stays a single offline file
+    writeln!(styles, "  <script>")?;
+    writeln!(styles, "    function setAllFindings(open) {{")?;
+    writeln!(styles, "      document.querySelectorAll('details.finding').forEach(function (d) {{ d.open = open; }});")?;
+    writeln!(styles, "    }}")?;
+    writeln!(styles, "    function openLinkedFinding() {{")?;
+    writeln!(styles, "      var target = location.hash && document.getElementById(location.hash.slice(1));")?;
+    writeln!(styles, "      if (target && target.tagName === 'DETAILS') {{ target.open = true; }}")?;
+    writeln!(styles, "    }}")?;
+    writeln!(styles, "    window.addEventListener('hashchange', openLinkedFinding);")?;
+    writeln!(styles, "    window.addEventListener('DOMContentLoaded', openLinkedFinding);")?;
+    writeln!(styles, "  </script>")?;
+
+    let mut summary = String::new();
+    writeln!(summary, "<h2>Summary</h2>")?;
+    writeln!(summary, "<ul class=\"summary\">")?;
+    writeln!(summary, "<li><strong>Total functions analyzed:</strong> {}</li>", total)?;
     writeln!(
-        file,
+        summary,
         "<li><strong>Checks run:</strong> {} ({})</li>",
         checks.len(),
         checks.iter().map(|c| c.key.clone()).collect::<Vec<_>>().join(", ")
     )?;
-    writeln!(file, "<li><strong>Functions with issues:</strong> {}</li>", functions_with_issues)?;
-    writeln!(file, "<li><strong>Functions clean:</strong> {}</li>", total - functions_with_issues)?;
-    if !no_cache {
+    writeln!(summary, "<li><strong>Functions with issues:</strong> {}</li>", functions_with_issues)?;
+    writeln!(summary, "<li><strong>Functions clean:</strong> {}</li>", total - functions_with_issues)?;
+    if !options.no_cache {
         if let Ok((cache_total, cache_with_issues)) = cache.stats() {
             if cache_total > 0 {
                 writeln!(
-                    file,
+                    summary,
                     "<li><strong>Cache entries:</strong> {} total, {} with issues</li>",
                     cache_total,
                     cache_with_issues
@@ -2857,111 +8099,419 @@ fn write_report_to_file(
             }
         }
     }
-    writeln!(file, "</ul>")?;
+    writeln!(summary, "</ul>")?;
 
+    let mut issues_html = String::new();
     if functions_with_issues > 0 {
-        writeln!(file, "<h2>Issues Detected</h2>")?;
-        writeln!(file, "<ul class=\"issue-list\">")?;
+        writeln!(issues_html, "<h2>Issues Detected</h2>")?;
+        writeln!(issues_html, "<ul class=\"issue-list\">")?;
 
-        for result in all_results.iter() {
+        let results_with_issues: Vec<_> = all_results
+            .iter()
+            .filter(|r| r.check_results.iter().any(|cr| cr.has_issue))
+            .collect();
+
+        for (idx, result) in results_with_issues.iter().enumerate() {
             let issues: Vec<_> = result.check_results.iter().filter(|cr| cr.has_issue).collect();
             if !issues.is_empty() {
-                let func_name = if let Some(ref class_name) = result.function.class_name {
-                    format!("{}::{}", class_name, result.function.name)
-                } else {
-                    result.function.name.clone()
-                };
+                let func_name = result.function.qualified_name();
                 writeln!(
-                    file,
-                    "<li><code>{}</code> ({}:{})",
+                    issues_html,
+                    "<li><a href=\"#finding-{}\"><code>{}</code></a> ({}:{})",
+                    idx + 1,
                     escape_html(&func_name),
-                    escape_html(&result.function.file_path.display().to_string()),
+                    escape_html(&options.display_path(&result.function.file_path)),
                     result.function.line_number
                 )?;
-                writeln!(file, "<ul>")?;
+                writeln!(issues_html, "<ul>")?;
                 for issue in issues {
-                    writeln!(file, "<li>{}</li>", escape_html(&issue.check_name))?;
+                    writeln!(issues_html, "<li>{}</li>", escape_html(&issue.check_name))?;
                 }
-                writeln!(file, "</ul></li>")?;
+                writeln!(issues_html, "</ul></li>")?;
             }
         }
-        writeln!(file, "</ul>")?;
-
-        writeln!(file, "<hr>")?;
-        writeln!(file, "<h2>Detailed Analysis</h2>")?;
-
-        let results_with_issues: Vec<_> = all_results
-            .iter()
-            .filter(|r| r.check_results.iter().any(|cr| cr.has_issue))
-            .collect();
+        writeln!(issues_html, "</ul>")?;
+
+        writeln!(issues_html, "<hr>")?;
+        writeln!(issues_html, "<h2>Detailed Analysis</h2>")?;
+        writeln!(
+            issues_html,
+            "<div class=\"toggle-all\"><button type=\"button\" onclick=\"setAllFindings(true)\">Expand all</button> \
+             <button type=\"button\" onclick=\"setAllFindings(false)\">Collapse all</button></div>"
+        )?;
 
         for (idx, result) in results_with_issues.iter().enumerate() {
-            let func_name = if let Some(ref class_name) = result.function.class_name {
-                format!("{}::{}", class_name, result.function.name)
-            } else {
-                result.function.name.clone()
-            };
-            writeln!(file, "<h3>{} - <code>{}</code></h3>", idx + 1, escape_html(&func_name))?;
+            let func_name = result.function.qualified_name();
+            let check_names: Vec<&str> = result.check_results.iter()
+                .filter(|cr| cr.has_issue)
+                .map(|cr| cr.check_name.as_str())
+                .collect();
+            writeln!(issues_html, "<details class=\"finding\" id=\"finding-{}\">", idx + 1)?;
             writeln!(
-                file,
-                "<div><strong>Location:</strong> <code>{}:{}</code></div>",
-                escape_html(&result.function.file_path.display().to_string()),
-                result.function.line_number
+                issues_html,
+                "<summary><strong>{}. <code>{}</code></strong> &mdash; <code>{}:{}</code> &mdash; <span class=\"checks\">{}</span></summary>",
+                idx + 1,
+                escape_html(&func_name),
+                escape_html(&options.display_path(&result.function.file_path)),
+                result.function.line_number,
+                escape_html(&check_names.join(", "))
             )?;
-            writeln!(file, "<h4>Original Code</h4>")?;
-            let highlighted_html = highlight_source_html(&result.function.source, &result.check_results);
-            writeln!(file, "<pre><code class=\"language-python\">{}</code></pre>", highlighted_html)?;
-            writeln!(file, "<div class=\"note\">Lines with light red background are suspected hotspots.</div>")?;
+            if let Some(context) = options.context_above(&result.function) {
+                writeln!(issues_html, "<h4>Context (lines above)</h4>")?;
+                writeln!(issues_html, "<pre><code class=\"language-python\">{}</code></pre>", escape_html(context))?;
+            }
+            if !options.redact {
+                writeln!(issues_html, "<h4>Original Code</h4>")?;
+                let highlighted_html = highlight_source_html(&result.function, &result.check_results, checks);
+                writeln!(issues_html, "<pre><code class=\"language-python\">{}</code></pre>", highlighted_html)?;
+                writeln!(issues_html, "<div class=\"note\">Lines with light red background are suspected hotspots.</div>")?;
+            }
 
             let issues: Vec<_> = result.check_results.iter().filter(|cr| cr.has_issue).collect();
             for (issue_idx, issue) in issues.iter().enumerate() {
-                let confidence_pct = extract_confidence_percentage(&issue.analysis);
+                let confidence = confidence_label(issue);
                 if issues.len() > 1 {
                     writeln!(
-                        file,
-                        "<h4>Issue {}: {} (confidence: {}%)</h4>",
+                        issues_html,
+                        "<h4>Issue {}: {} ({})</h4>",
                         issue_idx + 1,
                         escape_html(&issue.check_name),
-                        confidence_pct
+                        confidence
                     )?;
                 } else {
                     writeln!(
-                        file,
-                        "<h4>Issue: {} (confidence: {}%)</h4>",
+                        issues_html,
+                        "<h4>Issue: {} ({})</h4>",
                         escape_html(&issue.check_name),
-                        confidence_pct
+                        confidence
                     )?;
                 }
+                if let Some(usage) = issue.stats.usage_label() {
+                    writeln!(issues_html, "<div class=\"note\">Tokens: {}</div>", usage)?;
+                }
 
                 if let Some(solution) = &issue.solution {
-                    writeln!(file, "<div><pre><code>{}</code></pre></div>", escape_html(issue.analysis.trim()))?;
-                    writeln!(file, "<h4>Suggested Optimization</h4>")?;
-                    writeln!(file, "<div><pre><code>{}</code></pre></div>", escape_html(solution.trim()))?;
+                    writeln!(issues_html, "<div><pre><code>{}</code></pre></div>", escape_html(issue.analysis.trim()))?;
+                    if !options.redact {
+                        let format = solution_format_for(checks, &issue.check_key);
+                        writeln!(issues_html, "<h4>{}</h4>", format.heading())?;
+                        match format {
+                            SolutionFormat::Prose => writeln!(
+                                issues_html,
+                                "<div class=\"recommendation\">{}</div>",
+                                escape_html(solution.trim())
+                            )?,
+                            SolutionFormat::Diff | SolutionFormat::Full => writeln!(
+                                issues_html,
+                                "<div><pre><code>{}</code></pre></div>",
+                                escape_html(solution.trim())
+                            )?,
+                        }
+                        if let Some(code) = options.optimized_code(checks, issue).filter(|_| format == SolutionFormat::Diff) {
+                            writeln!(issues_html, "<h4>Optimized Function</h4>")?;
+                            writeln!(
+                                issues_html,
+                                "<div><pre><code class=\"language-python\">{}</code></pre></div>",
+                                escape_html(code.trim_end())
+                            )?;
+                        }
+                        if let Some(test) = &issue.test {
+                            writeln!(issues_html, "<h4>Suggested Test</h4>")?;
+                            writeln!(
+                                issues_html,
+                                "<div><pre><code class=\"language-python\">{}</code></pre></div>",
+                                escape_html(test)
+                            )?;
+                        }
+                        for (idx, alternative) in issue.alternatives.iter().enumerate() {
+                            writeln!(issues_html, "<h4>Alternative {}</h4>", idx + 1)?;
+                            writeln!(
+                                issues_html,
+                                "<div><pre><code>{}</code></pre></div>",
+                                escape_html(alternative.trim())
+                            )?;
+                        }
+                    }
+                } else if is_detection_only(&issue.analysis) {
+                    writeln!(issues_html, "<div><pre><code>{}</code></pre></div>", escape_html(issue.analysis.trim()))?;
                 }
             }
 
-            if idx < results_with_issues.len() - 1 {
-                writeln!(file, "<hr>")?;
+            writeln!(issues_html, "</details>")?;
+        }
+
+        let rejected = verifier_rejected(all_results);
+        if !rejected.is_empty() {
+            writeln!(issues_html, "<hr>")?;
+            writeln!(issues_html, "<h2>Verifier-rejected solutions</h2>")?;
+            writeln!(issues_html, "<ul class=\"issue-list\">")?;
+            for (result, issue, reason) in rejected {
+                let func_name = result.function.qualified_name();
+                writeln!(
+                    issues_html,
+                    "<li><code>{}</code> ({}:{}) &mdash; {}: {}</li>",
+                    escape_html(&func_name),
+                    escape_html(&options.display_path(&result.function.file_path)),
+                    result.function.line_number,
+                    escape_html(&issue.check_name),
+                    escape_html(reason)
+                )?;
             }
+            writeln!(issues_html, "</ul>")?;
         }
     }
 
-    writeln!(file, "<hr>")?;
-    writeln!(file, "<div class=\"note\">Generated by LoopSleuth</div>")?;
-    writeln!(file, "</body>")?;
-    writeln!(file, "</html>")?;
+    let mut footer = String::new();
+    match &options.manifest_hash {
+        Some(hash) => writeln!(
+            footer,
+            "<div class=\"note\">Generated by LoopSleuth {} · run manifest <code>{}</code></div>",
+            env!("CARGO_PKG_VERSION"),
+            escape_html(hash)
+        )?,
+        None => writeln!(footer, "<div class=\"note\">Generated by LoopSleuth</div>")?,
+    }
 
-    Ok(())
+    let generated = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    let template = options.report_template.as_deref().unwrap_or(DEFAULT_REPORT_TEMPLATE);
+    let html = fill_report_template(template, &[
+        ("styles", styles.as_str()),
+        ("generated", generated.as_str()),
+        ("summary", summary.as_str()),
+        ("issues", issues_html.as_str()),
+        ("footer", footer.as_str()),
+    ]);
+    write_report_file(path, &html)
+}
+
+/// --live-report: every analyzed function is appended to a JSON Lines store next to the HTML
+/// report, which is regenerated from the store whenever a new finding arrives
+struct LiveReport<'a> {
+    store: fs::File,
+    html_path: PathBuf,
+    options: &'a ReportOptions,
+    results: Vec<AnalysisResult>,
+}
+
+/// One line of the live report store; FunctionInfo doesn't serialize the source the report shows
+#[derive(Serialize, Deserialize)]
+struct StoredResult {
+    source: String,
+    result: AnalysisResult,
+}
+
+impl<'a> LiveReport<'a> {
+    /// Start an empty store for the report at `html_path`
+    fn new(html_path: &std::path::Path, options: &'a ReportOptions) -> Result<Self> {
+        let mut store_path = html_path.as_os_str().to_os_string();
+        store_path.push(".results.jsonl");
+        let store_path = PathBuf::from(store_path);
+        let store = fs::File::create(&store_path)
+            .with_context(|| format!("Failed to create results store: {}", store_path.display()))?;
+        Ok(LiveReport { store, html_path: html_path.to_path_buf(), options, results: Vec::new() })
+    }
+
+    /// Record a result in memory and append its line to the store
+    fn append(&mut self, result: &AnalysisResult) -> Result<()> {
+        use std::io::Write;
+
+        let line = serde_json::to_string(&StoredResult { source: result.function.source.clone(), result: result.clone() })?;
+        writeln!(self.store, "{}", line).context("Failed to append to results store")?;
+        self.results.push(result.clone());
+        Ok(())
+    }
+
+    /// Regenerate the HTML from the accumulated results. Written beside the report and renamed
+    /// into place, so a browser reloading mid-run never sees a half-written file
+    fn write(&self, checks: &[CheckConfig], cache: &AnalysisCache) -> Result<()> {
+        let functions_with_issues = self.results.iter()
+            .filter(|r| r.check_results.iter().any(|cr| cr.has_issue))
+            .count();
+        let mut tmp_path = self.html_path.as_os_str().to_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        write_report_to_file(&tmp_path, &self.results, self.results.len(), functions_with_issues, checks, cache, self.options)?;
+        fs::rename(&tmp_path, &self.html_path)
+            .with_context(|| format!("Failed to replace report: {}", self.html_path.display()))
+    }
+}
+
+/// `--output` path to write: with --compress, `.gz` is appended unless already there
+fn report_output_path(path: &std::path::Path, compress: bool) -> PathBuf {
+    if compress && !is_gzip_path(path) {
+        let mut name = path.as_os_str().to_os_string();
+        name.push(".gz");
+        PathBuf::from(name)
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// Whether a report path names a gzip file (`.gz`)
+fn is_gzip_path(path: &std::path::Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("gz"))
+}
+
+/// Write a report file, gzip-compressing it when the path ends in `.gz`
+fn write_report_file(path: &std::path::Path, contents: &str) -> Result<()> {
+    use std::io::Write;
+
+    let write = || -> std::io::Result<()> {
+        if !is_gzip_path(path) {
+            return fs::write(path, contents);
+        }
+        let mut encoder = flate2::write::GzEncoder::new(fs::File::create(path)?, flate2::Compression::default());
+        encoder.write_all(contents.as_bytes())?;
+        encoder.finish()?;
+        Ok(())
+    };
+    write().with_context(|| format!("Failed to write report: {}", path.display()))
+}
+
+/// Built-in HTML skeleton; `--report-template` files use the same `{{placeholders}}`
+const DEFAULT_REPORT_TEMPLATE: &str = r#"<!doctype html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <meta name="viewport" content="width=device-width, initial-scale=1">
+  <title>LoopSleuth Analysis Report</title>
+{{styles}}</head>
+<body>
+<h1>LoopSleuth Analysis Report</h1>
+<div class="meta">Generated: {{generated}}</div>
+{{summary}}{{issues}}<hr>
+{{footer}}</body>
+</html>
+"#;
+
+/// Substitute `{{name}}` placeholders in one pass, so text inside the inserted sections
+/// (e.g. analyzed source code) is never expanded. Unknown placeholders are left as-is.
+fn fill_report_template(template: &str, sections: &[(&str, &str)]) -> String {
+    let placeholder = Regex::new(r"\{\{\s*([a-z_]+)\s*\}\}").unwrap();
+    placeholder
+        .replace_all(template, |caps: &regex::Captures| {
+            sections.iter()
+                .find(|(name, _)| *name == &caps[1])
+                .map(|(_, value)| value.to_string())
+                .unwrap_or_else(|| caps[0].to_string())
+        })
+        .into_owned()
 }
 
 #[cfg(test)]
 mod tests {
     use super::{
+        api_client,
+        apply_config_override,
+        apply_config_settings,
+        apply_template_expansion,
+        blended_confidence_analysis,
+        check_keys_from_file_contents,
+        check_selector_matches,
+        codeclimate_issues,
+        collapse_similar_findings,
+        config_problems,
+        context_size_warnings,
+        count_lines_to_offset,
+        dedupe_check_results,
+        ensure_network_allowed,
+        display_path,
+        draw_seed,
+        effective_config_toml,
         embedding_equality_scan_detail,
+        extract_confidence_percentage,
+        extract_detail_tokens,
+        extract_functions,
+        extract_functions_from_source,
+        extract_test_snippet,
+        fill_report_template,
+        finding_id,
+        format_eta,
+        generate_diff,
+        get_default_config_toml,
+        guard_skip_reason,
         has_explicit_token_dimension_loop,
         has_embedding_equality_scan,
         has_mask_built_inside_layer_loop,
+        is_async_def,
+        is_below_confidence,
+        is_detection_only,
+        is_prefiltered,
+        is_stub_body,
+        is_test_file,
+        lines_above,
+        majority_verdict,
+        missing_placeholders,
+        module_path_for,
+        optimized_code_from_solution,
+        override_prompt,
+        parse_json_detection,
+        parse_line_list,
+        parse_line_range,
+        parse_ratio,
+        parse_verification_result,
+        parse_yes_no_detection,
+        percent_encode,
+        rank_solution_candidates,
+        report_file_name,
+        report_findings,
+        report_output_path,
+        resolve_batch_size,
+        sample_indices,
+        skips_json,
+        slow_generation_error,
+        solution_diff,
+        stabilized_detection,
+        summary_json,
+        tally_detections,
+        template_prefix,
+        top_functions_by_tokens,
+        truncate_left,
+        truncate_right,
+        unescape_inline_code,
+        validated_detection_lines,
+        verifier_rejected,
+        verifier_rejection,
+        wrapped_line_count,
+        write_report_file,
+        AnalysisCache,
+        AnalysisOutput,
+        AnalysisResult,
+        CheckConfig,
+        CheckResult,
+        CheckScope,
+        ChecksConfig,
+        Cli,
+        ConfigFormat,
+        DEFAULT_BATCH_SIZE,
+        DEFAULT_TEST_PATTERNS,
+        DedupeRule,
+        DedupeStrategy,
+        DetectionFormat,
+        FileResults,
+        FunctionDirectives,
+        FunctionInfo,
+        LiveReport,
+        ProgressStyle,
+        Prompt,
+        Rejection,
+        RejectionStage,
+        ReportOptions,
+        Skip,
+        SkipStage,
+        SolutionFormat,
+        SourceOrigin,
+        StopReason,
+        TokenStats,
+        EXTRACT_FAILURE_REASON,
+        INLINE_PATH,
+        MIN_THROUGHPUT_WARMUP,
+        NO_SOLUTION_DIRECTIVE_NOTE,
+        NO_SOLUTION_NOTE,
+        SOLUTIONS_FOR_NOTE,
     };
+    use rustpython_ast::{Mod, Stmt};
+    use rustpython_parser::{parse, Mode};
 
     #[test]
     fn token_loop_filter_accepts_explicit_seq_len_loop() {
@@ -3136,4 +8686,1501 @@ def forward(self, input_ids):
         assert!(!has_embedding_equality_scan(source));
         assert!(embedding_equality_scan_detail(source).is_none());
     }
+
+    #[test]
+    fn detail_tokens_use_custom_highlight_regex() {
+        let analysis = "VERDICT: GROWING_CONTAINER\nCONFIDENCE: 0.80\nDETAIL: result.append in nested loop\nEND";
+
+        let default_tokens = extract_detail_tokens(analysis, None);
+        assert!(default_tokens.contains(&"result.append".to_string()));
+
+        let custom_tokens = extract_detail_tokens(analysis, Some(r"\b(append)\b"));
+        assert_eq!(custom_tokens, vec!["append".to_string()]);
+    }
+
+    #[test]
+    fn stub_body_detection_skips_only_trivial_functions() {
+        let source = r#"
+def ellipsis(x): ...
+
+def passes(x):
+    """Docstring only."""
+    pass
+
+def abstract(x):
+    raise NotImplementedError("subclass me")
+
+def real(x):
+    return [i for i in x]
+"#;
+        let Mod::Module(module) = parse(source, Mode::Module, "<test>").unwrap() else {
+            panic!("expected module");
+        };
+        let stubs: Vec<bool> = module.body.iter().map(|stmt| match stmt {
+            Stmt::FunctionDef(f) => is_stub_body(&f.body),
+            _ => panic!("expected function"),
+        }).collect();
+
+        assert_eq!(stubs, vec![true, true, true, false]);
+    }
+
+    #[test]
+    fn context_size_warnings_flag_clamped_and_untrained_contexts() {
+        assert!(context_size_warnings(4096, 4096, 32768).is_empty());
+        assert!(context_size_warnings(4096, 4096, 0).is_empty());
+
+        let clamped = context_size_warnings(65536, 32768, 32768);
+        assert_eq!(clamped.len(), 2);
+        assert!(clamped[0].contains("only has 32768 tokens"));
+        assert!(clamped[1].contains("trained with"));
+    }
+
+    #[test]
+    fn batch_size_is_capped_at_the_context_size() {
+        assert_eq!(resolve_batch_size(512, 4096), (512, None));
+        // The default only warns when the user lowered the context
+        assert_eq!(resolve_batch_size(DEFAULT_BATCH_SIZE, 2048), (2048, None));
+        let (size, warning) = resolve_batch_size(8192, 4096);
+        assert_eq!(size, 4096);
+        assert!(warning.unwrap().contains("exceeds --context-size 4096"));
+    }
+
+    #[test]
+    fn eta_formats_hours_minutes_and_seconds() {
+        use std::time::Duration;
+
+        assert_eq!(format_eta(Duration::from_secs(35)), "35s");
+        assert_eq!(format_eta(Duration::from_secs(260)), "4m20s");
+        assert_eq!(format_eta(Duration::from_secs(3900)), "1h05m");
+    }
+
+    #[test]
+    fn progress_bar_honors_style_and_shrinks_to_fit() {
+        let unicode = ProgressStyle { width: 10, ascii: false };
+        assert_eq!(unicode.render(5, 10, 40, None), "[█████░░░░░]");
+
+        let ascii = ProgressStyle { width: 30, ascii: true };
+        assert_eq!(ascii.render(1, 2, 40, Some(200)).len(), 32);
+        // 60 columns leave 18 for the bar after the brackets and 40 characters of status
+        assert_eq!(ascii.render(1, 2, 40, Some(60)), format!("[{}{}]", "#".repeat(9), "-".repeat(9)));
+        assert_eq!(ascii.render(1, 2, 40, Some(20)).len(), ProgressStyle::MIN_WIDTH + 2);
+    }
+
+    #[test]
+    fn dedupe_highest_confidence_keeps_best_in_group() {
+        let result = |key: &str, confidence: f32| CheckResult {
+            check_key: key.to_string(),
+            check_name: key.to_string(),
+            has_issue: true,
+            analysis: format!("VERDICT: X\n[Confidence: {:.2}]", confidence),
+            ..Default::default()
+        };
+        let rules = vec![DedupeRule {
+            strategy: DedupeStrategy::HighestConfidence,
+            group: vec!["quadratic".to_string(), "linear-in-loop".to_string()],
+            ..Default::default()
+        }];
+
+        let kept = dedupe_check_results(
+            vec![result("quadratic", 0.60), result("linear-in-loop", 0.90), result("unbounded-alloc", 0.50)],
+            &rules,
+        );
+        let keys: Vec<&str> = kept.iter().map(|r| r.check_key.as_str()).collect();
+
+        assert_eq!(keys, vec!["linear-in-loop", "unbounded-alloc"]);
+    }
+
+    #[test]
+    fn top_token_functions_skip_cached_and_rank_by_input() {
+        use std::path::PathBuf;
+
+        let analyzed = |name: &str, input_tokens: &[usize]| AnalysisResult {
+            function: FunctionInfo {
+                name: name.to_string(),
+                file_path: PathBuf::from("t.py"),
+                line_number: 1,
+                ..Default::default()
+            },
+            check_results: input_tokens.iter().map(|&tokens| CheckResult {
+                check_key: "quadratic".to_string(),
+                check_name: "Quadratic".to_string(),
+                has_issue: false,
+                analysis: String::new(),
+                stats: TokenStats::new(tokens, 10, Default::default()),
+                ..Default::default()
+            }).collect(),
+        };
+        let cached = AnalysisResult {
+            check_results: vec![],
+            ..analyzed("cached", &[])
+        };
+        let file_results = vec![FileResults {
+            file_path: PathBuf::from("t.py"),
+            results: vec![analyzed("small", &[100]), cached, analyzed("big", &[300, 200]), analyzed("mid", &[400])],
+        }];
+
+        let ranked = top_functions_by_tokens(&file_results, 2);
+        let names: Vec<(&str, usize)> = ranked.iter().map(|(f, s)| (f.name.as_str(), s.input_tokens)).collect();
+        assert_eq!(names, vec![("big", 500), ("mid", 400)]);
+    }
+
+    #[test]
+    fn solution_diff_handles_diff_full_and_prose_solutions() {
+        use std::path::PathBuf;
+
+        let func = FunctionInfo {
+            name: "f".to_string(),
+            source: "def f(xs):\n    return [x for x in xs if x in xs]\n".to_string(),
+            file_path: PathBuf::from("t.py"),
+            line_number: 1,
+            ..Default::default()
+        };
+
+        let diff = "```diff\n def f(xs):\n-    return 1\n+    return 2\n\n```";
+        assert_eq!(solution_diff(&func, diff).unwrap(), " def f(xs):\n-    return 1\n+    return 2\n");
+
+        let full = "```python\ndef f(xs):\n    return list(xs)\n```";
+        assert_eq!(
+            solution_diff(&func, full).unwrap(),
+            " def f(xs):\n-    return [x for x in xs if x in xs]\n+    return list(xs)\n"
+        );
+
+        assert_eq!(solution_diff(&func, "Use a set for membership tests."), None);
+
+        // Solutions cached as diffs aren't served once a check asks for full rewrites
+        let config: ChecksConfig = toml::from_str(get_default_config_toml()).unwrap();
+        let mut check = config.check[0].clone();
+        let diff_hash = check.prompt_hash();
+        check.solution_format = SolutionFormat::Full;
+        assert_ne!(check.prompt_hash(), diff_hash);
+    }
+
+    #[test]
+    fn skips_are_reported_per_file_function_and_check() {
+        use std::path::{Path, PathBuf};
+
+        let func = FunctionInfo {
+            name: "run".to_string(),
+            file_path: PathBuf::from("src/pkg/mod.py"),
+            line_number: 12,
+            class_name: Some("Model".to_string()),
+            ..Default::default()
+        };
+        let options = ReportOptions {
+            no_cache: true,
+            manifest_hash: None,
+            relative_to: Some(PathBuf::from("src")),
+            finding_root: None,
+            redact: false,
+            report_template: None,
+            emit_optimized: false,
+        };
+        let skips = [
+            Skip::file(Path::new("src/legacy.py"), SkipStage::Parse, "invalid syntax"),
+            Skip::check(&func, "quadratic", SkipStage::Guard, "guard require_any missing"),
+        ];
+
+        assert_eq!(skips_json(&skips, &options), serde_json::json!([
+            {"file": "legacy.py", "function": null, "line_number": null, "check": null,
+             "stage": "parse", "reason": "invalid syntax"},
+            {"file": "pkg/mod.py", "function": "Model::run", "line_number": 12, "check": "quadratic",
+             "stage": "guard", "reason": "guard require_any missing"},
+        ]));
+    }
+
+    #[test]
+    fn similar_findings_collapse_into_the_most_confident() {
+        let result = |key: &str, confidence: f32, detail: &str| CheckResult {
+            check_key: key.to_string(),
+            check_name: key.to_string(),
+            has_issue: true,
+            analysis: format!("VERDICT: X\nDETAIL: {}\n[Confidence: {:.2}]", detail, confidence),
+            solution: (key == "linear-in-loop").then(|| "```diff\n+fix\n```".to_string()),
+            ..Default::default()
+        };
+
+        let kept = collapse_similar_findings(vec![
+            result("linear-in-loop", 0.60, "nested loop scans items for each item"),
+            result("quadratic", 0.90, "Nested loop scans `items` for each item"),
+            result("string-concat", 0.80, "string built with += inside the loop"),
+        ], 0.8);
+        let keys: Vec<&str> = kept.iter().map(|r| r.check_key.as_str()).collect();
+
+        assert_eq!(keys, vec!["quadratic", "string-concat"]);
+        assert!(kept[0].analysis.ends_with("[Related: linear-in-loop]"));
+        assert!(kept[0].solution.is_some());
+        assert!(!kept[1].analysis.contains("[Related:"));
+
+        assert_eq!(parse_ratio("0.8"), Ok(0.8));
+        assert!(parse_ratio("1.5").is_err());
+        assert!(parse_ratio("-0.1").is_err());
+    }
+
+    #[test]
+    fn wrapped_line_count_counts_wrapped_rows() {
+        use ratatui::text::Line;
+
+        let lines = [Line::from("x".repeat(25)), Line::from(""), Line::from("short")];
+        assert_eq!(wrapped_line_count(&lines, 10), 5);
+        assert_eq!(wrapped_line_count(&lines, 0), 31);
+    }
+
+    #[test]
+    fn report_template_placeholders_expand_once() {
+        let html = fill_report_template(
+            "<main>{{ summary }}{{issues}}</main>{{unknown}}",
+            &[("summary", "<p>f-string: {{issues}}</p>"), ("issues", "<ol></ol>")],
+        );
+        assert_eq!(html, "<main><p>f-string: {{issues}}</p><ol></ol></main>{{unknown}}");
+    }
+
+    #[test]
+    fn display_path_strips_relative_base() {
+        use std::path::Path;
+
+        assert_eq!(display_path(Path::new("./src/pkg/mod.py"), Some(Path::new("./src"))), "pkg/mod.py");
+        assert_eq!(display_path(Path::new("/elsewhere/mod.py"), Some(Path::new("/repo"))), "/elsewhere/mod.py");
+        assert_eq!(display_path(Path::new("src/mod.py"), None), "src/mod.py");
+    }
+
+    #[test]
+    fn check_keys_file_ignores_comments_and_blank_lines() {
+        let contents = "# curated CI list\nquadratic\n\n  string-concat  # hot path\nlinear-in-loop\n";
+        assert_eq!(
+            check_keys_from_file_contents(contents),
+            "quadratic,string-concat,linear-in-loop"
+        );
+    }
+
+    #[test]
+    fn verifier_confidence_is_parsed_and_optional() {
+        let result = parse_verification_result("VERDICT: VALID\nCONFIDENCE: 0.72\nREASON: hoists lookup\nEND");
+        assert!(result.is_valid);
+        assert_eq!(result.confidence, Some(0.72));
+
+        let result = parse_verification_result("VERDICT: INVALID\nREASON: signature changed\nEND");
+        assert!(!result.is_valid);
+        assert_eq!(result.confidence, None);
+    }
+
+    #[test]
+    fn init_context_goes_before_the_configured_assistant_marker() {
+        use std::path::PathBuf;
+
+        let config: ChecksConfig = toml::from_str(get_default_config_toml()).unwrap();
+        let mut check = config.check[0].clone();
+        check.detection_prompt = "<start_of_turn>user\nCheck {function_source}<end_of_turn>\n<start_of_turn>model\n".to_string();
+        check.assistant_marker = Some("<start_of_turn>model".to_string());
+        let func = FunctionInfo {
+            name: "__init__".to_string(),
+            source_no_docstring: "def __init__(self):\n    self.x = 1\n".to_string(),
+            file_path: PathBuf::from("t.py"),
+            line_number: 1,
+            class_name: Some("A".to_string()),
+            ..Default::default()
+        };
+
+        let prompt = check.format_detection_prompt(&func).text;
+        assert!(prompt.ends_with("Simple attribute assignments and one-time setup calls are NOT performance issues.\n<start_of_turn>model\n"));
+
+        check.assistant_marker = Some(String::new());
+        assert!(check.format_detection_prompt(&func).text.ends_with("NOT performance issues.\n"));
+
+        let default_hash = check.prompt_hash();
+        check.init_special_case = Some(false);
+        assert!(!check.format_detection_prompt(&func).text.contains("__init__ (constructor)"));
+        assert_ne!(check.prompt_hash(), default_hash);
+    }
+
+    #[test]
+    fn async_defs_are_detected_past_decorators() {
+        assert!(is_async_def("@app.get(\n    \"/items\",\n)\nasync def items():\n    return []\n"));
+        assert!(!is_async_def("@cache\ndef items():\n    return []\n"));
+    }
+
+    #[test]
+    fn inline_code_is_analyzed_without_a_file() {
+        use std::path::PathBuf;
+
+        assert_eq!(unescape_inline_code("def f():\\n\\treturn 1"), "def f():\n\treturn 1");
+        // Real line breaks mean the escapes are string contents
+        assert_eq!(unescape_inline_code("def f():\n    return '\\n'"), "def f():\n    return '\\n'");
+
+        let path = PathBuf::from(INLINE_PATH);
+        let functions = extract_functions(&path, &path, false, false, SourceOrigin::Inline("def f(x):\\n  return x")).unwrap();
+        assert_eq!(functions.len(), 1);
+        assert_eq!((functions[0].name.as_str(), functions[0].file_path.as_path()), ("f", path.as_path()));
+    }
+
+    #[test]
+    fn prompt_overrides_respect_chatml_roles() {
+        let prompt = "<|im_start|>system\nYou review code.<|im_end|>\n<|im_start|>user\nCheck this.<|im_end|>\n<|im_start|>assistant\n";
+        assert_eq!(
+            override_prompt(prompt, Some("Be terse."), Some("Reply with VERDICT first.")),
+            "<|im_start|>system\nBe terse.\n\nYou review code.<|im_end|>\n<|im_start|>user\nCheck this.\n\nReply with VERDICT first.<|im_end|>\n<|im_start|>assistant\n"
+        );
+        assert_eq!(override_prompt("Check this.", Some("Be terse."), None), "Be terse.\n\nCheck this.");
+    }
+
+    #[test]
+    fn verifier_rounds_need_a_strict_majority() {
+        let vote = |response: &str| parse_verification_result(response);
+        let accepted = majority_verdict(vec![
+            vote("VERDICT: VALID\nCONFIDENCE: 0.8\nREASON: same output\nEND"),
+            vote("VERDICT: INVALID\nREASON: drops duplicates\nEND"),
+            vote("VERDICT: VALID\nCONFIDENCE: 0.6\nREASON: same output\nEND"),
+        ]).unwrap();
+        assert!(accepted.is_valid);
+        assert_eq!(accepted.reason, "2/3 verifier rounds accepted: same output");
+        assert!((accepted.confidence.unwrap() - 0.7).abs() < 1e-6);
+
+        let tied = majority_verdict(vec![
+            vote("VERDICT: VALID\nREASON: ok\nEND"),
+            vote("VERDICT: INVALID\nREASON: changes order\nEND"),
+        ]).unwrap();
+        assert!(!tied.is_valid);
+        assert!(majority_verdict(Vec::new()).is_none());
+    }
+
+    #[test]
+    fn draw_seeds_depend_on_prompt_and_round_only() {
+        let (a, b) = ([1u8; 32], [2u8; 32]);
+        assert_eq!(draw_seed(7, &a, 1), draw_seed(7, &a, 1));
+        assert_ne!(draw_seed(7, &a, 0), draw_seed(7, &a, 1));
+        assert_ne!(draw_seed(7, &a, 0), draw_seed(7, &b, 0));
+        assert_ne!(draw_seed(7, &a, 0), draw_seed(8, &a, 0));
+    }
+
+    #[test]
+    fn solution_candidates_rank_by_verifier_confidence() {
+        let (best, confidence, alternatives) = rank_solution_candidates(vec![
+            ("first".to_string(), Some(0.6)),
+            ("unverified".to_string(), None),
+            ("second".to_string(), Some(0.9)),
+            ("third".to_string(), Some(0.6)),
+        ]).unwrap();
+        assert_eq!(best, "second");
+        assert_eq!(confidence, Some(0.9));
+        assert_eq!(alternatives, ["first", "third", "unverified"]);
+        assert!(rank_solution_candidates(Vec::new()).is_none());
+
+        let config: ChecksConfig = toml::from_str(get_default_config_toml()).unwrap();
+        let mut check = config.check[0].clone();
+        let default_hash = check.prompt_hash();
+        check.solution_candidates = 3;
+        assert_ne!(check.prompt_hash(), default_hash);
+    }
+
+    #[test]
+    fn stabilize_keeps_the_majority_detection() {
+        let config: ChecksConfig = toml::from_str(get_default_config_toml()).unwrap();
+        let check = &config.check[0];
+        let flagged = format!("VERDICT: {}\nCONFIDENCE: 0.9\nDETAIL: nested scan\nEND", check.keyword);
+        let responses = [flagged.clone(), "VERDICT: OK\nCONFIDENCE: 0.4\nEND".to_string(), flagged.replace("0.9", "0.7")];
+        let mut round = 0;
+        let mut generate = |_: &Prompt, _: i32, _: f32, _: bool| {
+            round += 1;
+            Ok((responses[round - 1].clone(), StopReason::StopSequence, TokenStats::new(10, 5, Default::default())))
+        };
+
+        let prompt = Prompt::new("detect".to_string(), "");
+        let (text, _, stats, vote) = stabilized_detection(check, &prompt, 3, 64, 0.7, false, &mut generate).unwrap();
+        assert_eq!(text, flagged);
+        assert_eq!(stats.input_tokens, 30);
+        assert!(vote.has_issue());
+        assert!((vote.confidence.unwrap() - 0.8).abs() < 1e-6);
+        assert_eq!(vote.note(), "[Stabilized: 2/3 detection rounds flagged an issue]");
+
+        let tied = tally_detections(&[check.parse_detection(&flagged), check.parse_detection("VERDICT: OK\nEND")]);
+        assert!(!tied.has_issue());
+    }
+
+    #[test]
+    fn loopsleuth_comments_override_checks_per_function() {
+        let source = "# hot path, reviewed\n# loopsleuth: only=quadratic,linear-* confidence-threshold=0.9\n@cache\ndef f(xs):  # loopsleuth: no-solution\n    return xs\n\n# loopsleuth: ignore typo\ndef g():\n    pass\n";
+        let functions = extract_functions_from_source(source, &std::path::PathBuf::from("m.py"), std::path::Path::new("."), false, false).unwrap();
+
+        let f = FunctionDirectives::for_function(source, &functions[0]);
+        assert_eq!(f.only, Some(vec!["quadratic".to_string(), "linear-*".to_string()]));
+        assert_eq!(f.confidence_threshold, Some(0.9));
+        assert!(f.no_solution && !f.ignore);
+
+        let g = FunctionDirectives::for_function(source, &functions[1]);
+        assert!(g.ignore);
+        assert_eq!(g.unknown, vec!["typo".to_string()]);
+
+        let config: ChecksConfig = toml::from_str(get_default_config_toml()).unwrap();
+        let check = |key: &str| config.check.iter().find(|check| check.key == key).unwrap();
+        assert!(f.runs(check("quadratic")) && f.runs(check("linear-in-loop")));
+        assert!(!f.runs(check("unbounded-alloc")));
+        let skip = FunctionDirectives::parse(["# loopsleuth: skip=quadratic"].into_iter());
+        assert!(!skip.runs(check("quadratic")) && skip.runs(check("unbounded-alloc")));
+
+        // The directive sits on the closing line of a multi-line signature, and the comment
+        // above belongs to the previous function's body
+        let source = "def a(xs):\n    return xs\n    # loopsleuth: ignore\ndef b(\n    xs,\n):  # loopsleuth: no-solution\n    return xs\n";
+        let functions = extract_functions_from_source(source, &std::path::PathBuf::from("m.py"), std::path::Path::new("."), false, false).unwrap();
+        let b = FunctionDirectives::for_function(source, &functions[1]);
+        assert!(b.no_solution && !b.ignore);
+    }
+
+    #[test]
+    fn decorators_reach_the_prompt_and_guard() {
+        let source = "@app.get(\"/items\")\n@lru_cache(maxsize=None)\nasync def items(ids):\n    return requests.get(ids)\n\ndef plain():\n    return 1\n";
+        let functions = extract_functions_from_source(source, &std::path::PathBuf::from("m.py"), std::path::Path::new("."), false, false).unwrap();
+        assert_eq!(functions[0].decorators, vec!["@app.get(\"/items\")", "@lru_cache(maxsize=None)"]);
+        assert!(functions[1].decorators.is_empty());
+
+        let mut config: ChecksConfig = toml::from_str(get_default_config_toml()).unwrap();
+        apply_template_expansion(&mut config).unwrap();
+        let check = config.check.iter().find(|check| check.key == "decorator-misuse").unwrap();
+        assert!(check.format_detection_prompt(&functions[0]).text.contains("@app.get(\"/items\")\n@lru_cache(maxsize=None)"));
+        assert!(guard_skip_reason(check, &functions[0]).unwrap().is_none());
+        assert!(guard_skip_reason(check, &functions[1]).unwrap().is_some());
+    }
+
+    #[test]
+    fn detection_only_notes_are_recognized() {
+        assert!(is_detection_only(&format!("VERDICT: X\n\n{}", NO_SOLUTION_NOTE)));
+        assert!(is_detection_only(&format!("VERDICT: X\n\n{}", SOLUTIONS_FOR_NOTE)));
+        assert!(is_detection_only(&format!("VERDICT: X\n\n{}", NO_SOLUTION_DIRECTIVE_NOTE)));
+        assert!(!is_detection_only("VERDICT: X\n\n[Verifier rejected: changes output order]"));
+    }
+
+    #[test]
+    fn rejections_are_cached_alongside_results() {
+        use std::path::PathBuf;
+
+        let dir = tempfile::tempdir().unwrap();
+        let cache = AnalysisCache::new(None, Some(dir.path().join("cache.db")), true, false).unwrap();
+        let func = FunctionInfo {
+            name: "f".to_string(),
+            source: "def f(xs):\n    return xs\n".to_string(),
+            file_path: PathBuf::from("t.py"),
+            line_number: 1,
+            ..Default::default()
+        };
+
+        // The analysis text quoting a note is not enough to make a rejection
+        cache.put(&func, "quadratic", true, "DETAIL: [Verifier rejected: fake]", None, None).unwrap();
+        assert_eq!(cache.get(&func, "quadratic").unwrap().unwrap().rejection, None);
+
+        let rejection = Rejection::new(RejectionStage::ValidateOptimization, "optimized code is identical to original");
+        cache.put_rejection(&func, "quadratic", &rejection).unwrap();
+        assert_eq!(cache.get(&func, "quadratic").unwrap().unwrap().rejection, Some(rejection));
+    }
+
+    #[test]
+    fn verifier_rejected_lists_only_verifier_rejections() {
+        use std::path::PathBuf;
+
+        let check = |key: &str, rejection: Option<Rejection>| CheckResult {
+            check_key: key.to_string(),
+            check_name: key.to_string(),
+            has_issue: true,
+            analysis: String::new(),
+            rejection,
+            ..Default::default()
+        };
+        let result = AnalysisResult {
+            function: FunctionInfo {
+                name: "f".to_string(),
+                file_path: PathBuf::from("t.py"),
+                line_number: 1,
+                ..Default::default()
+            },
+            check_results: vec![
+                check("quadratic", Some(Rejection::new(RejectionStage::Verifier, "changes output order"))),
+                check("string-concat", Some(Rejection::new(RejectionStage::Extract, EXTRACT_FAILURE_REASON))),
+                check("unbounded-alloc", None),
+            ],
+        };
+
+        let results = [result];
+        let rejected: Vec<(&str, &str)> = verifier_rejected(&results)
+            .into_iter()
+            .map(|(_, cr, reason)| (cr.check_key.as_str(), reason))
+            .collect();
+        assert_eq!(rejected, vec![("quadratic", "changes output order")]);
+    }
+
+    #[test]
+    fn test_snippets_are_pulled_out_of_fenced_or_prefilled_responses() {
+        let fenced = "Here you go:\n```python\ndef test_same():\n    assert True\n```\nDone.";
+        assert_eq!(extract_test_snippet(fenced).as_deref(), Some("def test_same():\n    assert True"));
+        // The default test prompt already opens the fence
+        let prefilled = "import timeit\n\ndef test_fast():\n    pass\n```\n";
+        assert_eq!(extract_test_snippet(prefilled).as_deref(), Some("import timeit\n\ndef test_fast():\n    pass"));
+        assert_eq!(extract_test_snippet("```python\n```"), None);
+    }
+
+    #[test]
+    fn diff_ignores_trailing_newline_differences() {
+        let original = "def f(xs):\n    return sorted(xs)\n";
+        assert!(!generate_diff(original, original.trim_end()).lines().any(|l| l.starts_with(['+', '-'])));
+        assert!(!generate_diff(original.trim_end(), &format!("{}\n\n", original)).lines().any(|l| l.starts_with(['+', '-'])));
+
+        let diff = generate_diff(original, "def f(xs):\n    return list(xs)");
+        assert_eq!(diff, " def f(xs):\n-    return sorted(xs)\n+    return list(xs)\n");
+    }
+
+    #[test]
+    fn emit_optimized_rebuilds_the_function_from_the_diff() {
+        let config: ChecksConfig = toml::from_str(get_default_config_toml()).unwrap();
+        let original = "def f(items):\n    return [x for x in items if x in items[:10]]\n";
+        let optimized = "def f(items):\n    head = set(items[:10])\n    return [x for x in items if x in head]\n";
+        let issue = CheckResult {
+            check_key: "linear-in-loop".to_string(),
+            check_name: "Linear Operations in Loops".to_string(),
+            has_issue: true,
+            analysis: String::new(),
+            solution: Some(format!("```diff\n{}\n```", generate_diff(original, optimized))),
+            ..Default::default()
+        };
+
+        let mut options = ReportOptions::default();
+        assert_eq!(options.optimized_code(&config.check, &issue), None);
+        options.emit_optimized = true;
+        assert_eq!(options.optimized_code(&config.check, &issue).as_deref(), Some(optimized));
+    }
+
+    #[test]
+    fn cached_diff_solutions_can_be_reverified() {
+        let original = "def f(items):\n    for x in items:\n        pass\n";
+        let optimized = "def f(items):\n    seen = set(items)\n    for x in seen:\n        pass\n";
+        let solution = format!("```diff\n{}\n```", generate_diff(original, optimized));
+        assert_eq!(optimized_code_from_solution(&solution).as_deref(), Some(optimized));
+        let rewrite = format!("```python\n{}```", optimized);
+        assert_eq!(optimized_code_from_solution(&rewrite).as_deref(), Some(optimized));
+        assert_eq!(optimized_code_from_solution("no diff here"), None);
+
+        let low = parse_verification_result("VERDICT: VALID\nCONFIDENCE: 0.4\nEND");
+        assert!(verifier_rejection(&low, Some(0.6)).is_some());
+        assert_eq!(verifier_rejection(&low, None), None);
+        // A minimum outside 0.0-1.0 would reject everything or nothing
+        assert!(<Cli as clap::Parser>::try_parse_from(["loopsleuth", "--verify-min-confidence", "5"]).is_err());
+    }
+
+    #[test]
+    fn unrecognized_detection_responses_are_told_apart_from_clean_ones() {
+        let config: ChecksConfig = toml::from_str(get_default_config_toml()).unwrap();
+        let mut check = config.check[0].clone();
+        assert!(check.parse_detection("VERDICT: OK\nCONFIDENCE: 0.9\nEND").verdict_found);
+        assert!(check.parse_detection(&format!("{}: 0.95", check.keyword)).verdict_found);
+        assert!(!check.parse_detection("The function looks fine to me.").verdict_found);
+        assert!(!check.parse_detection("END\nVERDICT: OK").verdict_found);
+
+        check.detection_format = DetectionFormat::YesNo;
+        assert!(check.parse_detection("No.").verdict_found);
+        assert!(!check.parse_detection("Maybe").verdict_found);
+
+        check.detection_format = DetectionFormat::Json;
+        assert!(check.parse_detection("{\"issue\": false}").verdict_found);
+        assert!(!check.parse_detection("{\"verdict\": \"ok\"}").verdict_found);
+    }
+
+    #[test]
+    fn alternative_detection_formats_are_parsed() {
+        let yes = parse_yes_no_detection("**Yes**, the inner loop rescans the list.\nCONFIDENCE: 0.8\nEND");
+        assert!(yes.has_issue);
+        assert_eq!(yes.confidence, Some(0.8));
+        assert!(!parse_yes_no_detection("NO").has_issue);
+
+        let json = parse_json_detection("```json\n{\"issue\": true, \"confidence\": 0.9, \"detail\": \"x in list\"}\n```");
+        assert!(json.has_issue);
+        assert_eq!(json.confidence, Some(0.9));
+        assert!(!parse_json_detection("not json at all").has_issue);
+    }
+
+    #[test]
+    fn lines_above_stops_at_file_start() {
+        let content = "class Cache:\n    size = 10\n\n    def get(self, key):\n        return key\n";
+        assert_eq!(lines_above(content, 4, 2).as_deref(), Some("    size = 10\n"));
+        assert_eq!(lines_above(content, 4, 10).as_deref(), Some("class Cache:\n    size = 10\n"));
+        assert_eq!(lines_above(content, 1, 5), None);
+    }
+
+    #[test]
+    fn prompt_prefix_stops_at_function_source() {
+        let template = "<|im_start|>system\nFind {keyword}\n```python\n{function_source}\n```";
+        let prefix = template_prefix(template).replace("{keyword}", "QUADRATIC");
+        let text = template
+            .replace("{function_source}", "def f(): pass")
+            .replace("{keyword}", "QUADRATIC");
+
+        let prompt = Prompt::new(text, &prefix);
+        assert_eq!(prompt.prefix(), "<|im_start|>system\nFind QUADRATIC\n```python\n");
+        assert_eq!(prompt.suffix(), "def f(): pass\n```");
+
+        // A prefix that doesn't match the assembled text disables reuse
+        assert_eq!(Prompt::new("other".to_string(), &prefix).prefix_len, 0);
+        assert_eq!(template_prefix("no placeholder"), "");
+
+        // Rules mentioning the decorators come before the code: the prefix must stop there,
+        // or the tokenizer would cache a new prefix for every function
+        let mut config: ChecksConfig = toml::from_str(get_default_config_toml()).unwrap();
+        apply_template_expansion(&mut config).unwrap();
+        let check = config.check.iter().find(|check| check.key == "decorator-misuse").unwrap();
+        let func = |decorator: &str| FunctionInfo {
+            name: "f".to_string(),
+            source_no_docstring: "def f(xs):\n    return xs\n".to_string(),
+            decorators: vec![decorator.to_string()],
+            ..Default::default()
+        };
+        let cached = check.format_detection_prompt(&func("@cache"));
+        let property = check.format_detection_prompt(&func("@property"));
+        assert!(cached.prefix_len > 0);
+        assert_eq!(cached.prefix(), property.prefix());
+        assert!(!cached.prefix().contains("@cache"));
+    }
+
+    #[test]
+    fn module_path_is_dotted_relative_to_root() {
+        use std::path::Path;
+
+        let root = Path::new("project/src");
+        assert_eq!(module_path_for(Path::new("project/src/app/models/user.py"), root), "app.models.user");
+        assert_eq!(module_path_for(Path::new("project/src/app/__init__.py"), root), "app");
+        assert_eq!(module_path_for(Path::new("elsewhere/utils.py"), root), "elsewhere.utils");
+
+        // Extraction fills it in, so every caller sees the same value
+        let file_path = Path::new("project/src/app/models/user.py").to_path_buf();
+        let functions = extract_functions_from_source("def load():\n    pass\n", &file_path, root, false, false).unwrap();
+        assert_eq!(functions[0].module_path, "app.models.user");
+    }
+
+    #[test]
+    fn table_cells_truncate_with_ellipsis() {
+        assert_eq!(truncate_left("src/app/models/user.py", 12), "…els/user.py");
+        assert_eq!(truncate_right("VeryLongClass::method", 10), "VeryLongC…");
+        assert_eq!(truncate_right("short", 10), "short");
+    }
+
+    #[test]
+    fn sampling_is_reproducible_and_bounded() {
+        let picked = sample_indices(100, 10, 42);
+        assert_eq!(picked.len(), 10);
+        assert_eq!(picked, sample_indices(100, 10, 42));
+        assert_ne!(picked, sample_indices(100, 10, 43));
+        assert!(picked.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(sample_indices(3, 10, 1), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_files_match_name_globs_and_directories() {
+        use std::path::Path;
+
+        let patterns: Vec<String> = DEFAULT_TEST_PATTERNS.iter().map(|p| p.to_string()).collect();
+        let root = Path::new("repo");
+        assert!(is_test_file(Path::new("repo/pkg/test_utils.py"), root, &patterns));
+        assert!(is_test_file(Path::new("repo/pkg/utils_test.py"), root, &patterns));
+        assert!(is_test_file(Path::new("repo/tests/helpers.py"), root, &patterns));
+        assert!(!is_test_file(Path::new("repo/pkg/testing.py"), root, &patterns));
+        // Only directories count for `tests/`, not a file of that name
+        assert!(!is_test_file(Path::new("repo/pkg/tests"), root, &patterns));
+        // A `tests` directory above the analyzed root doesn't make everything a test file
+        let root = Path::new("work/tests/myproj");
+        assert!(!is_test_file(Path::new("work/tests/myproj/app.py"), root, &patterns));
+        assert!(is_test_file(Path::new("work/tests/myproj/tests/app.py"), root, &patterns));
+    }
+
+    #[test]
+    fn check_selectors_support_categories_and_globs() {
+        let config: ChecksConfig = toml::from_str(get_default_config_toml()).unwrap();
+        let selected = |selector: &str| -> Vec<String> {
+            config.check.iter()
+                .filter(|check| check_selector_matches(selector, check))
+                .map(|check| check.key.clone())
+                .collect()
+        };
+
+        assert_eq!(selected("quadratic"), vec!["quadratic"]);
+        assert_eq!(selected("quad*"), vec!["quadratic"]);
+        assert_eq!(selected("ml-specific/*").len(), 4);
+        assert!(selected("*-in-loop").contains(&"linear-in-loop".to_string()));
+        assert!(selected("quad").is_empty());
+    }
+
+    #[test]
+    fn report_file_names_flatten_paths() {
+        assert!(report_file_name("pkg/models/bert.py").starts_with("pkg_models_bert.py-"));
+        assert!(report_file_name("/abs/mod.py").starts_with("abs_mod.py-"));
+        assert!(report_file_name("C:\\src\\mod.py").starts_with("C__src_mod.py-"));
+        assert!(report_file_name("pkg/mod.py").ends_with(".html"));
+
+        // Flattening alone would give both of these `pkg_a_b.py.html`
+        assert_ne!(report_file_name("pkg/a_b.py"), report_file_name("pkg_a/b.py"));
+        assert_eq!(percent_encode("a#1?b%c.py.html"), "a%231%3Fb%25c.py.html");
+    }
+
+    #[test]
+    fn compressed_reports_are_gzipped() {
+        use std::io::Read;
+        use std::path::Path;
+
+        assert_eq!(report_output_path(Path::new("report.html"), true), Path::new("report.html.gz"));
+        assert_eq!(report_output_path(Path::new("report.json.gz"), true), Path::new("report.json.gz"));
+        assert_eq!(report_output_path(Path::new("report.html"), false), Path::new("report.html"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("report.json.gz");
+        write_report_file(&path, "{\"files\": []}").unwrap();
+        let mut contents = String::new();
+        flate2::read::GzDecoder::new(std::fs::File::open(&path).unwrap())
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "{\"files\": []}");
+    }
+
+    #[test]
+    fn live_report_regenerates_html_as_results_arrive() {
+        use std::path::PathBuf;
+
+        let dir = tempfile::tempdir().unwrap();
+        let html_path = dir.path().join("report.html");
+        let options = ReportOptions::default();
+        let cache = AnalysisCache::new(None, Some(dir.path().join("cache.db")), false, false).unwrap();
+        let mut live_report = LiveReport::new(&html_path, &options).unwrap();
+        let result = |name: &str, has_issue: bool| AnalysisResult {
+            function: FunctionInfo {
+                name: name.to_string(),
+                source: format!("def {}(xs):\n    return xs.count(1)\n", name),
+                file_path: PathBuf::from("t.py"),
+                line_number: 1,
+                ..Default::default()
+            },
+            check_results: vec![CheckResult {
+                check_key: "quadratic".to_string(),
+                check_name: "Quadratic".to_string(),
+                has_issue,
+                analysis: "VERDICT: QUADRATIC".to_string(),
+                stop_reason: Some(StopReason::StopSequence),
+                ..Default::default()
+            }],
+        };
+
+        live_report.append(&result("clean_fn", false)).unwrap();
+        live_report.append(&result("slow_fn", true)).unwrap();
+        live_report.write(&[], &cache).unwrap();
+        let html = std::fs::read_to_string(&html_path).unwrap();
+        assert!(html.contains("<strong>Total functions analyzed:</strong> 2"));
+        assert!(html.contains("<strong>Functions with issues:</strong> 1"));
+        assert!(html.contains("def slow_fn(xs):"));
+        assert!(!html.contains("def clean_fn(xs):"));
+        assert!(!dir.path().join("report.html.tmp").exists());
+        // Every result is kept in the store, one line each
+        let store = std::fs::read_to_string(dir.path().join("report.html.results.jsonl")).unwrap();
+        assert_eq!(store.lines().count(), 2);
+        assert!(store.contains("def slow_fn(xs):"));
+    }
+
+    #[test]
+    fn config_problems_flags_broken_checks() {
+        let config: ChecksConfig = toml::from_str(get_default_config_toml()).unwrap();
+        assert!(config_problems(&config).is_empty());
+
+        let broken: ChecksConfig = toml::from_str(r#"
+            [[check]]
+            key = "dup"
+            name = "Dup"
+            description = ""
+            category = "test"
+            keyword = "DUP"
+            detection_prompt = "{template:missing}"
+            solution_prompt = "fix it"
+
+            [[check]]
+            key = "dup"
+            name = "Dup again"
+            description = ""
+            category = "test"
+            keyword = "DUP"
+            detection_prompt = "detect"
+            solution_prompt = ""
+            guard = { require_regex_any = ["("] }
+            prefilter_regex = "("
+        "#).unwrap();
+        let problems = config_problems(&broken);
+        assert_eq!(problems.len(), 5, "{:?}", problems);
+        assert!(problems.iter().any(|p| p.contains("unknown template 'missing'")));
+        assert!(problems.iter().any(|p| p.contains("duplicate key")));
+        assert!(problems.iter().any(|p| p.contains("solution_prompt is empty")));
+        assert!(problems.iter().any(|p| p.contains("guard Invalid regex")));
+        assert!(problems.iter().any(|p| p.contains("invalid prefilter_regex")));
+    }
+
+    #[test]
+    fn duplicate_check_keys_are_rejected() {
+        let mut config: ChecksConfig = toml::from_str(get_default_config_toml()).unwrap();
+        let duplicate = config.check[0].clone();
+        config.check.push(duplicate);
+
+        let err = apply_template_expansion(&mut config).unwrap_err();
+        assert!(err.to_string().contains(&format!("Duplicate check key '{}'", config.check[0].key)));
+    }
+
+    #[test]
+    fn prompts_missing_required_placeholders_are_rejected() {
+        let mut config: ChecksConfig = toml::from_str(get_default_config_toml()).unwrap();
+        apply_template_expansion(&mut config).unwrap();
+        let key = config.check[1].key.clone();
+        config.check[1].solution_prompt = "Fix the code above.".to_string();
+        config.check[1].verifier_prompt = "Is {function_source} fixed?".to_string();
+
+        assert_eq!(missing_placeholders(&config.check[1]), vec![
+            "solution_prompt does not contain {function_source}".to_string(),
+            "verifier_prompt does not contain {solution}".to_string(),
+        ]);
+        let err = apply_template_expansion(&mut config).unwrap_err();
+        assert_eq!(err.to_string(), format!("Check '{}': solution_prompt does not contain {{function_source}}", key));
+    }
+
+    #[test]
+    fn yaml_config_deserializes_like_toml() {
+        use std::path::Path;
+
+        assert_eq!(ConfigFormat::for_path(Path::new("team/loopsleuth.YML")), ConfigFormat::Yaml);
+        assert_eq!(ConfigFormat::for_path(Path::new("loopsleuth.toml")), ConfigFormat::Toml);
+
+        let yaml = r#"
+settings:
+  context_size: 8192
+templates:
+  head: "Review this: {detection_rules} {function_source}"
+check:
+  - key: quadratic
+    name: Quadratic Complexity
+    description: Nested loops
+    category: performance
+    keyword: QUADRATIC
+    detection_prompt: "{template:head}"
+    detection_rules: nested loops over the same input
+    solution_prompt: "Fix {function_source}"
+    guard:
+      require_any: ["for "]
+"#;
+        let mut config = ConfigFormat::Yaml.parse(yaml).unwrap();
+        apply_template_expansion(&mut config).unwrap();
+        assert_eq!(config.settings.context_size, Some(8192));
+        assert_eq!(config.check[0].guard.require_any, vec!["for ".to_string()]);
+        assert_eq!(
+            config.check[0].detection_prompt,
+            "Review this: nested loops over the same input {function_source}"
+        );
+    }
+
+    #[test]
+    fn prefilter_regex_only_passes_matching_functions() {
+        use std::path::PathBuf;
+
+        let mut config: ChecksConfig = toml::from_str(get_default_config_toml()).unwrap();
+        let func = |body: &str| FunctionInfo {
+            name: "f".to_string(),
+            source_no_docstring: format!("def f(xs):\n    {}\n", body),
+            file_path: PathBuf::from("t.py"),
+            line_number: 1,
+            ..Default::default()
+        };
+
+        assert!(!is_prefiltered(&config.check[0], &func("return 1")));
+        config.check[0].prefilter_regex = Some(r"\bfor\b".to_string());
+        apply_template_expansion(&mut config).unwrap();
+        let check = &config.check[0];
+        assert!(is_prefiltered(check, &func("return 1")));
+        assert!(!is_prefiltered(check, &func("return [x for x in xs]")));
+    }
+
+    #[test]
+    fn set_overrides_settings_and_checks_by_key() {
+        let mut config: ChecksConfig = toml::from_str(get_default_config_toml()).unwrap();
+        apply_template_expansion(&mut config).unwrap();
+        let key = config.check[0].key.clone();
+
+        let config = apply_config_override(&config, "settings.context_size=8192").unwrap();
+        let config = apply_config_override(&config, &format!("check.{}.verifier_rounds = 3", key)).unwrap();
+        let config = apply_config_override(&config, "settings.api_url=http://localhost:8080").unwrap();
+        assert_eq!(config.settings.context_size, Some(8192));
+        assert_eq!(config.check[0].verifier_rounds, 3);
+        assert_eq!(config.settings.api_url.as_deref(), Some("http://localhost:8080"));
+
+        assert!(apply_config_override(&config, "settings.context_sise=1").is_err());
+        assert!(apply_config_override(&config, "check.no-such-check.name=x").is_err());
+        assert!(apply_config_override(&config, "settings.context_size=big").is_err());
+    }
+
+    #[test]
+    fn show_config_notes_where_each_setting_came_from() {
+        let mut cli = Cli::parse_tracking_sources([
+            "loopsleuth", "--max-tokens", "512", "--skip-large", "200", "--set", "settings.context_size=8192", "--checks", "quadratic",
+        ]);
+        let config: ChecksConfig = toml::from_str(get_default_config_toml()).unwrap();
+        let mut config = apply_config_override(&config, "settings.context_size=8192").unwrap();
+        config.settings.max_tokens = Some(2048);
+        config.settings.skip_large = Some(200);
+        config.settings.skip_small = Some(3);
+        apply_config_settings(&mut cli, &config).unwrap();
+
+        let shown = effective_config_toml(&cli, &config, "config file: my.toml", &config.check[..1]);
+        assert!(shown.contains("max_tokens = 512  # command line\n"));
+        // Explicit even though it matches the config
+        assert!(shown.contains("skip_large = 200  # command line\n"));
+        assert!(shown.contains("skip_small = 3  # config file: my.toml\n"));
+        assert!(shown.contains("context_size = 8192  # --set\n"));
+        assert!(shown.contains("cache_dir = \".loopsleuth_cache\"  # default\n"));
+        assert!(shown.contains("# model is not set  # default\n"));
+
+        let parsed: toml::Table = toml::from_str(&shown).unwrap();
+        assert_eq!(parsed["settings"]["context_size"].as_integer(), Some(8192));
+        assert_eq!(parsed["checks"].as_array().map(Vec::len), Some(1));
+    }
+
+    #[test]
+    fn model_profile_sits_between_flags_and_settings() {
+        use clap::Parser;
+
+        let config: ChecksConfig = toml::from_str(&format!(
+            "{}\n[model_profiles.large]\nmodel = \"big.gguf\"\ncontext_size = 16384\ntemperature = 0.2\ngpu_layers = 32\n",
+            get_default_config_toml()
+        )).unwrap();
+        let mut config = apply_config_override(&config, "settings.max_tokens=512").unwrap();
+        config.settings.context_size = Some(8192);
+
+        let mut cli = Cli::parse_tracking_sources(["loopsleuth", "--model-profile", "large", "--temperature", "0"]);
+        apply_config_settings(&mut cli, &config).unwrap();
+        assert_eq!(cli.model, Some(std::path::PathBuf::from("big.gguf")));
+        assert_eq!(cli.context_size, 16384);
+        assert_eq!(cli.max_tokens, 512);
+        // An explicit flag wins even when it matches the default
+        assert_eq!(cli.temperature, 0.0);
+        assert_eq!(cli.gpu_layers, Some(32));
+
+        let mut cli = Cli::parse_from(["loopsleuth", "--model-profile", "huge"]);
+        let err = apply_config_settings(&mut cli, &config).unwrap_err();
+        assert!(err.to_string().contains("config defines: large"));
+    }
+
+    #[test]
+    fn min_throughput_waits_for_warmup_before_aborting() {
+        let slow = TokenStats::new(3000, 30, std::time::Duration::from_secs(60));
+        assert!(slow_generation_error(&slow, MIN_THROUGHPUT_WARMUP - 1, 1.0).is_none());
+        let message = slow_generation_error(&slow, MIN_THROUGHPUT_WARMUP, 1.0).unwrap();
+        assert!(message.contains("0.50 tok/s"));
+        assert!(slow_generation_error(&slow, MIN_THROUGHPUT_WARMUP, 0.5).is_none());
+    }
+
+    #[test]
+    fn line_numbers_match_editor_lines() {
+        let lf = "import os\n\ndef f():\n    pass\n";
+        assert_eq!(count_lines_to_offset(lf, 0usize), 1);
+        assert_eq!(count_lines_to_offset(lf, lf.find("def").unwrap()), 3);
+
+        let crlf = "import os\r\n\r\ndef f():\r\n    pass\r\n";
+        assert_eq!(count_lines_to_offset(crlf, crlf.find("def").unwrap()), 3);
+        assert_eq!(count_lines_to_offset(crlf, crlf.find("pass").unwrap()), 4);
+
+        // Offsets inside a line (indented methods) and files without a trailing newline
+        let method = "class A:\n    def m(self):\n        return 1";
+        assert_eq!(count_lines_to_offset(method, method.find("def").unwrap()), 2);
+        assert_eq!(count_lines_to_offset(method, method.find("return").unwrap()), 3);
+        assert_eq!(count_lines_to_offset(method, method.len()), 3);
+        assert_eq!(count_lines_to_offset(method, method.len() + 10), 3);
+    }
+
+    #[test]
+    fn codeclimate_issues_follow_the_spec() {
+        use std::path::PathBuf;
+
+        let config: ChecksConfig = toml::from_str(get_default_config_toml()).unwrap();
+        let issue = |key: &str, analysis: &str| CheckResult {
+            check_key: key.to_string(),
+            check_name: key.to_string(),
+            has_issue: true,
+            analysis: analysis.to_string(),
+            ..Default::default()
+        };
+        let file_results = vec![FileResults {
+            file_path: PathBuf::from("pkg/mod.py"),
+            results: vec![AnalysisResult {
+                function: FunctionInfo {
+                    name: "run".to_string(),
+                    file_path: PathBuf::from("pkg/mod.py"),
+                    line_number: 12,
+                    class_name: Some("Model".to_string()),
+                    ..Default::default()
+                },
+                check_results: vec![
+                    issue("quadratic", "VERDICT: QUADRATIC\nDETAIL: nested scan\n[Confidence: 0.92]"),
+                    issue("conversion-churn", "VERDICT: CHURN\n[Confidence: 0.55]"),
+                ],
+            }],
+        }];
+
+        let issues = codeclimate_issues(&file_results, &config.check, &ReportOptions::default());
+        let issues = issues.as_array().unwrap();
+        assert_eq!(issues.len(), 2);
+        assert_eq!(issues[0]["type"], "issue");
+        assert_eq!(issues[0]["check_name"], "quadratic");
+        assert_eq!(issues[0]["description"], "quadratic in `Model::run`: nested scan");
+        assert_eq!(issues[0]["categories"], serde_json::json!(["Performance"]));
+        assert_eq!(issues[0]["location"], serde_json::json!({"path": "pkg/mod.py", "lines": {"begin": 12}}));
+        assert_eq!(issues[0]["fingerprint"], finding_id("pkg/mod.py", "Model::run", "quadratic"));
+        assert_eq!(issues[0]["severity"], "critical");
+        assert_eq!(issues[1]["severity"], "minor");
+
+        // The ID follows the path below the analyzed directory, not the path shown
+        let options = ReportOptions { finding_root: Some(PathBuf::from("pkg")), ..Default::default() };
+        let issues = codeclimate_issues(&file_results, &config.check, &options);
+        assert_eq!(issues[0]["location"]["path"], "pkg/mod.py");
+        assert_eq!(issues[0]["fingerprint"], finding_id("mod.py", "Model::run", "quadratic"));
+    }
+
+    #[test]
+    fn summary_json_counts_issues_per_check() {
+        use std::path::PathBuf;
+
+        let config: ChecksConfig = toml::from_str(get_default_config_toml()).unwrap();
+        let issue = |key: &str, advisory: bool| CheckResult {
+            check_key: key.to_string(),
+            check_name: key.to_string(),
+            has_issue: true,
+            analysis: String::new(),
+            advisory,
+            ..Default::default()
+        };
+        let output = AnalysisOutput {
+            file_results: vec![FileResults {
+                file_path: PathBuf::from("pkg/mod.py"),
+                results: vec![AnalysisResult {
+                    function: FunctionInfo {
+                        name: "run".to_string(),
+                        file_path: PathBuf::from("pkg/mod.py"),
+                        line_number: 12,
+                        ..Default::default()
+                    },
+                    check_results: vec![issue("quadratic", false), issue("conversion-churn", true)],
+                }],
+            }],
+            total_functions: 4,
+            functions_with_issues: 1,
+            stats: TokenStats::new(1200, 300, std::time::Duration::from_secs(3)),
+            stopped_early: false,
+            capped: false,
+            unchecked_functions: Vec::new(),
+            prefiltered: Default::default(),
+            unparseable_files: Vec::new(),
+            skips: Vec::new(),
+        };
+
+        let summary = summary_json(&output, 2, &config.check);
+        assert_eq!(summary["issues"], 2);
+        assert_eq!(summary["advisory_issues"], 1);
+        assert_eq!(summary["issues_by_check"]["quadratic"], 1);
+        assert_eq!(summary["issues_by_check"]["linear-in-loop"], 0);
+        assert_eq!(summary["token_usage"]["output_tokens"], 300);
+        assert_eq!(summary["health_score"], 75.0);
+
+        // A cutoff outside 0.0-1.0 would make every issue advisory or none of them
+        assert!(<Cli as clap::Parser>::try_parse_from(["loopsleuth", "--warn-below-confidence", "1.5"]).is_err());
+    }
+
+    #[test]
+    fn report_findings_are_keyed_on_function_and_check() {
+        let report = serde_json::json!({
+            "files": [{
+                "path": "pkg/mod.py",
+                "results": [
+                    {
+                        "function_name": "run",
+                        "class_name": "Model",
+                        "line_number": 3,
+                        "issues": [
+                            {"check_key": "quadratic"},
+                            {"check_key": "linear-in-loop"},
+                            {"check_key": "sort-in-loop", "advisory": true},
+                        ],
+                    },
+                    {"function_name": "helper", "class_name": null, "line_number": 9, "issues": []},
+                ],
+            }],
+        });
+
+        let findings: Vec<_> = report_findings(&report).into_keys().collect();
+        assert_eq!(findings, vec![
+            ("pkg/mod.py".to_string(), "Model::run".to_string(), "linear-in-loop".to_string()),
+            ("pkg/mod.py".to_string(), "Model::run".to_string(), "quadratic".to_string()),
+        ]);
+        assert!(report_findings(&serde_json::json!({})).is_empty());
+
+        // IDs ignore line numbers and path separators but tell functions and checks apart
+        let id = finding_id("pkg/mod.py", "Model::run", "quadratic");
+        assert_eq!(id.len(), 16);
+        assert_eq!(id, finding_id("pkg\\mod.py", "Model::run", "quadratic"));
+        assert_ne!(id, finding_id("pkg/mod.py", "run", "quadratic"));
+        assert_ne!(id, finding_id("pkg/mod.py", "Model::run", "linear-in-loop"));
+
+        assert!(is_below_confidence("VERDICT: X\n[Confidence: 0.40]", 0.6));
+        assert!(!is_below_confidence("VERDICT: X\n[Confidence: 0.60]", 0.6));
+        assert!(!is_below_confidence("VERDICT: X", 0.6));
+    }
+
+    #[test]
+    fn verifier_confidence_blends_into_the_reported_one() {
+        let analysis = "VERDICT: X\n[Confidence: 0.80]\n[Verifier confidence: 0.90]";
+        let blended = blended_confidence_analysis(analysis).unwrap();
+        assert_eq!(blended, "VERDICT: X\n[Confidence: 0.89]\n[Detection confidence: 0.80]\n[Verifier confidence: 0.90]");
+        assert_eq!(extract_confidence_percentage(&blended), 89);
+
+        assert_eq!(blended_confidence_analysis("VERDICT: X\n[Confidence: 1.00]\n[Verifier confidence: 1.00]").as_deref(),
+            Some("VERDICT: X\n[Confidence: 1.00]\n[Detection confidence: 1.00]\n[Verifier confidence: 1.00]"));
+        // Nothing to blend without both confidences
+        assert_eq!(blended_confidence_analysis("VERDICT: X\n[Confidence: 0.80]"), None);
+        assert_eq!(blended_confidence_analysis("VERDICT: X\n[Verifier confidence: 0.90]"), None);
+    }
+
+    #[test]
+    fn line_ranges_parse_and_validate() {
+        assert_eq!(parse_line_range("10:40"), Ok((10, 40)));
+        assert_eq!(parse_line_range("7:7"), Ok((7, 7)));
+        assert!(parse_line_range("40:10").is_err());
+        assert!(parse_line_range("0:5").is_err());
+        assert!(parse_line_range("12").is_err());
+    }
+
+    #[test]
+    fn nested_functions_are_extracted_on_request() {
+        use std::path::PathBuf;
+
+        let source = r#"
+def outer(xs):
+    def helper(x):
+        return x * 2
+    return [helper(x) for x in xs]
+
+class Model:
+    def forward(self, xs):
+        async def fetch(x):
+            return x
+        return xs
+"#;
+        let extract = |nested: bool| extract_functions_from_source(source, &PathBuf::from("t.py"), std::path::Path::new("."), false, nested).unwrap();
+        let names = |nested: bool| -> Vec<String> { extract(nested).iter().map(|f| f.qualified_name()).collect() };
+
+        assert_eq!(names(false), vec!["outer", "Model::forward"]);
+        assert_eq!(names(true), vec!["outer", "outer::helper", "Model::forward", "Model::forward::fetch"]);
+        // A helper nested in a method isn't a method itself
+        let fetch = &extract(true)[3];
+        assert_eq!(fetch.class_name, None);
+        assert_eq!(fetch.enclosing_function.as_deref(), Some("Model::forward"));
+    }
+
+    #[test]
+    fn class_units_are_extracted_whole_and_routed_to_class_checks() {
+        use std::path::PathBuf;
+
+        let source = r#"
+class Registry:
+    def add(self, item):
+        self.items.append(item)
+
+    def find(self, key):
+        return [i for i in self.items if i.key == key]
+
+def helper(xs):
+    return xs
+"#;
+        let config: ChecksConfig = toml::from_str(get_default_config_toml()).unwrap();
+        let function_check = config.check[0].clone();
+        let class_check = CheckConfig { scope: CheckScope::Class, ..function_check.clone() };
+
+        let functions = extract_functions_from_source(source, &PathBuf::from("t.py"), std::path::Path::new("."), true, false).unwrap();
+        let names: Vec<_> = functions.iter().map(|f| (f.qualified_name(), f.is_class_scope)).collect();
+        assert_eq!(names, vec![
+            ("Registry".to_string(), true),
+            ("Registry::add".to_string(), false),
+            ("Registry::find".to_string(), false),
+            ("helper".to_string(), false),
+        ]);
+        // One unit holding the whole class, methods included
+        assert!(functions[0].source.contains("def add") && functions[0].source.contains("def find"));
+        assert!(class_check.applies_to_unit(&functions[0]) && !function_check.applies_to_unit(&functions[0]));
+        assert!(functions[1..].iter().all(|f| function_check.applies_to_unit(f) && !class_check.applies_to_unit(f)));
+
+        // Class units only exist when a class-scoped check asks for them, and stay internal
+        assert!(extract_functions_from_source(source, &PathBuf::from("t.py"), std::path::Path::new("."), false, false).unwrap()
+            .iter().all(|f| !f.is_class_scope));
+        assert!(serde_json::to_value(&functions[0]).unwrap().get("is_class_scope").is_none());
+    }
+
+    #[test]
+    fn direct_recursion_is_detected() {
+        use std::path::PathBuf;
+
+        let source = r#"
+def fib(n):
+    return n if n < 2 else fib(n - 1) + fib(n - 2)
+
+def total(xs):
+    return sum(xs)
+
+class Tree:
+    def size(self):
+        return 1 + sum(child.size() for child in self.children)
+
+    def depth(self):
+        return 1 + max((self.depth() for _ in self.children), default=0)
+
+    def fib(self, n):
+        return fib(n)
+"#;
+        let functions = extract_functions_from_source(source, &PathBuf::from("t.py"), std::path::Path::new("."), false, false).unwrap();
+        let recursive: Vec<&str> = functions.iter()
+            .filter(|f| f.is_recursive)
+            .map(|f| f.name.as_str())
+            .collect();
+        // `child.size()` recurses on another object and `Tree.fib` calls the module-level `fib`
+        assert_eq!(recursive, vec!["fib", "depth"]);
+    }
+
+    #[test]
+    fn methods_carry_their_class_init_attributes() {
+        use std::path::PathBuf;
+
+        let source = r#"
+class Index:
+    def __init__(self, items, strict=False):
+        self.items = list(items)
+        self.seen: set = set()
+        if strict:
+            self.lookup = {}
+        count = 0
+
+    def has(self, item):
+        return item in self.items
+
+def free(x):
+    return x
+"#;
+        let functions = extract_functions_from_source(source, &PathBuf::from("t.py"), std::path::Path::new("."), false, false).unwrap();
+        let context = "self.items = list(items)\nself.seen: set = set()\nself.lookup = {}";
+        assert_eq!(functions.iter().map(|f| f.class_context.as_str()).collect::<Vec<_>>(), vec![context, context, ""]);
+    }
+
+    #[test]
+    fn no_network_only_allows_loopback_endpoints() {
+        assert!(ensure_network_allowed(false, "https://api-inference.huggingface.co").is_ok());
+        assert!(ensure_network_allowed(true, "http://localhost:8080").is_ok());
+        assert!(ensure_network_allowed(true, "http://127.0.0.1:8765/v1").is_ok());
+        assert!(ensure_network_allowed(true, "http://[::1]:8000").is_ok());
+        assert!(ensure_network_allowed(true, "https://api-inference.huggingface.co").is_err());
+        assert!(ensure_network_allowed(true, "not a url").is_err());
+    }
+
+    #[test]
+    fn no_network_client_skips_proxies_and_redirects() {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/v1/models", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request);
+            stream.write_all(b"HTTP/1.1 302 Found\r\nLocation: http://example.com/\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").unwrap();
+        });
+
+        // Nothing listens on port 9, so the request only succeeds if the proxy is bypassed
+        std::env::set_var("HTTP_PROXY", "http://127.0.0.1:9");
+        std::env::set_var("ALL_PROXY", "http://127.0.0.1:9");
+        let client = api_client(true);
+        std::env::remove_var("HTTP_PROXY");
+        std::env::remove_var("ALL_PROXY");
+
+        let response = client.unwrap().get(&url).send().unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::FOUND);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn batched_cache_writes_persist_on_drop() {
+        use std::path::PathBuf;
+
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("cache.db");
+        let functions: Vec<FunctionInfo> = (0..100)
+            .map(|i| FunctionInfo {
+                name: format!("f{}", i),
+                source: format!("def f{}():\n    return {}\n", i, i),
+                file_path: PathBuf::from("t.py"),
+                line_number: 1,
+                ..Default::default()
+            })
+            .collect();
+
+        {
+            let cache = AnalysisCache::new(None, Some(db_path.clone()), true, false).unwrap();
+            for func in &functions {
+                cache.put(func, "quadratic", true, "VERDICT: QUADRATIC", None, None).unwrap();
+            }
+            // Uncommitted writes are visible on the same connection
+            let config: ChecksConfig = toml::from_str(get_default_config_toml()).unwrap();
+            let (quadratic, others): (Vec<_>, Vec<_>) = config.check.into_iter().partition(|check| check.key == "quadratic");
+            assert!(cache.contains_function(&functions[99], &quadratic).unwrap());
+            assert!(!cache.contains_function(&functions[99], &others).unwrap());
+        }
+
+        let reopened = AnalysisCache::new(None, Some(db_path), true, false).unwrap();
+        assert_eq!(reopened.stats().unwrap(), (100, 100));
+        assert!(reopened.get(&functions[99], "quadratic").unwrap().is_some_and(|cached| cached.has_issue));
+    }
+
+    #[test]
+    fn stop_reasons_are_cached_alongside_older_rows() {
+        use std::path::PathBuf;
+
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("cache.db");
+        let func = FunctionInfo {
+            name: "f".to_string(),
+            source: "def f():\n    pass\n".to_string(),
+            file_path: PathBuf::from("t.py"),
+            line_number: 1,
+            ..Default::default()
+        };
+
+        // A cache written before stop reasons were recorded
+        rusqlite::Connection::open(&db_path).unwrap().execute_batch(&format!(
+            "CREATE TABLE check_results (function_hash TEXT NOT NULL, check_key TEXT NOT NULL,
+                has_issue INTEGER NOT NULL, analysis TEXT NOT NULL, solution TEXT,
+                created_at INTEGER NOT NULL, PRIMARY KEY (function_hash, check_key));
+             INSERT INTO check_results VALUES ('{}', 'old', 0, 'VERDICT: OK', NULL, 0);",
+            AnalysisCache::hash_function(&func.source)
+        )).unwrap();
+
+        let cache = AnalysisCache::new(None, Some(db_path), true, false).unwrap();
+        assert_eq!(cache.get(&func, "old").unwrap().unwrap().stop_reason, None);
+        cache.put(&func, "new", true, "VERDICT: X", None, Some(StopReason::MaxTokens)).unwrap();
+        assert_eq!(cache.get(&func, "new").unwrap().unwrap().stop_reason, Some(StopReason::MaxTokens));
+    }
+
+    #[test]
+    fn results_cached_with_other_prompts_are_misses() {
+        use std::path::PathBuf;
+
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("cache.db");
+        let func = FunctionInfo {
+            name: "f".to_string(),
+            source: "def f():\n    pass\n".to_string(),
+            file_path: PathBuf::from("t.py"),
+            line_number: 1,
+            ..Default::default()
+        };
+        let config: ChecksConfig = toml::from_str(get_default_config_toml()).unwrap();
+        let mut check = config.check[0].clone();
+
+        {
+            let cache = AnalysisCache::new(None, Some(db_path.clone()), true, false).unwrap()
+                .with_prompt_hashes(std::slice::from_ref(&check));
+            cache.put(&func, &check.key, true, "VERDICT: X", None, None).unwrap();
+            assert!(cache.get(&func, &check.key).unwrap().is_some());
+        }
+
+        check.detection_prompt.push_str("\nBe thorough.");
+        let cache = AnalysisCache::new(None, Some(db_path), true, false).unwrap()
+            .with_prompt_hashes(std::slice::from_ref(&check));
+        assert!(cache.get(&func, &check.key).unwrap().is_none());
+    }
+
+    #[test]
+    fn dismissed_findings_feed_the_per_check_rate() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = AnalysisCache::new(None, Some(dir.path().join("cache.db")), true, false).unwrap();
+        let record = |id: &str, key: &str| (id.to_string(), key.to_string(), format!("t.py:1 {}", id));
+        cache.record_findings(&[record("aaa", "quadratic"), record("bbb", "quadratic"), record("ccc", "string-concat")]).unwrap();
+        // Seen again in a later run: still one finding
+        cache.record_findings(&[record("aaa", "quadratic")]).unwrap();
+
+        assert_eq!(cache.dismiss("aaa").unwrap(), Some(("quadratic".to_string(), "t.py:1 aaa".to_string())));
+        assert_eq!(cache.dismiss("zzz").unwrap(), None);
+
+        let rows = cache.feedback().unwrap();
+        let counts: Vec<(&str, usize, usize)> = rows.iter()
+            .map(|row| (row.check_key.as_str(), row.findings, row.dismissed))
+            .collect();
+        assert_eq!(counts, vec![("quadratic", 2, 1), ("string-concat", 1, 0)]);
+        assert_eq!(rows[0].month.len(), "2026-01".len());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn concurrency_safe_cache_holds_a_lock_until_dropped() {
+        use std::os::unix::io::AsRawFd;
+
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("cache.db");
+        let try_lock = || {
+            let file = std::fs::File::open(dir.path().join("cache.db.lock")).unwrap();
+            unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) == 0 }
+        };
+
+        let cache = AnalysisCache::new(None, Some(db_path.clone()), true, true).unwrap();
+        assert!(!try_lock());
+        drop(cache);
+        assert!(try_lock());
+    }
+
+    #[test]
+    fn vacuum_prunes_expired_results_and_keeps_fresh_ones() {
+        use std::path::PathBuf;
+
+        let dir = tempfile::tempdir().unwrap();
+        let cache = AnalysisCache::new(None, Some(dir.path().join("cache.db")), true, false).unwrap();
+        let functions: Vec<FunctionInfo> = (0..50)
+            .map(|i| FunctionInfo {
+                name: format!("f{}", i),
+                source: format!("def f{}():\n    return {}\n", i, "x".repeat(2000)),
+                file_path: PathBuf::from("t.py"),
+                line_number: 1,
+                ..Default::default()
+            })
+            .collect();
+        for func in &functions {
+            cache.put(func, "quadratic", true, &func.source, None, None).unwrap();
+        }
+        cache.flush().unwrap();
+        // Age all but the first result by a year
+        cache.conn.execute("UPDATE check_results SET created_at = created_at - 365 * 86400 WHERE analysis != ?1",
+            rusqlite::params![functions[0].source]).unwrap();
+
+        let stats = cache.vacuum(Some(30)).unwrap();
+        assert_eq!((stats.pruned, stats.kept), (49, 1));
+        assert!(stats.bytes_after < stats.bytes_before);
+        assert!(cache.get(&functions[0], "quadratic").unwrap().is_some());
+
+        let stats = cache.vacuum(None).unwrap();
+        assert_eq!((stats.pruned, stats.kept), (0, 1));
+    }
+
+    #[test]
+    fn detection_lines_are_validated_against_the_function() {
+        use std::path::PathBuf;
+
+        assert_eq!(parse_line_list("3, 5-7 x 9"), vec![3, 5, 6, 7, 9]);
+        assert_eq!(parse_yes_no_detection("YES\nLINES: 2,4\nEND").lines, vec![2, 4]);
+        assert_eq!(parse_json_detection(r#"{"issue": true, "lines": [3]}"#).lines, vec![3]);
+
+        let source = "def f(xs):\n    \"\"\"Doc.\"\"\"\n    for x in xs:\n        xs.index(x)\n    return xs";
+        let func = FunctionInfo {
+            name: "f".to_string(),
+            source: source.to_string(),
+            source_no_docstring: super::strip_docstring(source),
+            file_path: PathBuf::from("t.py"),
+            line_number: 10,
+            ..Default::default()
+        };
+
+        // Shown lines 2 and 3 (`for`, `xs.index`) are file lines 12 and 13 once the
+        // docstring on line 11 is accounted for; 0 and 9 fall outside the function
+        assert_eq!(validated_detection_lines(&[3, 0, 9, 3, 2], &func), vec![12, 13]);
+    }
 }